@@ -0,0 +1,101 @@
+//!
+//! Timings for the happy path: parsing a request, and serving a GET over TCP.
+//! Run with `cargo bench`.
+//!
+
+use std::{
+    fs,
+    hint::black_box,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    path::PathBuf,
+    time::Duration,
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use httpfs::{
+    bullshit_scanner::BullshitScanner, parse::parse_http_request, transport::BoundAddr, Server,
+};
+
+fn parse(c: &mut Criterion) {
+    let request = concat!(
+        "GET /some/file.txt HTTP/1.1\r\n",
+        "Host: localhost:8080\r\n",
+        "User-Agent: bench\r\n",
+        "Accept: */*\r\n",
+        "\r\n"
+    );
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(request.len() as u64));
+    group.bench_function("request", |b| {
+        b.iter(|| {
+            let mut input = request.as_bytes();
+            black_box(parse_http_request(BullshitScanner::new(&mut input)).unwrap());
+        })
+    });
+    group.finish();
+}
+
+fn tcp(c: &mut Criterion) {
+    let dir = std::env::temp_dir().join(format!("httpfs-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("small.txt"), "hello world\n").unwrap();
+    fs::write(dir.join("large.bin"), vec![b'x'; 1 << 20]).unwrap();
+    let served = Served::new(dir);
+
+    let mut group = c.benchmark_group("tcp");
+    group.bench_function("get_small", |b| {
+        b.iter(|| black_box(get(served.addr, "/small.txt")))
+    });
+    group.throughput(Throughput::Bytes(1 << 20));
+    group.bench_function("get_1mib", |b| {
+        b.iter(|| black_box(get(served.addr, "/large.bin")))
+    });
+    group.finish();
+}
+
+/// A server on a free port, serving a directory that is removed along with
+/// the server when this is dropped
+struct Served {
+    handle: httpfs::Handle,
+    addr: SocketAddr,
+    dir: PathBuf,
+}
+
+impl Served {
+    fn new(dir: PathBuf) -> Self {
+        let handle = Server {
+            port: 0,
+            dir: dir.to_string_lossy().into_owned(),
+            ..Default::default()
+        }
+        .serve()
+        .unwrap();
+        handle.wait_ready(Duration::from_secs(5)).unwrap();
+        let addr = match handle.bound_addrs() {
+            [BoundAddr::Tcp(addr), ..] => *addr,
+            addrs => panic!("not listening on TCP: {:?}", addrs),
+        };
+        Self { handle, addr, dir }
+    }
+}
+
+impl Drop for Served {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+        let _ = self.handle.clone().join();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Sends a GET and reads the response until the server closes the connection
+fn get(addr: SocketAddr, path: &str) -> usize {
+    let mut sock = TcpStream::connect(addr).unwrap();
+    write!(sock, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut res = Vec::new();
+    sock.read_to_end(&mut res).unwrap();
+    res.len()
+}
+
+criterion_group!(benches, parse, tcp);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+//!
+//! Timings for the scanner, reading a thousand header lines. Run with
+//! `cargo bench`.
+//!
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use httpfs::bullshit_scanner::BullshitScanner;
+
+fn scanner(c: &mut Criterion) {
+    let headers = (0..1000)
+        .map(|i| format!("X-Header-{}: {}\r\n", i, "a".repeat(64)))
+        .collect::<String>();
+
+    let mut group = c.benchmark_group("scanner");
+    group.throughput(Throughput::Bytes(headers.len() as u64));
+    group.bench_function("next_line", |b| {
+        b.iter(|| {
+            let mut input = headers.as_bytes();
+            let mut scnr = BullshitScanner::new(&mut input);
+            while let Ok(line) = scnr.next_line() {
+                black_box(line);
+            }
+        })
+    });
+    group.bench_function("next_line_ref", |b| {
+        b.iter(|| {
+            let mut input = headers.as_bytes();
+            let mut scnr = BullshitScanner::new(&mut input);
+            while let Ok(line) = scnr.next_line_ref() {
+                black_box(line);
+            }
+        })
+    });
+    group.bench_function("next_byte", |b| {
+        b.iter(|| {
+            let mut input = headers.as_bytes();
+            let mut scnr = BullshitScanner::new(&mut input);
+            while let Ok(b) = scnr.next_byte() {
+                black_box(b);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, scanner);
+criterion_main!(benches);
@@ -1,11 +1,24 @@
-use std::time::Instant;
+use std::{
+    fs,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
 
-use httpfs::server::{Handle, Server};
+use clap::CommandFactory;
+use httpfs::{
+    discovery::Discovery,
+    parse::Strictness,
+    server::{ErrorPages, Handle, Server, SignedUrls},
+    transport::{BindOptions, BoundAddr},
+};
+#[cfg(unix)]
+use httpfs::{server::BeforeAccept, ServerError};
 
 use crate::cmd::{
-    config::Config,
-    exit::{EXIT_NOT_OKAY, EXIT_OKAY},
-    utils,
+    config::{Action, Bind, Config},
+    exit::{EXIT_ABORTED, EXIT_NOT_OKAY, EXIT_OKAY},
+    generate, utils,
 };
 
 /// Runs the CLI and exits with an error code.
@@ -20,16 +33,80 @@ pub fn run(args: impl Iterator<Item = String>) -> i32 {
         Err(exit) => return exit,
     };
 
+    match cfg.action {
+        Some(Action::Completions { shell }) => {
+            print!("{}", generate::completions(Config::command(), shell));
+            return EXIT_OKAY;
+        }
+        Some(Action::Manpage) => {
+            print!("{}", generate::manpage(Config::command()));
+            return EXIT_OKAY;
+        }
+        Some(Action::Discover { port, timeout }) => return discover(port, timeout),
+        Some(Action::Sign {
+            ref path,
+            expires_in,
+        }) => return sign(&cfg, path, expires_in),
+        None => {}
+    }
+
     utils::logging::init_logging(cfg.verbose);
+    utils::logging::toggle_debug_on_signal();
     log::info!("Configuration: {}", cfg);
 
-    let srv = server(cfg);
+    let dir = cfg.dir.clone();
+    let signed_urls = match signed_urls(&cfg) {
+        Ok(signed_urls) => signed_urls,
+        Err(e) => {
+            log::error!("{}", e);
+            return EXIT_NOT_OKAY;
+        }
+    };
+    let error_pages = match cfg.error_pages.as_deref().map(ErrorPages::from_dir) {
+        Some(Err(e)) => {
+            log::error!("Failed to load the error pages: {}", e);
+            return EXIT_NOT_OKAY;
+        }
+        pages => pages.and_then(Result::ok),
+    };
+    #[cfg(unix)]
+    let ids = match confine(&cfg) {
+        Ok(ids) => ids,
+        Err(e) => {
+            log::error!("{}", e);
+            return EXIT_NOT_OKAY;
+        }
+    };
+    let srv = Server {
+        signed_urls,
+        error_pages,
+        // Switching users once the sockets are bound, before any request is
+        // read, so that no request is served with root privileges
+        #[cfg(unix)]
+        before_accept: ids.map(|ids| -> BeforeAccept {
+            Box::new(move || {
+                utils::privileges::drop_to(ids).map_err(|e| ServerError::new().msg(&e))
+            })
+        }),
+        ..server(cfg)
+    };
     std::process::exit(match srv.serve() {
         Ok(handle) => {
-            log::debug!("Got a server handle: {:?}", handle);
+            for addr in handle.bound_addrs() {
+                match addr {
+                    BoundAddr::Tcp(addr) => log::info!("Serving {} at http://{}/", dir, addr),
+                    #[allow(unreachable_patterns)]
+                    addr => log::info!("Serving {} at {}", dir, addr),
+                }
+            }
             set_at_exit_handler(handle.clone());
-            handle.join();
-            EXIT_OKAY
+            match handle.join() {
+                Ok(()) => EXIT_OKAY,
+                Err(e) => {
+                    log::error!("{}", e);
+                    EXIT_NOT_OKAY
+                }
+            }
         }
         Err(e) => {
             log::info!("{}", e);
@@ -38,21 +115,131 @@ pub fn run(args: impl Iterator<Item = String>) -> i32 {
     })
 }
 
+/// Prints the servers announcing themselves on `port`
+fn discover(port: u16, timeout: u64) -> i32 {
+    let found = Discovery::bind(port).and_then(|d| d.collect(Duration::from_secs(timeout)));
+    match found {
+        Ok(servers) if servers.is_empty() => {
+            eprintln!("No servers found");
+            EXIT_NOT_OKAY
+        }
+        Ok(servers) => {
+            servers.iter().for_each(|server| println!("{}", server));
+            EXIT_OKAY
+        }
+        Err(e) => {
+            eprintln!("Failed to listen for servers on port {}: {}", port, e);
+            EXIT_NOT_OKAY
+        }
+    }
+}
+
+/// Prints a signed link to `path`
+fn sign(cfg: &Config, path: &str, expires_in: u64) -> i32 {
+    match signed_urls(cfg) {
+        Ok(Some(signed)) => {
+            let expires = SystemTime::now() + Duration::from_secs(expires_in);
+            println!("{}", signed.sign(path, expires));
+            EXIT_OKAY
+        }
+        Ok(None) => EXIT_NOT_OKAY,
+        Err(e) => {
+            eprintln!("{}", e);
+            EXIT_NOT_OKAY
+        }
+    }
+}
+
+/// Reads the secret for signed links, if there is one
+fn signed_urls(cfg: &Config) -> Result<Option<SignedUrls>, String> {
+    let read = |path: &Path| {
+        let secret = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        match secret.trim_ascii() {
+            [] => Err(format!("{}: the signing key is empty", path.display())),
+            secret => Ok(SignedUrls::new(secret, cfg.signed.clone())),
+        }
+    };
+    cfg.signing_key.as_deref().map(read).transpose()
+}
+
 fn server(cfg: Config) -> Server {
-    Server {
+    let mut srv = Server {
         dir: cfg.dir,
         port: cfg.port,
-        n_workers: num_cpus::get(),
+        extra_addrs: cfg.also_bind,
+        n_workers: cfg.workers.unwrap_or_else(num_cpus::get),
+        admin: cfg.admin,
+        announce: cfg.announce,
+        max_dir_bytes: cfg.max_dir_bytes,
+        keep_versions: cfg.keep_versions,
+        dedup: cfg.dedup,
+        reveal_paths: cfg.reveal_paths,
+        max_line_length: cfg
+            .max_line_length
+            .unwrap_or(Server::DEFAULT_MAX_LINE_LENGTH),
+        strictness: match cfg.strict {
+            true => Strictness::Strict,
+            false => Strictness::Lenient,
+        },
+        proxies: cfg.proxy,
+        proxy_timeout: cfg
+            .proxy_timeout
+            .map_or(Server::DEFAULT_PROXY_TIMEOUT, Duration::from_secs),
+        usage_report: cfg.usage_report.map(Duration::from_secs),
+        bind_options: BindOptions {
+            reuse_port: cfg.reuse_port,
+            ..Default::default()
+        },
         ..Default::default()
+    };
+    #[cfg(unix)]
+    if cfg.chroot {
+        srv.dir = String::from("/");
+    }
+    match cfg.bind {
+        Some(Bind::Ip(addr)) => srv.addr = addr,
+        #[cfg(unix)]
+        Some(Bind::Unix(path)) => srv.unix_socket = Some(path),
+        _ => {}
     }
+    srv
 }
 
-fn set_at_exit_handler(mut handle: Handle) {
+/// Looks up the user and group to switch to, then chroots into the served
+/// directory if asked to. Binding the sockets does not need the filesystem, so
+/// the server can start in the new root, and never serves a request from the
+/// old one.
+#[cfg(unix)]
+fn confine(cfg: &Config) -> Result<Option<utils::privileges::Ids>, String> {
+    let ids = utils::privileges::resolve(cfg.user.as_deref(), cfg.group.as_deref())?;
+    if cfg.chroot {
+        utils::privileges::chroot(&cfg.dir)?;
+        if ids.is_none() && nix::unistd::Uid::effective().is_root() {
+            log::warn!("Still running as root inside the chroot, see --user");
+        }
+    }
+    Ok(ids)
+}
+
+/// Shuts the server down gracefully on ctrl-c, `SIGTERM` or `SIGHUP`,
+/// letting the requests in progress finish. A second signal exits right away
+/// with [EXIT_ABORTED].
+fn set_at_exit_handler(handle: Handle) {
     let now = Instant::now();
+    let signals = AtomicUsize::new(0);
     let set_handler = ctrlc::set_handler(move || {
-        log::info!("Server shutting down...");
-        handle.shutdown();
-        log::debug!("Server ran for {} seconds...", now.elapsed().as_secs());
+        if signals.fetch_add(1, Ordering::SeqCst) > 0 {
+            log::warn!("Server aborted, requests in progress were cut off");
+            std::process::exit(EXIT_ABORTED);
+        }
+        log::info!("Server shutting down, signal again to abort...");
+
+        // The handler must return for the next signal to be noticed
+        let mut handle = handle.clone();
+        std::thread::spawn(move || {
+            handle.shutdown();
+            log::debug!("Server ran for {} seconds...", now.elapsed().as_secs());
+        });
     });
     if set_handler.is_err() {
         log::debug!(concat!(
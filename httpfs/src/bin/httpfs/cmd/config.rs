@@ -1,8 +1,22 @@
-use std::{error::Error, fmt::Display, path::Path};
+use std::{
+    error::Error,
+    fmt::Display,
+    fs,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueHint, ValueSource};
+use serde::{de, Deserialize, Deserializer};
 
-use crate::cmd::exit::EXIT_NOT_OKAY;
+use httpfs::{
+    discovery::{DEFAULT_ANNOUNCE_ADDR, DEFAULT_DISCOVERY_PORT},
+    server::Proxy,
+};
+
+use crate::cmd::{exit::EXIT_NOT_OKAY, generate::Shell};
 
 #[derive(Debug)]
 pub struct ConfigError(pub String);
@@ -25,28 +39,327 @@ pub struct Config {
 
     /// Specifies the directory that the server will use to read/write requested
     /// files. Default is the current directory when launching the application.
-    #[clap(short, long, default_value = "./")]
+    #[clap(short, long, default_value = "./", value_hint = ValueHint::DirPath)]
     pub dir: String,
 
     /// Specifies the port number that the server will listen and serve at.
     #[clap(short, long, default_value_t = 8080)]
     pub port: u32,
+
+    /// The IP address to listen on, or 'unix:PATH' to listen on a unix domain
+    /// socket instead of TCP. Default is 127.0.0.1.
+    #[clap(short, long, value_name = "ADDR")]
+    pub bind: Option<Bind>,
+
+    /// Also listens on ADDR, on the same port, e.g. '--bind 0.0.0.0
+    /// --also-bind ::' for both IPv4 and IPv6. Can be given more than once.
+    #[clap(long, value_name = "ADDR")]
+    pub also_bind: Vec<IpAddr>,
+
+    /// Enables the admin endpoints, e.g. '/__admin/loglevel' for changing the
+    /// log level at runtime. Only use this on a trusted network. The log level
+    /// can also be toggled between the startup level and debug with SIGUSR1.
+    #[clap(long)]
+    pub admin: bool,
+
+    /// Number of worker threads handling requests. Default is the number of
+    /// CPUs.
+    #[clap(short, long)]
+    pub workers: Option<usize>,
+
+    /// Reads settings from a TOML file. The keys are the long names of the
+    /// other options with '_' for '-', e.g. 'max_dir_bytes = 1_000_000', and
+    /// options that can be given more than once take an array. Options given
+    /// on the command line take precedence over the file.
+    #[clap(short, long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub config: Option<PathBuf>,
+
+    /// Announces the server on the local network so that 'httpfs discover'
+    /// can find it. Beacons are broadcast on port 8699 unless an address is
+    /// given.
+    #[clap(
+        long,
+        value_name = "ADDR",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "255.255.255.255:8699"
+    )]
+    pub announce: Option<SocketAddr>,
+
+    /// Limits the total size of the files in the directory. Uploads that
+    /// would go over the limit are refused with 507 Insufficient Storage.
+    #[clap(long, value_name = "BYTES")]
+    pub max_dir_bytes: Option<u64>,
+
+    /// Keeps up to N old versions of each file that an upload overwrites, in
+    /// '.trash/' in the directory.
+    #[clap(long, value_name = "N")]
+    pub keep_versions: Option<usize>,
+
+    /// Stores each distinct upload once, in '.objects/' in the directory, and
+    /// hard links the uploaded files to it.
+    #[clap(long)]
+    pub dedup: bool,
+
+    /// Forwards the requests under PREFIX to the server at ADDR, e.g.
+    /// '/api=127.0.0.1:9000' sends '/api/users' to that server as '/users'.
+    /// Can be given more than once.
+    #[clap(long, value_name = "PREFIX=ADDR")]
+    pub proxy: Vec<Proxy>,
+
+    /// Answers proxied requests with '504 Gateway Timeout' when the upstream
+    /// server takes longer than SECS seconds to connect, or to read or write
+    /// any of the request or response. Default is 30.
+    #[clap(long, value_name = "SECS")]
+    pub proxy_timeout: Option<u64>,
+
+    /// Only serves the paths under PREFIX with a signed link from 'httpfs
+    /// sign', until the link expires. Requires '--signing-key'. Can be given
+    /// more than once.
+    #[clap(long, value_name = "PREFIX")]
+    pub signed: Vec<String>,
+
+    /// The file holding the secret that links are signed with. Leading and
+    /// trailing whitespace is ignored.
+    #[clap(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub signing_key: Option<PathBuf>,
+
+    /// Logs the clients and the paths that were sent the most bytes every
+    /// SECS seconds. With '--admin', the totals are also served at
+    /// '/__admin/usage'.
+    #[clap(long, value_name = "SECS")]
+    pub usage_report: Option<u64>,
+
+    /// Sends the pages in DIR to browsers instead of the plain text error
+    /// messages. The pages are named after the status, e.g. '404.html', and
+    /// '{STATUS}', '{REASON}', '{MESSAGE}' and '{PATH}' in them are filled
+    /// in. They are read once, before '--chroot'.
+    #[clap(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub error_pages: Option<PathBuf>,
+
+    /// Shows the location of the directory on the server in the bodies of
+    /// '403 Forbidden' and '404 Not Found' responses.
+    #[clap(long)]
+    pub reveal_paths: bool,
+
+    /// Refuses requests with a request line or a header longer than this,
+    /// with '414 URI Too Long' or '431 Request Header Fields Too Large'.
+    /// Default is 8192.
+    #[clap(long, value_name = "BYTES")]
+    pub max_line_length: Option<usize>,
+
+    /// Refuses requests that bend the HTTP syntax, e.g. with lines ending in
+    /// a bare '\n', or headers folded over several lines, instead of making
+    /// sense of them.
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Lets several servers listen on the same port, with SO_REUSEPORT. The
+    /// system spreads the connections between them. Every one of them must
+    /// be started with this flag.
+    #[clap(long)]
+    pub reuse_port: bool,
+
+    /// Confines the server to the directory with chroot(2) before it starts
+    /// listening, so that nothing outside of it can be read or written.
+    /// Requires root. Unix socket paths are then inside the directory.
+    #[cfg(unix)]
+    #[clap(long)]
+    pub chroot: bool,
+
+    /// Switches to this user, by name or id, once the server is listening,
+    /// e.g. after binding port 80 as root.
+    #[cfg(unix)]
+    #[clap(long, value_name = "USER")]
+    pub user: Option<String>,
+
+    /// Switches to this group, by name or id, once the server is listening.
+    /// Default is the primary group of '--user'.
+    #[cfg(unix)]
+    #[clap(long, value_name = "GROUP")]
+    pub group: Option<String>,
+
+    #[clap(subcommand)]
+    pub action: Option<Action>,
+}
+
+/// Subcommands that do something other than serving
+#[derive(Subcommand, Debug, Hash, Clone)]
+pub enum Action {
+    /// Prints a completion script for the given shell
+    Completions {
+        #[clap(value_enum, value_name = "SHELL")]
+        shell: Shell,
+    },
+
+    /// Prints a man page in roff format
+    Manpage,
+
+    /// Lists the servers announcing themselves on the local network
+    Discover {
+        /// The UDP port to listen for announcements on
+        #[clap(long, default_value_t = DEFAULT_DISCOVERY_PORT)]
+        port: u16,
+
+        /// How long to listen for, in seconds
+        #[clap(long, default_value_t = 3)]
+        timeout: u64,
+    },
+
+    /// Prints a link to PATH that is valid for a while, for paths protected
+    /// with '--signed'. Uses the secret from '--signing-key'.
+    Sign {
+        #[clap(value_name = "PATH")]
+        path: String,
+
+        /// How long the link is valid for, in seconds
+        #[clap(long, default_value_t = 3600)]
+        expires_in: u64,
+    },
 }
 
 impl Config {
     pub fn from_args(args: impl Iterator<Item = String>) -> Result<Config, i32> {
-        Config::try_parse_from(args)
-            .map_err(|e| ConfigError(format!("{}", e)))
-            .and_then(Self::verify)
-            .map_err(|e| {
-                eprint!("{}{}", e, if e.0.ends_with('\n') { "" } else { "\n" });
-                EXIT_NOT_OKAY
-            })
+        Self::parse_args(args).and_then(Self::verify).map_err(|e| {
+            eprint!("{}{}", e, if e.0.ends_with('\n') { "" } else { "\n" });
+            EXIT_NOT_OKAY
+        })
+    }
+
+    /// Parses the command line, then merges in the configuration file if one
+    /// was given
+    fn parse_args(args: impl Iterator<Item = String>) -> Result<Config, ConfigError> {
+        let matches = Self::command()
+            .try_get_matches_from(args)
+            .map_err(|e| ConfigError(format!("{}", e)))?;
+        let mut cfg =
+            Self::from_arg_matches(&matches).map_err(|e| ConfigError(format!("{}", e)))?;
+        if let Some(file) = cfg.config.clone() {
+            let cmd = Self::command();
+            cfg.merge_file(&file, |key| {
+                // value_source panics on keys that are not arguments
+                cmd.get_arguments().any(|arg| arg.get_id() == key)
+                    && matches.value_source(key) == Some(ValueSource::CommandLine)
+            })?;
+        }
+        Ok(cfg)
+    }
+
+    /// Applies the settings from a TOML configuration file, except for the
+    /// keys for which `on_command_line` returns true. Those are still
+    /// validated.
+    pub fn merge_file(
+        &mut self,
+        path: &Path,
+        on_command_line: impl Fn(&str) -> bool,
+    ) -> Result<(), ConfigError> {
+        let src = fs::read_to_string(path)
+            .map_err(|e| ConfigError(format!("{}: {}", path.display(), e)))?;
+        let file = FileConfig::parse(&src)
+            .map_err(|e| ConfigError(format!("{}:{}", path.display(), e)))?;
+
+        // The value for the key, unless the command line overrides it
+        macro_rules! given {
+            ($key:ident) => {
+                file.$key.filter(|_| !on_command_line(stringify!($key)))
+            };
+        }
+        if let Some(dir) = given!(dir) {
+            self.dir = dir;
+        }
+        if let Some(port) = given!(port) {
+            self.port = port.into();
+        }
+        if let Some(bind) = given!(bind) {
+            self.bind = Some(bind);
+        }
+        if let Some(also_bind) = given!(also_bind) {
+            self.also_bind = also_bind;
+        }
+        if let Some(workers) = given!(workers) {
+            self.workers = Some(workers.get());
+        }
+        if let Some(verbose) = given!(verbose) {
+            self.verbose = verbose;
+        }
+        if let Some(admin) = given!(admin) {
+            self.admin = admin;
+        }
+        if let Some(announce) = given!(announce) {
+            self.announce = announce;
+        }
+        if let Some(max_dir_bytes) = given!(max_dir_bytes) {
+            self.max_dir_bytes = Some(max_dir_bytes);
+        }
+        if let Some(keep_versions) = given!(keep_versions) {
+            self.keep_versions = Some(keep_versions);
+        }
+        if let Some(dedup) = given!(dedup) {
+            self.dedup = dedup;
+        }
+        if let Some(proxy) = given!(proxy) {
+            self.proxy = proxy;
+        }
+        if let Some(proxy_timeout) = given!(proxy_timeout) {
+            self.proxy_timeout = Some(proxy_timeout);
+        }
+        if let Some(signed) = given!(signed) {
+            self.signed = signed;
+        }
+        if let Some(signing_key) = given!(signing_key) {
+            self.signing_key = Some(signing_key);
+        }
+        if let Some(usage_report) = given!(usage_report) {
+            self.usage_report = Some(usage_report);
+        }
+        if let Some(error_pages) = given!(error_pages) {
+            self.error_pages = Some(error_pages);
+        }
+        if let Some(reveal_paths) = given!(reveal_paths) {
+            self.reveal_paths = reveal_paths;
+        }
+        if let Some(max_line_length) = given!(max_line_length) {
+            self.max_line_length = Some(max_line_length);
+        }
+        if let Some(strict) = given!(strict) {
+            self.strict = strict;
+        }
+        if let Some(reuse_port) = given!(reuse_port) {
+            self.reuse_port = reuse_port;
+        }
+        #[cfg(unix)]
+        if let Some(chroot) = given!(chroot) {
+            self.chroot = chroot;
+        }
+        #[cfg(unix)]
+        if let Some(user) = given!(user) {
+            self.user = Some(user);
+        }
+        #[cfg(unix)]
+        if let Some(group) = given!(group) {
+            self.group = Some(group);
+        }
+        Ok(())
     }
 
     pub fn verify(self) -> Result<Self, ConfigError> {
         if !Path::new(self.dir.as_str()).exists() {
-            Err(ConfigError(String::from("ConfigError")))
+            Err(ConfigError(format!(
+                "directory '{}' does not exist",
+                self.dir
+            )))
+        } else if matches!(self.bind, Some(Bind::Unix(_))) && !self.also_bind.is_empty() {
+            Err(ConfigError(String::from(
+                "'--also-bind' can't be used with a unix socket",
+            )))
+        } else if self.workers == Some(0) {
+            Err(ConfigError(String::from("workers must be at least 1")))
+        } else if self.signing_key.is_none()
+            && (!self.signed.is_empty() || matches!(self.action, Some(Action::Sign { .. })))
+        {
+            Err(ConfigError(String::from(
+                "signed links need a secret, see '--signing-key'",
+            )))
         } else {
             Ok(self)
         }
@@ -57,8 +370,283 @@ impl Display for Config {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "port: {}, dir: {}, verbose: {}",
-            self.port, self.dir, self.verbose,
+            "port: {}, dir: {}, verbose: {}, admin: {}, bind: {}, workers: {}, announce: {}",
+            self.port,
+            self.dir,
+            self.verbose,
+            self.admin,
+            self.bind
+                .as_ref()
+                .map(|b| b.to_string())
+                .unwrap_or_else(|| String::from("default")),
+            self.workers
+                .map(|w| w.to_string())
+                .unwrap_or_else(|| String::from("default")),
+            self.announce
+                .map(|a| a.to_string())
+                .unwrap_or_else(|| String::from("off")),
         )
     }
 }
+
+/// The settings in a configuration file. The keys are named after the
+/// options they set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    dir: Option<String>,
+    port: Option<u16>,
+    #[serde(default, deserialize_with = "from_str")]
+    bind: Option<Bind>,
+    also_bind: Option<Vec<IpAddr>>,
+    workers: Option<NonZeroUsize>,
+    verbose: Option<bool>,
+    admin: Option<bool>,
+    /// `true` for the default address, or the address to announce on
+    #[serde(default, deserialize_with = "announce")]
+    announce: Option<Option<SocketAddr>>,
+    max_dir_bytes: Option<u64>,
+    keep_versions: Option<usize>,
+    dedup: Option<bool>,
+    #[serde(default, deserialize_with = "from_strs")]
+    proxy: Option<Vec<Proxy>>,
+    proxy_timeout: Option<u64>,
+    signed: Option<Vec<String>>,
+    signing_key: Option<PathBuf>,
+    usage_report: Option<u64>,
+    error_pages: Option<PathBuf>,
+    reveal_paths: Option<bool>,
+    max_line_length: Option<usize>,
+    strict: Option<bool>,
+    reuse_port: Option<bool>,
+    #[cfg(unix)]
+    chroot: Option<bool>,
+    #[cfg(unix)]
+    user: Option<String>,
+    #[cfg(unix)]
+    group: Option<String>,
+}
+
+impl FileConfig {
+    /// Parses the file. Errors start with the line and column they were
+    /// found at, e.g. `3:11: invalid value`.
+    fn parse(src: &str) -> Result<Self, String> {
+        toml::from_str(src).map_err(|e: toml::de::Error| {
+            let at = e.span().map(|span| span.start).unwrap_or(0);
+            let line = src[..at].matches('\n').count() + 1;
+            let col = src[..at]
+                .rsplit('\n')
+                .next()
+                .unwrap_or_default()
+                .chars()
+                .count()
+                + 1;
+            format!("{}:{}: {}", line, col, e.message().trim_end())
+        })
+    }
+}
+
+/// Deserializes a string with [FromStr]
+fn from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(de::Error::custom)
+}
+
+/// Deserializes an array of strings with [FromStr]
+fn from_strs<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Deserializes `announce`, which is a boolean or an address
+fn announce<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Option<SocketAddr>>, D::Error> {
+    struct Visitor;
+
+    impl de::Visitor<'_> for Visitor {
+        type Value = Option<SocketAddr>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a boolean or an address")
+        }
+
+        fn visit_bool<E: de::Error>(self, announce: bool) -> Result<Self::Value, E> {
+            Ok(announce.then_some(DEFAULT_ANNOUNCE_ADDR))
+        }
+
+        fn visit_str<E: de::Error>(self, addr: &str) -> Result<Self::Value, E> {
+            addr.parse()
+                .map(Some)
+                .map_err(|e| E::custom(format!("invalid announce address '{}': {}", addr, e)))
+        }
+    }
+
+    deserializer.deserialize_any(Visitor).map(Some)
+}
+
+/// Where the server listens, parsed from `--bind`
+#[derive(Debug, Hash, Clone, PartialEq, Eq)]
+pub enum Bind {
+    Ip(IpAddr),
+    Unix(PathBuf),
+}
+
+impl Bind {
+    pub const UNIX_PREFIX: &'static str = "unix:";
+}
+
+impl FromStr for Bind {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix(Self::UNIX_PREFIX) {
+            if !cfg!(unix) {
+                return Err(ConfigError(String::from(
+                    "unix sockets are not supported on this platform",
+                )));
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        s.parse()
+            .map(Self::Ip)
+            .map_err(|e| ConfigError(format!("invalid bind address '{}': {}", s, e)))
+    }
+}
+
+impl Display for Bind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "{}{}", Self::UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_file() {
+        let src = r#"
+# A comment
+dir = "./public"   # trailing comment
+bind = 'unix:/tmp/httpfs.sock'
+port = 8_080
+verbose = true
+announce = "10.0.0.255:8699"
+"#;
+        let file = FileConfig::parse(src).unwrap();
+        assert_eq!(Some(String::from("./public")), file.dir);
+        assert_eq!(
+            Some(Bind::Unix(PathBuf::from("/tmp/httpfs.sock"))),
+            file.bind
+        );
+        assert_eq!(Some(8080), file.port);
+        assert_eq!(Some(true), file.verbose);
+        assert_eq!(None, file.admin);
+        assert_eq!(
+            Some(Some(SocketAddr::from(([10, 0, 0, 255], 8699)))),
+            file.announce
+        );
+        assert_eq!(
+            Some(Some(DEFAULT_ANNOUNCE_ADDR)),
+            FileConfig::parse("announce = true").unwrap().announce
+        );
+    }
+
+    #[test]
+    fn test_merge_file() {
+        let file = std::env::temp_dir().join(format!("httpfs-config-{}.toml", std::process::id()));
+        fs::write(
+            &file,
+            r#"
+port = 9000
+also_bind = ["::1"]
+max_dir_bytes = 1_000_000
+keep_versions = 3
+dedup = true
+proxy = ["/api=127.0.0.1:9001"]
+proxy_timeout = 5
+signed = ["/private"]
+signing_key = "/etc/httpfs/key"
+usage_report = 60
+error_pages = "/etc/httpfs/pages"
+reveal_paths = true
+max_line_length = 4096
+strict = true
+reuse_port = true
+"#,
+        )
+        .unwrap();
+        let mut cfg = Config::default();
+        let merged = cfg.merge_file(&file, |key| key == "port");
+        fs::remove_file(&file).unwrap();
+        merged.unwrap();
+
+        assert_eq!(0, cfg.port, "set on the command line");
+        assert_eq!(vec![IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])], cfg.also_bind);
+        assert_eq!(Some(1_000_000), cfg.max_dir_bytes);
+        assert_eq!(Some(3), cfg.keep_versions);
+        assert!(cfg.dedup);
+        assert_eq!(1, cfg.proxy.len());
+        assert_eq!(Some(5), cfg.proxy_timeout);
+        assert_eq!(vec![String::from("/private")], cfg.signed);
+        assert_eq!(Some(PathBuf::from("/etc/httpfs/key")), cfg.signing_key);
+        assert_eq!(Some(60), cfg.usage_report);
+        assert_eq!(Some(PathBuf::from("/etc/httpfs/pages")), cfg.error_pages);
+        assert!(cfg.reveal_paths);
+        assert_eq!(Some(4096), cfg.max_line_length);
+        assert!(cfg.strict);
+        assert!(cfg.reuse_port);
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        let err = FileConfig::parse("\nport = 1\ncolour = true").unwrap_err();
+        assert!(
+            err.starts_with("3:1: unknown field `colour`, expected one of `dir`, "),
+            "{}",
+            err
+        );
+    }
+
+    macro_rules! parse_error_tests {
+        ($($name:ident: $value:expr,)*) => {
+        $(
+            #[test]
+            fn $name() {
+                let (input, expected) = $value;
+                assert_eq!(expected, FileConfig::parse(input).unwrap_err());
+            }
+        )*
+        };
+    }
+
+    parse_error_tests! {
+        error_bad_value: ("\nport = 80x", "2:10: expected newline, `#`"),
+        error_missing_equals: ("port 80", "1:6: expected `.`, `=`"),
+        error_duplicate: ("port = 1\nport = 2", "2:1: duplicate key `port` in document root"),
+        error_wrong_type: ("verbose = 1", "1:11: invalid type: integer `1`, expected a boolean"),
+        error_port_range: ("port = 70000", "1:8: invalid value: integer `70000`, expected u16"),
+        error_no_workers: ("workers = 0", "1:11: invalid value: integer `0`, expected a nonzero usize"),
+        error_bind: ("bind = \"nowhere\"", "1:8: invalid bind address 'nowhere': invalid IP address syntax"),
+        error_announce: ("announce = 1", "1:12: invalid type: integer `1`, expected a boolean or an address"),
+        error_proxy: ("proxy = \"/api=127.0.0.1:9001\"", "1:9: invalid type: string \"/api=127.0.0.1:9001\", expected a sequence"),
+        error_keep_versions: ("keep_versions = -1", "1:17: invalid value: integer `-1`, expected usize"),
+    }
+}
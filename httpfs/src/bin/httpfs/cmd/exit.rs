@@ -1,2 +1,6 @@
 pub const EXIT_NOT_OKAY: i32 = 1;
 pub const EXIT_OKAY: i32 = 0;
+
+/// The server was stopped by a second signal before it finished the requests
+/// in progress
+pub const EXIT_ABORTED: i32 = 2;
@@ -0,0 +1,69 @@
+//!
+//! Shell completion scripts and a man page, generated from the clap definition
+//! of the command line so that they never drift from the real options.
+//!
+
+use clap::Command;
+
+pub use clap_complete::Shell;
+
+/// Generates the completion script for `shell`
+pub fn completions(mut cmd: Command, shell: Shell) -> String {
+    let name = cmd.get_name().to_string();
+    let mut out = Vec::new();
+    clap_complete::generate(shell, &mut cmd, name, &mut out);
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Generates a man page in roff format
+pub fn manpage(cmd: Command) -> String {
+    let mut out = Vec::new();
+    // Writing to a Vec can't fail
+    let _ = clap_mangen::Man::new(cmd).render(&mut out);
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+    use crate::cmd::config::Config;
+
+    #[test]
+    fn test_bash_completions() {
+        let script = completions(Config::command(), Shell::Bash);
+        assert!(script.contains("complete -F _httpfs -o bashdefault -o default httpfs"));
+        assert!(script.contains(" --max-dir-bytes "), "{}", script);
+        assert!(script.contains("opts=\"-h --help bash elvish fish powershell zsh\""));
+    }
+
+    #[test]
+    fn test_zsh_completions() {
+        let script = completions(Config::command(), Shell::Zsh);
+        assert!(script.starts_with("#compdef httpfs\n"));
+        assert!(script.contains("'--config=[Reads settings from a TOML file."));
+        assert!(script.contains("]:FILE:_files' \\\n"));
+    }
+
+    #[test]
+    fn test_fish_completions() {
+        let script = completions(Config::command(), Shell::Fish);
+        assert!(script.contains(
+            "complete -c httpfs -n \"__fish_use_subcommand\" -s v -l verbose -d 'Prints debugging messages'"
+        ));
+        assert!(script.contains(
+            "complete -c httpfs -n \"__fish_use_subcommand\" -f -a \"completions\" -d 'Prints a completion script for the given shell'"
+        ));
+    }
+
+    #[test]
+    fn test_manpage() {
+        let page = manpage(Config::command());
+        assert!(page.contains("\n.TH httpfs 1 "), "{}", page);
+        assert!(
+            page.contains(".TP\n\\fB\\-p\\fR, \\fB\\-\\-port\\fR=\\fIPORT\\fR [default: 8080]\n")
+        );
+        assert!(page.contains("httpfs\\-completions(1)"));
+    }
+}
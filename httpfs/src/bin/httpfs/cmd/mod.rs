@@ -1,6 +1,7 @@
 mod cli;
 pub mod config;
 pub mod exit;
+pub mod generate;
 pub mod utils;
 
 pub use cli::*;
@@ -1,8 +1,28 @@
 pub mod logging {
+    use std::{io::IsTerminal, sync::Mutex};
+
+    use log::LevelFilter;
+    use tracing::{
+        span::{Attributes, Id, Record},
+        subscriber::Interest,
+        Metadata, Subscriber,
+    };
+    use tracing_log::{AsLog, LogTracer};
+    use tracing_subscriber::{
+        filter::{self, EnvFilter},
+        layer::{Context, Filter},
+        prelude::*,
+        registry::LookupSpan,
+    };
+
     pub const LOGGING_ENV_VARIABLE: &str = "HTTPFS_LOG_LEVEL";
+    pub const WRITE_STYLE_ENV_VARIABLE: &str = "RUST_LOG_STYLE";
     pub const DEFAULT_LOG_LEVEL: &str = "info";
     pub const VERBOSE_LOG_LEVEL: &str = "debug";
 
+    /// The level that was configured when the logger was initialized
+    static STARTUP_LEVEL: Mutex<LevelFilter> = Mutex::new(LevelFilter::Info);
+
     pub fn init_logging(verbose: bool) {
         init_logging_with_level(if verbose {
             VERBOSE_LOG_LEVEL
@@ -11,9 +31,206 @@ pub mod logging {
         });
     }
 
+    /// Installs a [tracing_subscriber] that prints events to stderr, prefixed
+    /// with the spans they were logged in. Records from the [log] macros are
+    /// forwarded to it by a [LogTracer].
+    ///
+    /// The filter from [LOGGING_ENV_VARIABLE] applies until the level is
+    /// changed at runtime with [log::set_max_level] (see
+    /// [toggle_debug_on_signal] and the server's admin endpoint), after which
+    /// the new level applies to all modules.
     pub fn init_logging_with_level(level: &str) {
-        env_logger::init_from_env(
-            env_logger::Env::default().filter_or(LOGGING_ENV_VARIABLE, level),
+        let env = EnvFilter::new(
+            std::env::var(LOGGING_ENV_VARIABLE).unwrap_or_else(|_| String::from(level)),
         );
+        let startup = env
+            .max_level_hint()
+            .map(|level| level.as_log())
+            .unwrap_or(LevelFilter::Trace);
+
+        let ansi = match std::env::var(WRITE_STYLE_ENV_VARIABLE).as_deref() {
+            Ok("always") => true,
+            Ok("never") => false,
+            _ => std::io::stderr().is_terminal(),
+        };
+        let fmt = tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(ansi)
+            .with_filter(DynamicFilter { env, startup });
+
+        if tracing::subscriber::set_global_default(tracing_subscriber::registry().with(fmt)).is_ok()
+            && LogTracer::builder().with_max_level(startup).init().is_ok()
+        {
+            if let Ok(mut level) = STARTUP_LEVEL.lock() {
+                *level = startup;
+            }
+        }
+    }
+
+    /// Sets the level to `debug`, or back to the level configured at startup if
+    /// it has already been raised
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn toggle_debug() {
+        let startup = STARTUP_LEVEL
+            .lock()
+            .map(|l| *l)
+            .unwrap_or(LevelFilter::Info);
+        let level = if log::max_level() < LevelFilter::Debug {
+            LevelFilter::Debug
+        } else {
+            startup
+        };
+        log::set_max_level(level);
+        log::info!("Log level set to {}", level);
+    }
+
+    /// Spawns a thread that calls [toggle_debug] whenever the process receives
+    /// `SIGUSR1`. This must be called before any other threads are spawned so
+    /// that they inherit the signal mask.
+    #[cfg(unix)]
+    pub fn toggle_debug_on_signal() {
+        use nix::sys::signal::{SigSet, Signal};
+
+        let mut set = SigSet::empty();
+        set.add(Signal::SIGUSR1);
+        if let Err(e) = set.thread_block() {
+            log::debug!(
+                "Failed to block SIGUSR1, log level cannot be toggled: {}",
+                e
+            );
+            return;
+        }
+        std::thread::spawn(move || {
+            while let Ok(Signal::SIGUSR1) = set.wait() {
+                toggle_debug();
+            }
+        });
+    }
+
+    #[cfg(not(unix))]
+    pub fn toggle_debug_on_signal() {}
+
+    /// Reads the level from [log::max_level] on every call, so that the level
+    /// can be changed after the subscriber has been installed
+    struct DynamicFilter {
+        env: EnvFilter,
+        startup: LevelFilter,
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Filter<S> for DynamicFilter {
+        fn enabled(&self, metadata: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+            let level = log::max_level();
+            if level == self.startup {
+                Filter::<S>::enabled(&self.env, metadata, cx)
+            } else {
+                metadata.level().as_log() <= level
+            }
+        }
+
+        fn callsite_enabled(&self, metadata: &'static Metadata<'static>) -> Interest {
+            // Registers the callsite with the env filter, but never lets the
+            // answer be cached because the level can change
+            Filter::<S>::callsite_enabled(&self.env, metadata);
+            Interest::sometimes()
+        }
+
+        fn max_level_hint(&self) -> Option<filter::LevelFilter> {
+            Some(filter::LevelFilter::TRACE)
+        }
+
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, cx: Context<'_, S>) {
+            Filter::<S>::on_new_span(&self.env, attrs, id, cx)
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, cx: Context<'_, S>) {
+            Filter::<S>::on_record(&self.env, id, values, cx)
+        }
+
+        fn on_enter(&self, id: &Id, cx: Context<'_, S>) {
+            Filter::<S>::on_enter(&self.env, id, cx)
+        }
+
+        fn on_exit(&self, id: &Id, cx: Context<'_, S>) {
+            Filter::<S>::on_exit(&self.env, id, cx)
+        }
+
+        fn on_close(&self, id: Id, cx: Context<'_, S>) {
+            Filter::<S>::on_close(&self.env, id, cx)
+        }
+    }
+}
+
+/// Confining the server with `--chroot`, `--user` and `--group`
+#[cfg(unix)]
+pub mod privileges {
+    use nix::unistd::{self, Gid, Group, Uid, User};
+
+    /// The user and group the server switches to once it is listening, before
+    /// it accepts a connection
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Ids {
+        pub uid: Option<Uid>,
+        pub gid: Option<Gid>,
+    }
+
+    /// Looks up the user and group, which are names or numeric ids. This has
+    /// to happen before [chroot], which hides `/etc/passwd` and `/etc/group`.
+    /// Without a group, the user's primary group is used.
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Ids>, String> {
+        let user = match user {
+            Some(name) => Some(match name.parse() {
+                Ok(uid) => (Uid::from_raw(uid), None),
+                Err(_) => User::from_name(name)
+                    .map_err(|e| format!("failed to look up user '{}': {}", name, e))?
+                    .map(|u| (u.uid, Some(u.gid)))
+                    .ok_or_else(|| format!("no such user '{}'", name))?,
+            }),
+            None => None,
+        };
+        let gid = match group {
+            Some(name) => Some(match name.parse() {
+                Ok(gid) => Gid::from_raw(gid),
+                Err(_) => Group::from_name(name)
+                    .map_err(|e| format!("failed to look up group '{}': {}", name, e))?
+                    .map(|g| g.gid)
+                    .ok_or_else(|| format!("no such group '{}'", name))?,
+            }),
+            None => user.and_then(|(_, primary)| primary),
+        };
+        Ok((user.is_some() || gid.is_some()).then_some(Ids {
+            uid: user.map(|(uid, _)| uid),
+            gid,
+        }))
+    }
+
+    /// Makes `dir` the root directory of the process, so that `/` is the
+    /// served directory from now on
+    pub fn chroot(dir: &str) -> Result<(), String> {
+        unistd::chroot(dir)
+            .and_then(|_| unistd::chdir("/"))
+            .map_err(|e| format!("failed to chroot into '{}': {}", dir, e))?;
+        log::info!("Confined to {}", dir);
+        Ok(())
+    }
+
+    /// Switches the process to the user and group for good. The
+    /// supplementary groups are dropped too.
+    pub fn drop_to(ids: Ids) -> Result<(), String> {
+        if let Some(gid) = ids.gid {
+            #[cfg(not(target_vendor = "apple"))]
+            unistd::setgroups(&[gid])
+                .map_err(|e| format!("failed to drop supplementary groups: {}", e))?;
+            unistd::setgid(gid).map_err(|e| format!("failed to switch to group {}: {}", gid, e))?;
+        }
+        if let Some(uid) = ids.uid {
+            unistd::setuid(uid).map_err(|e| format!("failed to switch to user {}: {}", uid, e))?;
+            if !uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+                return Err(String::from(
+                    "root privileges could be regained after dropping them",
+                ));
+            }
+        }
+        log::info!("Running as uid {}, gid {}", Uid::current(), Gid::current());
+        Ok(())
     }
 }
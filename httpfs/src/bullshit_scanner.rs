@@ -1,9 +1,9 @@
-///
-/// This is a port of buffered_scanner.go
-///
+//!
+//! This is a port of buffered_scanner.go
+//!
 use self::constants::*;
 use core::slice;
-use std::{io::Read, rc::Rc};
+use std::io::Read;
 
 use self::errors::{BullshitError, Result};
 
@@ -29,7 +29,10 @@ pub struct BullshitScanner<'a> {
     buf: Buffer,
 
     /// An error registered on the scanner
-    err: Option<Rc<BullshitError>>,
+    err: Option<BullshitError>,
+
+    /// Lines can be at most this many bytes long, line terminator included
+    max_line: usize,
 }
 
 impl<'a> BullshitScanner<'a> {
@@ -40,11 +43,11 @@ impl<'a> BullshitScanner<'a> {
 
     /// Creates a new BufferedScanner
     pub fn with_capacity(reader: &'a mut dyn Read, size: usize) -> Self {
-        use std::cmp::{max, min};
-        let capacity = min(max(size, MIN_BUFSIZE), MAX_BUFSIZE);
+        let capacity = size.clamp(MIN_BUFSIZE, MAX_BUFSIZE);
         Self {
             reader,
             err: None,
+            max_line: capacity,
             buf: Buffer {
                 red: 0,
                 filled: 0,
@@ -53,43 +56,62 @@ impl<'a> BullshitScanner<'a> {
         }
     }
 
-    pub fn next_line(&mut self) -> Result<(String, usize)> {
-        if self.cannot_read_anymore() {
-            return Err(self.err.clone().unwrap());
+    /// Limits the length of the lines, line terminator included. Longer lines
+    /// fail with [BullshitError::LineTooLong], without being read any further than
+    /// the limit. Lines never get longer than the buffer, which is the limit
+    /// by default.
+    pub fn max_line_length(self, max: usize) -> Self {
+        Self {
+            max_line: max.clamp(1, self.buf.bites.len()),
+            ..self
         }
-        self.load_empty();
+    }
 
-        let buf = &self.buf.bites[self.buf.red..];
-        match Self::scan_line(buf) {
-            Ok((line, n)) => {
-                self.buf.red += n;
-                Ok((line, n))
-            }
-            Err(e) => {
-                if let Some(e) = self.err.clone() {
-                    // We have reached EOF and there are no lines left in the
-                    // buffer
-                    return Err(e);
-                }
+    pub fn next_line(&mut self) -> Result<(String, usize)> {
+        self.next_line_ref()
+            .map(|(line, n)| (String::from(line), n))
+    }
 
-                if self.buf.red == 0 {
-                    // Then our buffer is not big enough to handle the line
-                    return Err(Rc::new(
-                        BullshitError::new()
-                            .wrap(Box::new(e))
-                            .msg("buffer is not big enough"),
-                    ));
-                }
+    /// Like [BullshitScanner::next_line], but the line is borrowed from the
+    /// scanner's buffer instead of copied, so it has to be dropped before the
+    /// scanner is used again
+    pub fn next_line_ref(&mut self) -> Result<(&str, usize)> {
+        let (start, n) = self.find_line()?;
+        let line =
+            std::str::from_utf8(&self.buf.bites[start..start + n]).map_err(BullshitError::Utf8)?;
+        Ok((line.trim_end_matches(['\r', '\n']), n))
+    }
+
+    /// Consumes the next line and returns where it starts in the buffer, and
+    /// the number of bytes it takes up
+    fn find_line(&mut self) -> Result<(usize, usize)> {
+        loop {
+            if self.cannot_read_anymore() {
+                return Err(self.err.clone().unwrap());
+            }
+            self.load_empty();
+
+            let buf = &self.buf.bites[self.buf.red..self.buf.filled];
+            let limit = buf.len().min(self.max_line);
+            if let Some(i) = memchr::memchr(b'\n', &buf[..limit]) {
+                let start = self.buf.red;
+                self.buf.red += i + 1;
+                return Ok((start, i + 1));
+            }
 
-                // Otherwise, we may need to load more data. Discard the read
-                // portion of the buffer and read from the socket again
-                self.load();
+            if let Some(e) = self.err.clone() {
+                // We have reached EOF and there are no lines left in the
+                // buffer
+                return Err(e);
+            }
 
-                // After loading, we can retry this operation. Recursion is
-                // broken by the branches at the top of this scope - this
-                // function should not recurse more than once
-                self.next_line()
+            if buf.len() >= self.max_line {
+                return Err(BullshitError::LineTooLong { max: self.max_line });
             }
+
+            // Otherwise, the line has only partly arrived. Discard the read
+            // portion of the buffer and read from the socket again
+            self.load();
         }
     }
 
@@ -112,6 +134,12 @@ impl<'a> BullshitScanner<'a> {
         self.next_byte()
     }
 
+    /// The bytes that were read from the reader but not yet from the
+    /// scanner. They come before whatever the reader returns next.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf.bites[self.buf.red..self.buf.filled]
+    }
+
     fn cannot_read_anymore(&self) -> bool {
         self.err.is_some() && self.buf.red == self.buf.filled
     }
@@ -119,12 +147,7 @@ impl<'a> BullshitScanner<'a> {
     /// Discards the unread portion of the buffer and loads more data from the
     /// reader
     fn load(&mut self) {
-        use std::cmp::min;
-
-        let cap = self.buf.bites.len();
-        let (left, right) = self.buf.bites[..cap].split_at_mut(self.buf.red);
-        let n = min(left.len(), right.len());
-        left[..n].copy_from_slice(&right[..n]);
+        self.buf.bites.copy_within(self.buf.red..self.buf.filled, 0);
         self.buf.filled -= self.buf.red;
         self.buf.red = 0;
 
@@ -133,12 +156,12 @@ impl<'a> BullshitScanner<'a> {
             Ok(n) => {
                 self.buf.filled += n;
                 if n == 0 {
-                    self.err = Some(Rc::new(BullshitError::new().msg("EOF")));
+                    self.err = Some(BullshitError::Eof);
                 }
             }
 
             // Register the error on the scanner
-            Err(e) => self.err = Some(Rc::new(BullshitError::new().wrap(Box::new(e)))),
+            Err(e) => self.err = Some(BullshitError::from(e)),
         }
     }
 
@@ -148,18 +171,6 @@ impl<'a> BullshitScanner<'a> {
         }
     }
 
-    fn scan_line(buf: &[u8]) -> core::result::Result<(String, usize), BullshitError> {
-        use std::str::from_utf8;
-        for (i, b) in buf.iter().enumerate() {
-            if *b == b'\n' {
-                let err = from_utf8(&buf[..i]).map_err(|e| BullshitError::wrapping(Box::new(e)))?;
-                return Ok((String::from(err.trim_end_matches(['\r', '\n'])), i + 1));
-            }
-        }
-
-        Err(BullshitError::new().msg(&format!("read {} bytes without a newline", buf.len())))
-    }
-
     /// Note that the iterator will stop once there are no more newline
     /// delimited tokens in the string - there may still be some bytes left, the
     /// [BullshitScanner] is meant to provide fine-grained control over reading.
@@ -173,7 +184,7 @@ impl<'a> BullshitScanner<'a> {
     ///
     /// I named this function [`bites`](`BullshitScanner::bites`) just so that
     /// it is a bit easier to call without confusing [Read::bytes]
-    pub fn bites(&'a mut self) -> iterators::Bytes<&'a mut BullshitScanner> {
+    pub fn bites(&'a mut self) -> iterators::Bytes<&'a mut BullshitScanner<'a>> {
         iterators::Bytes { inner: self }
     }
 }
@@ -230,15 +241,15 @@ impl<'a> Read for BullshitScanner<'a> {
 
             // Copy as many bytes as possible from the internal buffer
             let src = &self.buf.bites[self.buf.red..self.buf.filled];
-            let n = std::cmp::min(src.len(), buf.len());
+            let n = std::cmp::min(src.len(), buf.len() - red);
             let src = &src[..n];
             let buff = &mut buf[red..red + n];
             buff.copy_from_slice(src);
             self.buf.red += n;
             red += n;
 
-            // If we have satisfied the read request, then we are done
-            if red == buf.len() {
+            // Hand over what we have rather than wait on the reader for more
+            if red > 0 {
                 break;
             }
 
@@ -251,81 +262,58 @@ impl<'a> Read for BullshitScanner<'a> {
 }
 
 pub mod errors {
-    use std::rc::Rc;
-
-    pub static EOF: &str = "EOF";
-
-    pub type Result<T> = core::result::Result<T, Rc<BullshitError>>;
-    pub type WrappableError = Box<dyn std::error::Error>;
-
-    /// An simple error returned by the [`super::BufferedScanner`]. [Error] may
-    /// wrap [`std::io::Error`], and it may include a message.
-    #[derive(Debug)]
-    pub struct BullshitError {
-        msg: Option<String>,
-        err: Option<WrappableError>,
+    use std::{fmt, io, str::Utf8Error, sync::Arc};
+
+    pub type Result<T> = core::result::Result<T, BullshitError>;
+
+    /// An error returned by the [`super::BullshitScanner`]. Once the reader
+    /// fails, the scanner returns the same error on every call, so the error
+    /// is cheap to clone. It is also [Send], so it can be handed to another
+    /// thread.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum BullshitError {
+        /// The reader has no more bytes, and there is no line left in the
+        /// buffer
+        Eof,
+
+        /// Reading failed. An [io::Error] can't be cloned, so its kind and
+        /// message are kept instead.
+        Io { kind: io::ErrorKind, msg: Arc<str> },
+
+        /// A line did not end within the scanner's
+        /// [maximum line length](super::BullshitScanner::max_line_length)
+        LineTooLong { max: usize },
+
+        /// A line is not valid UTF-8. The line is skipped.
+        Utf8(Utf8Error),
     }
 
-    impl BullshitError {
-        pub fn new() -> Self {
-            Self {
-                msg: None,
-                err: None,
-            }
-        }
-
-        pub fn with_msg(msg: String) -> Self {
-            Self::of(Some(msg), None)
-        }
-
-        pub fn wrapping(err: Box<dyn std::error::Error>) -> Self {
-            Self::of(None, Some(err))
-        }
-
-        pub fn of(msg: Option<String>, err: Option<Box<dyn std::error::Error>>) -> Self {
-            Self { msg, err }
-        }
-
-        /// Returns an EOF error
-        pub fn default() -> Self {
-            Self::new().msg(EOF)
-        }
-
-        /// Transforms the message of this error
-        pub fn msg(self, msg: &str) -> Self {
-            Self {
-                msg: Some(String::from(msg)),
-                ..self
+    impl From<io::Error> for BullshitError {
+        fn from(err: io::Error) -> Self {
+            Self::Io {
+                kind: err.kind(),
+                msg: Arc::from(err.to_string()),
             }
         }
-
-        pub fn wrap(self, err: Box<dyn std::error::Error>) -> Self {
-            Self {
-                err: Some(err),
-                ..self
-            }
-        }
-    }
-
-    impl Default for BullshitError {
-        fn default() -> Self {
-            Self::new()
-        }
     }
 
     impl std::error::Error for BullshitError {
         fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-            Some(self.err.as_ref()?.as_ref())
+            match self {
+                Self::Utf8(err) => Some(err),
+                _ => None,
+            }
         }
     }
 
-    impl std::fmt::Display for BullshitError {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(
-                f,
-                "{}",
-                self.msg.clone().unwrap_or_else(|| String::from(""))
-            )
+    impl fmt::Display for BullshitError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Eof => write!(f, "EOF"),
+                Self::Io { msg, .. } => write!(f, "{}", msg),
+                Self::LineTooLong { max } => write!(f, "line is longer than {} bytes", max),
+                Self::Utf8(err) => write!(f, "line is not valid UTF-8: {}", err),
+            }
         }
     }
 }
@@ -333,7 +321,11 @@ pub mod errors {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::{io::Read, str::from_utf8};
+    use std::{
+        error::Error,
+        io::{ErrorKind, Read},
+        str::from_utf8,
+    };
 
     const BUFSIZES: [usize; 8] = [1, 1 << 1, 1 << 2, 1 << 4, 1 << 6, 1 << 10, 1 << 20, 1 << 27];
 
@@ -422,4 +414,122 @@ mod tests {
         let out = scnr.lines().map(|l| l.0).collect::<String>();
         assert_eq!(data.replace('\n', "").trim_end(), out);
     }
+
+    #[test]
+    fn test_next_line_ref() {
+        let mut reader = &b"first\r\nsecond\n\nbad \xff\nafter\n"[..];
+        let mut scnr = BullshitScanner::new(&mut reader);
+        assert_eq!(("first", 7), scnr.next_line_ref().unwrap());
+        assert_eq!(("second", 7), scnr.next_line_ref().unwrap());
+        assert_eq!(("", 1), scnr.next_line_ref().unwrap());
+        assert!(matches!(scnr.next_line_ref(), Err(BullshitError::Utf8(_))));
+        assert_eq!(("after", 6), scnr.next_line_ref().unwrap());
+        assert!(scnr.next_line_ref().is_err());
+    }
+
+    #[test]
+    fn test_buffered() {
+        let mut reader = stringreader::StringReader::new("line\r\nrest of it");
+        let mut scnr = BullshitScanner::new(&mut reader);
+        assert_eq!("line", scnr.next_line().unwrap().0);
+        assert_eq!(b"rest of it", scnr.buffered());
+        assert_eq!(b'r', scnr.next_byte().unwrap());
+        assert_eq!(b"est of it", scnr.buffered());
+    }
+
+    /// Returns at most `.1` bytes per read, like a slow socket
+    struct Trickle<'a>(&'a [u8], usize);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.1.min(buf.len()).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_lines_split_across_reads() {
+        let data = (0..20)
+            .map(|i| format!("line number {}\r\n", "x".repeat(i)))
+            .collect::<String>();
+        for per_read in [1, 2, 5, 13, 40, 64] {
+            let mut reader = Trickle(data.as_bytes(), per_read);
+            let mut scnr = BullshitScanner::with_capacity(&mut reader, MIN_BUFSIZE);
+            for i in 0..20 {
+                let want = format!("line number {}", "x".repeat(i));
+                assert_eq!(want, scnr.next_line().unwrap().0, "{}", per_read);
+            }
+            assert!(scnr.next_line().is_err());
+        }
+
+        // Reads that come up short hand over what they have, and never copy
+        // past the end of the buffer
+        let data = b"0123456789abcd";
+        let mut reader = data[..4].chain(&data[4..]);
+        let mut scnr = BullshitScanner::new(&mut reader);
+        let mut buf = [0; 10];
+        assert_eq!(4, scnr.read(&mut buf).unwrap());
+        assert_eq!(b"0123", &buf[..4]);
+        assert_eq!(10, scnr.read(&mut buf).unwrap());
+        assert_eq!(b"456789abcd", &buf);
+        assert_eq!(0, scnr.read(&mut buf).unwrap());
+
+        // A line that doesn't fit is still an error
+        let long = format!("{}\n", "x".repeat(MIN_BUFSIZE + 1));
+        let mut reader = Trickle(long.as_bytes(), 3);
+        let mut scnr = BullshitScanner::with_capacity(&mut reader, MIN_BUFSIZE);
+        assert_eq!(
+            BullshitError::LineTooLong { max: MIN_BUFSIZE },
+            scnr.next_line().unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_max_line_length() {
+        let data = "12345678\r\n123456789\r\n";
+        let mut reader = Trickle(data.as_bytes(), 4);
+        let mut scnr = BullshitScanner::new(&mut reader).max_line_length(10);
+        assert_eq!(("12345678", 10), scnr.next_line_ref().unwrap());
+        let err = scnr.next_line_ref().unwrap_err();
+        assert_eq!(BullshitError::LineTooLong { max: 10 }, err);
+        assert_eq!("line is longer than 10 bytes", err.to_string());
+
+        // Other errors are not mistaken for it
+        let mut reader = "no newline".as_bytes();
+        let mut scnr = BullshitScanner::new(&mut reader).max_line_length(64);
+        assert_eq!(BullshitError::Eof, scnr.next_line().unwrap_err());
+    }
+
+    /// Reads `.0`, then fails every read after it
+    struct Broken<'a>(&'a [u8]);
+
+    impl Read for Broken<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(std::io::Error::new(ErrorKind::ConnectionReset, "reset"));
+            }
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut reader = Broken(b"line\npartial");
+        let mut scnr = BullshitScanner::new(&mut reader);
+        assert_eq!("line", scnr.next_line().unwrap().0);
+
+        // The error sticks, and can be matched on and sent to other threads
+        let err = scnr.next_line().unwrap_err();
+        assert!(
+            matches!(&err, BullshitError::Io { kind: ErrorKind::ConnectionReset, msg } if &**msg == "reset"),
+            "{:?}",
+            err
+        );
+        assert_eq!(err, scnr.next_line().unwrap_err());
+        let sent = std::thread::spawn(move || err).join().unwrap();
+        assert_eq!("reset", sent.to_string());
+        assert!(sent.source().is_none());
+    }
 }
@@ -0,0 +1,224 @@
+//!
+//! Finding httpfs servers on the local network. A server with
+//! [Server::announce](crate::server::Server::announce) set sends a small UDP
+//! beacon every [ANNOUNCE_INTERVAL], usually to the broadcast address, and a
+//! [Discovery] listening on the same port collects the beacons it hears.
+//!
+//! A beacon is a single line of text:
+//!
+//! ```text
+//! HTTPFS1 <transport> <port> <name>
+//! ```
+//!
+//! The address of the server is the source address of the datagram, with the
+//! port taken from the beacon.
+//!
+
+use std::{
+    collections::HashSet,
+    fmt::{self, Display, Formatter},
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    str,
+    time::{Duration, Instant},
+};
+
+/// The UDP port beacons are sent to by default
+pub const DEFAULT_DISCOVERY_PORT: u16 = 8699;
+
+/// The default destination for beacons, the limited broadcast address
+pub const DEFAULT_ANNOUNCE_ADDR: SocketAddr = SocketAddr::new(
+    std::net::IpAddr::V4(Ipv4Addr::BROADCAST),
+    DEFAULT_DISCOVERY_PORT,
+);
+
+/// How often a server sends a beacon
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+
+const MAGIC: &str = "HTTPFS1";
+
+/// The largest beacon that will be sent or parsed
+const MAX_BEACON_SIZE: usize = 512;
+
+/// What a server says about itself
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Beacon {
+    /// The transport the server listens on, e.g. `tcp`
+    pub transport: String,
+    pub port: u16,
+
+    /// A human readable name, by default the name of the served directory
+    pub name: String,
+}
+
+impl Beacon {
+    /// Parses a beacon, returning [None] for anything that isn't one
+    pub fn parse(datagram: &[u8]) -> Option<Self> {
+        if datagram.len() > MAX_BEACON_SIZE {
+            return None;
+        }
+        let line = str::from_utf8(datagram).ok()?.trim_end_matches('\n');
+        let mut parts = line.splitn(4, ' ');
+        if parts.next()? != MAGIC {
+            return None;
+        }
+        let transport = parts.next()?;
+        let port = parts.next()?.parse().ok()?;
+        let name = parts.next().unwrap_or_default();
+        if transport.is_empty() || name.contains('\n') {
+            return None;
+        }
+        Some(Self {
+            transport: String::from(transport),
+            port,
+            name: String::from(name),
+        })
+    }
+}
+
+impl Display for Beacon {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} {} {} {}",
+            MAGIC, self.transport, self.port, self.name
+        )
+    }
+}
+
+/// A server heard by a [Discovery]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Discovered {
+    /// Where the server accepts connections
+    pub addr: SocketAddr,
+    pub beacon: Beacon,
+}
+
+impl Display for Discovered {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}://{}  {}",
+            if self.beacon.transport == "tcp" {
+                "http"
+            } else {
+                self.beacon.transport.as_str()
+            },
+            self.addr,
+            self.beacon.name
+        )
+    }
+}
+
+/// Listens for beacons. Bind it before the servers are expected to announce
+/// themselves, then call [Discovery::collect].
+pub struct Discovery {
+    socket: UdpSocket,
+}
+
+impl Discovery {
+    /// Binds to `port` on all interfaces
+    pub fn bind(port: u16) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?,
+        })
+    }
+
+    /// Listens for `timeout` and returns each server heard, once, in the order
+    /// they were first heard. Datagrams that are not beacons are ignored.
+    pub fn collect(&self, timeout: Duration) -> io::Result<Vec<Discovered>> {
+        let deadline = Instant::now() + timeout;
+        let mut seen = HashSet::new();
+        let mut found = Vec::new();
+        let mut buf = [0; MAX_BEACON_SIZE + 1];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(found);
+            }
+            self.socket.set_read_timeout(Some(left))?;
+            let (n, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(found)
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(beacon) = Beacon::parse(&buf[..n]) {
+                let server = Discovered {
+                    addr: SocketAddr::new(from.ip(), beacon.port),
+                    beacon,
+                };
+                if seen.insert(server.clone()) {
+                    found.push(server);
+                }
+            }
+        }
+    }
+}
+
+/// Sends `beacon` to `to` every [ANNOUNCE_INTERVAL] from a background thread,
+/// until `exit` is set
+#[cfg(feature = "server")]
+pub(crate) fn announce(
+    beacon: Beacon,
+    to: SocketAddr,
+    exit: std::sync::Arc<std::sync::atomic::AtomicBool>,
+) -> io::Result<()> {
+    use std::{sync::atomic::Ordering, thread};
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    let datagram = beacon.to_string();
+    thread::spawn(move || {
+        let tick = Duration::from_millis(50);
+        while !exit.load(Ordering::SeqCst) {
+            if let Err(e) = socket.send_to(datagram.as_bytes(), to) {
+                log::debug!("Failed to send beacon to {}: {}", to, e);
+            }
+            let next = Instant::now() + ANNOUNCE_INTERVAL;
+            while Instant::now() < next && !exit.load(Ordering::SeqCst) {
+                thread::sleep(tick);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beacon_round_trip() {
+        let beacon = Beacon {
+            transport: String::from("tcp"),
+            port: 8080,
+            name: String::from("my files"),
+        };
+        assert_eq!("HTTPFS1 tcp 8080 my files\n", beacon.to_string());
+        assert_eq!(
+            Some(beacon.clone()),
+            Beacon::parse(beacon.to_string().as_bytes())
+        );
+    }
+
+    #[test]
+    fn test_not_a_beacon() {
+        for datagram in [
+            &b""[..],
+            b"HTTPFS2 tcp 8080 files\n",
+            b"HTTPFS1 tcp port files\n",
+            b"HTTPFS1 tcp 99999 files\n",
+            b"HTTPFS1\n",
+            b"\xff\xfe",
+        ] {
+            assert_eq!(None, Beacon::parse(datagram), "{:?}", datagram);
+        }
+    }
+}
@@ -2,70 +2,261 @@ use std::{
     any::type_name,
     error::Error,
     fmt::{self, Display, Formatter},
+    io,
+    num::TryFromIntError,
 };
 
-/// This is the catch-all error returned the library. It provides factory
-/// functions that give the error a different print out. There is no way to
-/// distinguish between the errors created by the factory functions; they are
-/// all ServerErrors.
+use crate::{bullshit_scanner::errors::BullshitError, status::StatusCode};
+
+/// This is the catch-all error returned by the library. The variant tells what
+/// kind of failure occurred, and each variant carries a [Context] with a
+/// message and optionally the error that caused it, which is exposed through
+/// [Error::source] so the whole chain can be inspected.
 #[derive(Debug)]
-pub struct ServerError {
+pub enum ServerError {
+    /// The requested file does not exist
+    NotFound(Context),
+
+    /// The client is not allowed to do what it asked for, e.g. reading a file
+    /// outside the served directory
+    Forbidden(Context),
+
+    /// The request is malformed or asks for something that is not supported
+    BadRequest(Context),
+
+    /// The request line is longer than the server reads
+    UriTooLong(Context),
+
+    /// A request header is longer than the server reads
+    HeaderFieldsTooLarge(Context),
+
+    /// The request is for an HTTP version other than 1.0 and 1.1
+    VersionNotSupported(Context),
+
+    /// The request can't be carried out while another one is using the same
+    /// file, e.g. an upload to a file that is being uploaded
+    Conflict(Context),
+
+    /// The request has a body whose length isn't given by `Content-Length`,
+    /// where the server needs to know it up front, e.g. to proxy it
+    LengthRequired(Context),
+
+    /// The request asks for a feature the server does not have, e.g. to be
+    /// proxied
+    NotImplemented(Context),
+
+    /// An upload would take the served directory over its quota
+    InsufficientStorage(Context),
+
+    /// The upstream server of a proxied request could not be reached, or the
+    /// request could not be sent to it
+    BadGateway(Context),
+
+    /// The upstream server of a proxied request took longer than
+    /// [Server::proxy_timeout](crate::server::Server::proxy_timeout) to
+    /// accept the request or to answer it
+    GatewayTimeout(Context),
+
+    /// Reading or writing a file on the server failed
+    Io(Context),
+
+    /// Reading from or writing to the connection failed
+    Transport(Context),
+
+    /// Anything else
+    Internal(Context),
+}
+
+/// The message and source error carried by a [ServerError]
+#[derive(Debug, Default)]
+pub struct Context {
+    pub msg: String,
+
     /// Optional source error
-    src: Option<Box<dyn Error>>,
-    msg: String,
+    pub src: Option<Box<dyn Error>>,
 }
 
 impl ServerError {
-    /// An empty ServerError
+    /// An empty [ServerError::Internal]
     pub fn new() -> Self {
-        Self {
-            src: None,
-            msg: String::from(""),
+        Self::Internal(Context::default())
+    }
+
+    pub fn msg(mut self, msg: &str) -> Self {
+        self.context_mut().msg = String::from(msg);
+        self
+    }
+
+    pub fn wrap(mut self, err: Box<dyn Error>) -> Self {
+        self.context_mut().src = Some(err);
+        self
+    }
+
+    pub fn context(&self) -> &Context {
+        match self {
+            Self::NotFound(ctx)
+            | Self::Forbidden(ctx)
+            | Self::BadRequest(ctx)
+            | Self::UriTooLong(ctx)
+            | Self::HeaderFieldsTooLarge(ctx)
+            | Self::VersionNotSupported(ctx)
+            | Self::Conflict(ctx)
+            | Self::LengthRequired(ctx)
+            | Self::NotImplemented(ctx)
+            | Self::InsufficientStorage(ctx)
+            | Self::BadGateway(ctx)
+            | Self::GatewayTimeout(ctx)
+            | Self::Io(ctx)
+            | Self::Transport(ctx)
+            | Self::Internal(ctx) => ctx,
         }
     }
 
-    pub fn msg(self, msg: &str) -> Self {
-        Self {
-            msg: String::from(msg),
-            ..self
+    fn context_mut(&mut self) -> &mut Context {
+        match self {
+            Self::NotFound(ctx)
+            | Self::Forbidden(ctx)
+            | Self::BadRequest(ctx)
+            | Self::UriTooLong(ctx)
+            | Self::HeaderFieldsTooLarge(ctx)
+            | Self::VersionNotSupported(ctx)
+            | Self::Conflict(ctx)
+            | Self::LengthRequired(ctx)
+            | Self::NotImplemented(ctx)
+            | Self::InsufficientStorage(ctx)
+            | Self::BadGateway(ctx)
+            | Self::GatewayTimeout(ctx)
+            | Self::Io(ctx)
+            | Self::Transport(ctx)
+            | Self::Internal(ctx) => ctx,
         }
     }
 
-    pub fn wrap(self, err: Box<dyn Error>) -> Self {
-        Self {
-            src: Some(err),
-            ..self
+    /// The HTTP status that should be sent to the client when a request fails
+    /// with this error
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::UriTooLong(_) => StatusCode::URI_TOO_LONG,
+            Self::HeaderFieldsTooLarge(_) => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Self::VersionNotSupported(_) => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::LengthRequired(_) => StatusCode::LENGTH_REQUIRED,
+            Self::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            Self::InsufficientStorage(_) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            Self::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Io(_) | Self::Transport(_) | Self::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
         }
     }
 
+    pub fn not_found(path: &str) -> Self {
+        Self::NotFound(Context::default()).msg(path)
+    }
+
+    pub fn forbidden(msg: &str) -> Self {
+        Self::Forbidden(Context::default()).msg(msg)
+    }
+
+    pub fn bad_request(err: impl Error + 'static) -> Self {
+        Self::BadRequest(Context::default()).wrap(Box::new(err))
+    }
+
+    pub fn uri_too_long(msg: &str) -> Self {
+        Self::UriTooLong(Context::default()).msg(msg)
+    }
+
+    pub fn header_fields_too_large(msg: &str) -> Self {
+        Self::HeaderFieldsTooLarge(Context::default()).msg(msg)
+    }
+
+    pub fn version_not_supported(err: impl Error + 'static) -> Self {
+        Self::VersionNotSupported(Context::default()).wrap(Box::new(err))
+    }
+
+    pub fn conflict(msg: &str) -> Self {
+        Self::Conflict(Context::default()).msg(msg)
+    }
+
+    pub fn length_required(msg: &str) -> Self {
+        Self::LengthRequired(Context::default()).msg(msg)
+    }
+
+    pub fn not_implemented(msg: &str) -> Self {
+        Self::NotImplemented(Context::default()).msg(msg)
+    }
+
+    pub fn insufficient_storage(msg: &str) -> Self {
+        Self::InsufficientStorage(Context::default()).msg(msg)
+    }
+
+    pub fn bad_gateway(msg: &str) -> Self {
+        Self::BadGateway(Context::default()).msg(msg)
+    }
+
+    pub fn gateway_timeout(msg: &str) -> Self {
+        Self::GatewayTimeout(Context::default()).msg(msg)
+    }
+
+    pub fn io(err: io::Error) -> Self {
+        Self::Io(Context::default()).wrap(Box::new(err))
+    }
+
+    pub fn transport(err: impl Error + 'static) -> Self {
+        Self::Transport(Context::default()).wrap(Box::new(err))
+    }
+
     pub fn malformed_request() -> Self {
-        Self::wrap_err(MalformedRequestError(None))
+        Self::bad_request(MalformedRequestError(None))
     }
 
     pub fn unsupported_proto() -> Self {
-        Self::wrap_err(UnsupportedProtoError(None))
+        Self::version_not_supported(UnsupportedProtoError(None))
     }
 
     pub fn unsupported_method() -> Self {
-        Self::wrap_err(UnsupportedMethodError(None))
+        Self::bad_request(UnsupportedMethodError(None))
     }
 
     pub fn writing_to_directory() -> Self {
-        Self::wrap_err(WritingToDirectoryError(None))
+        Self::Forbidden(Context::default()).wrap(Box::new(WritingToDirectoryError(None)))
     }
 
     pub fn writing_to_symlink() -> Self {
-        Self::wrap_err(WritingToSymlinkError(None))
+        Self::Forbidden(Context::default()).wrap(Box::new(WritingToSymlinkError(None)))
     }
 
     pub fn wrapping(err: Box<dyn Error>) -> Self {
-        let msg = format!("{}: {}", type_name::<Self>(), err);
-        Self::new().wrap(err).msg(&msg)
+        Self::new().wrap(err)
     }
 
     pub fn wrap_err(err: impl Error + 'static) -> Self {
         Self::wrapping(Box::new(err))
     }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::NotFound(_) => "Not found",
+            Self::Forbidden(_) => "Forbidden",
+            Self::BadRequest(_) => "Bad request",
+            Self::UriTooLong(_) => "URI too long",
+            Self::HeaderFieldsTooLarge(_) => "Header fields too large",
+            Self::VersionNotSupported(_) => "HTTP version not supported",
+            Self::Conflict(_) => "Conflict",
+            Self::LengthRequired(_) => "Length required",
+            Self::NotImplemented(_) => "Not implemented",
+            Self::InsufficientStorage(_) => "Insufficient storage",
+            Self::BadGateway(_) => "Bad gateway",
+            Self::GatewayTimeout(_) => "Gateway timeout",
+            Self::Io(_) => "I/O error",
+            Self::Transport(_) => "Transport error",
+            Self::Internal(_) => "Internal error",
+        }
+    }
 }
 
 impl Default for ServerError {
@@ -76,12 +267,48 @@ impl Default for ServerError {
 
 impl Display for ServerError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.msg)
+        let ctx = self.context();
+        write!(f, "{}", self.kind())?;
+        if !ctx.msg.is_empty() {
+            write!(f, ": {}", ctx.msg)?;
+        }
+        if let Some(src) = &ctx.src {
+            write!(f, ": {}", src)?;
+        }
+        Ok(())
     }
 }
+
 impl Error for ServerError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        Some(self.src.as_ref()?.as_ref())
+        Some(self.context().src.as_ref()?.as_ref())
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> Self {
+        Self::io(err)
+    }
+}
+
+impl From<HttpParseError> for ServerError {
+    fn from(err: HttpParseError) -> Self {
+        Self::bad_request(err)
+    }
+}
+
+impl From<BullshitError> for ServerError {
+    /// Scanner errors come from reading the connection
+    fn from(err: BullshitError) -> Self {
+        Self::transport(err)
+    }
+}
+
+impl From<TryFromIntError> for ServerError {
+    fn from(err: TryFromIntError) -> Self {
+        Self::new()
+            .msg("bad numerical conversion")
+            .wrap(Box::new(err))
     }
 }
 
@@ -89,6 +316,7 @@ impl Error for ServerError {
 super::basic_error!(MalformedRequestError, "Malformed request");
 super::basic_error!(UnsupportedProtoError, "Unsupported protocol");
 super::basic_error!(UnsupportedMethodError, "Unsupported HTTP method");
+super::basic_error!(WebSocketHandshakeError, "Invalid WebSocket handshake");
 super::basic_error!(WritingToDirectoryError, "File exists and is a directory");
 super::basic_error!(WritingToSymlinkError, "File exists and is a symlink");
 
@@ -109,7 +337,6 @@ impl Display for HttpParseError {
 
 impl Error for HttpParseError {}
 
-pub use self::macros::*;
 mod macros {
     /// A macro for generating basic errors containing a fixed string message
     /// with the option to append a custom string to the message when the error
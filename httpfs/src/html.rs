@@ -1,16 +1,71 @@
-///
-/// This module contains the webpage stuff for the dir listing of the file
-/// server
-///
-
-/// Template generation - insert a list of file names as links into our html doc
-pub fn template(files: impl IntoIterator<Item = String>) -> String {
-    let links = files
-        .into_iter()
-        .map(|file| format!("    <a href=\"{}\">{}</a>\n", file, file))
-        .collect::<String>();
-
-    HTML.replacen("    {LINKS}", links.as_str(), 1)
+//!
+//! This module contains the webpage stuff for the dir listing of the file
+//! server
+//!
+
+use std::io::{self, Write};
+
+use crate::parse::percent_encode;
+
+/// Template generation - insert a list of file names as links into our html
+/// doc, and links to the pages before and after it if the listing has more
+/// than one
+pub fn template(
+    files: impl IntoIterator<Item = String>,
+    prev: Option<&str>,
+    next: Option<&str>,
+) -> String {
+    let mut out = Vec::new();
+    write_template(&mut out, files, prev, next).expect("writing to a Vec can't fail");
+    String::from_utf8(out).expect("the template is UTF-8")
+}
+
+/// Like [template], but writes the doc to `out` a link at a time instead of
+/// building it in memory
+pub fn write_template(
+    out: &mut impl Write,
+    files: impl IntoIterator<Item = String>,
+    prev: Option<&str>,
+    next: Option<&str>,
+) -> io::Result<()> {
+    let (head, rest) = HTML.split_once("    {LINKS}\n").expect("HTML has {LINKS}");
+    let (middle, tail) = rest.split_once("    {NAV}\n").expect("HTML has {NAV}");
+
+    out.write_all(head.as_bytes())?;
+    for file in files {
+        writeln!(
+            out,
+            "    <a href=\"{}\">{}</a>",
+            percent_encode(&file),
+            escape(&file)
+        )?;
+    }
+
+    out.write_all(middle.as_bytes())?;
+    let nav = [
+        ("prev", "&larr; previous", prev),
+        ("next", "next &rarr;", next),
+    ];
+    for (rel, text, query) in nav {
+        if let Some(query) = query {
+            writeln!(
+                out,
+                "    <a rel=\"{}\" href=\"{}\">{}</a>",
+                rel,
+                escape(query),
+                text
+            )?;
+        }
+    }
+    out.write_all(tail.as_bytes())
+}
+
+/// Escapes the characters that are special in HTML text
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 /// This is the html document that is returned by the dir listing function
@@ -83,6 +138,9 @@ pub const HTML: &str = r#"
     <a href="..">../</a>
     {LINKS}
   </p>
+  <nav>
+    {NAV}
+  </nav>
   <div id="drop-zone" ondrop="dropHandler(event);" ondragover="dragOverHandler(event);">
     <p>Drag and Drop</p>
   </div>
@@ -1,5 +1,49 @@
-pub mod bullshit_scanner;
+//!
+//! httpfs is a small HTTP/1.1 file server. It serves the files in a directory,
+//! accepts uploads into it, and can be embedded in another program:
+//!
+//! ```no_run
+//! # #[cfg(feature = "server")] {
+//! let handle = httpfs::Server {
+//!     dir: String::from("./public"),
+//!     port: 8080,
+//!     ..Default::default()
+//! }
+//! .serve()?;
+//! handle.join()?;
+//! # }
+//! # Ok::<(), httpfs::ServerError>(())
+//! ```
+//!
+//! The server is behind the `server` feature, which the default `cli` feature
+//! (the httpfs binary) enables. With `default-features = false` the crate
+//! only depends on `log` and `memchr` (and `libc` on unix), and provides the
+//! request parser, the error types and the transport traits. The `wasm`
+//! feature adds `wasm::parse_http_request`, the parser for JavaScript.
+//!
+//! The modules that are not documented are implementation details. They are
+//! public for the binary and the integration tests, and may change in any
+//! release.
+//!
+
+pub mod discovery;
 pub mod errors;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod status;
+pub mod transport;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use errors::ServerError;
+#[cfg(feature = "server")]
+pub use server::{Handle, Server};
+pub use status::StatusCode;
+
+#[doc(hidden)]
+pub mod bullshit_scanner;
+#[cfg(feature = "server")]
+#[doc(hidden)]
 pub mod html;
+#[doc(hidden)]
 pub mod parse;
-pub mod server;
@@ -2,23 +2,28 @@ use std::{
     collections::HashMap,
     fmt::{Debug, Display},
     io::{Read, Take},
-    str,
+    str::{self, FromStr},
 };
 
 use crate::{
-    bullshit_scanner::BullshitScanner,
-    errors::{MalformedRequestError, ServerError, UnsupportedMethodError, UnsupportedProtoError},
+    bullshit_scanner::{errors::BullshitError, BullshitScanner},
+    errors::{
+        HttpParseError, MalformedRequestError, ServerError, UnsupportedMethodError,
+        UnsupportedProtoError,
+    },
 };
 
 const CONTENT_LENGTH: &str = "Content-Length";
+const CONTENT_TYPE: &str = "Content-Type";
 
 /// HTTP request methods
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum Method {
     GET,
     POST,
 
     /// Represents an request with an unsupported HTTP method
+    #[default]
     Unsupported,
 }
 
@@ -32,17 +37,14 @@ impl Method {
     }
 }
 
-impl Default for Method {
-    fn default() -> Self {
-        Method::Unsupported
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub enum Proto {
+    #[default]
     HTTP1_1,
     HTTP1_0,
-    Unsupported,
+
+    /// Any other version, as the client sent it, e.g. `HTTP/2.0`
+    Other(String),
 }
 
 impl Proto {
@@ -50,15 +52,22 @@ impl Proto {
         match string.to_lowercase().as_str() {
             "http/1.1" => Proto::HTTP1_1,
             "http/1.0" => Proto::HTTP1_0,
-            _ => Proto::Unsupported,
+            _ => Proto::Other(String::from(string)),
         }
     }
 }
 
-impl Default for Proto {
-    fn default() -> Self {
-        Proto::HTTP1_1
-    }
+/// How forgiving the parser is of requests that bend the syntax of RFC 7230
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Strictness {
+    /// Refuses lines that don't end with `\r\n`, headers folded over several
+    /// lines, and whitespace in or after header names
+    Strict,
+
+    /// Takes lines ending with a bare `\n`, unfolds folded headers, and trims
+    /// the whitespace around header names
+    #[default]
+    Lenient,
 }
 
 pub struct Request<R>
@@ -68,16 +77,32 @@ where
     pub proto: Proto,
     pub method: Method,
     pub file: String,
+
+    /// The query of the request target, as it was sent, without the `?`
+    pub query: Option<String>,
     pub headers: HashMap<String, String>,
     pub body: R,
 }
 
+impl<R: Read> Request<R> {
+    /// The value of the header, whatever the case of its name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// The parsed `Content-Type` header, if there is a valid one
+    pub fn content_type(&self) -> Option<MediaType> {
+        content_type(&self.headers)
+    }
+}
+
 impl<R: Read> Debug for Request<R> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Request")
             .field("proto", &self.proto)
             .field("method", &self.method)
             .field("file", &self.file)
+            .field("query", &self.query)
             .field("body", &"...")
             .finish()
     }
@@ -90,12 +115,19 @@ impl<R: Read> Display for Request<R> {
 }
 
 pub fn parse_http_request(
+    scnr: BullshitScanner,
+) -> Result<Request<Take<BullshitScanner>>, ServerError> {
+    parse_http_request_with(scnr, Strictness::default())
+}
+
+/// Like [parse_http_request], but as forgiving as `strictness` says
+pub fn parse_http_request_with(
     mut scnr: BullshitScanner,
+    strictness: Strictness,
 ) -> Result<Request<Take<BullshitScanner>>, ServerError> {
-    let (proto, method, file) = parse_request_line(&mut scnr)?;
-    let headers = parse_headers(&mut scnr)?;
-    let limit = headers
-        .get(CONTENT_LENGTH)
+    let (proto, method, file, query) = parse_request_line(&mut scnr, strictness)?;
+    let headers = parse_headers(&mut scnr, strictness)?;
+    let limit = header(&headers, CONTENT_LENGTH)
         .map(|l| l.parse::<u64>().ok().unwrap_or(0))
         .unwrap_or(0);
 
@@ -103,76 +135,652 @@ pub fn parse_http_request(
         proto,
         method,
         file,
+        query,
         headers,
         body: scnr.take(limit),
     })
 }
 
-fn parse_headers(scnr: &mut BullshitScanner) -> Result<HashMap<String, String>, ServerError> {
+/// Looks up a header, whatever the case of its name
+fn header<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn parse_headers(
+    scnr: &mut BullshitScanner,
+    strictness: Strictness,
+) -> Result<HashMap<String, String>, ServerError> {
     // Headers we read line-by-line
-    let mut headers = HashMap::with_capacity(64);
+    let mut headers: HashMap<String, String> = HashMap::with_capacity(64);
+    let mut last = None;
     loop {
-        let line = scnr.next_line().map(|l| l.0).map_err(|_| {
-            ServerError::new().wrap(Box::new(MalformedRequestError(Some(String::from(
-                "invalid request headers, headers must end with '\\r\\n'",
-            )))))
+        let (line, n) = scnr.next_line_ref().map_err(|e| match e {
+            BullshitError::LineTooLong { max } => ServerError::header_fields_too_large(&format!(
+                "request header is longer than {} bytes",
+                max
+            )),
+            _ => malformed("invalid request headers, headers must end with '\\r\\n'"),
         })?;
+        check_line_ending(line, n, strictness)?;
 
         if line.is_empty() {
             return Ok(headers);
         }
 
-        let (left, right) = line.split_once(':').ok_or_else(|| {
-            ServerError::new().wrap(Box::new(MalformedRequestError(Some(format!(
-                "failed to parse request header '{}'",
-                line
-            )))))
-        })?;
+        // A line starting with whitespace continues the header before it
+        if line.starts_with([' ', '\t']) {
+            let value = match (strictness, &last) {
+                (Strictness::Lenient, Some(name)) => headers.get_mut(name),
+                _ => None,
+            }
+            .ok_or_else(|| {
+                malformed(&format!(
+                    "request header '{}' is folded over several lines",
+                    line.trim()
+                ))
+            })?;
+            if !value.is_empty() {
+                value.push(' ');
+            }
+            value.push_str(line.trim());
+            continue;
+        }
+
+        let (left, right) = line
+            .split_once(':')
+            .ok_or_else(|| malformed(&format!("failed to parse request header '{}'", line)))?;
+        let name = match strictness {
+            Strictness::Strict if left.contains([' ', '\t']) => None,
+            _ => Some(left.trim()),
+        }
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| malformed(&format!("invalid request header name '{}'", left)))?;
+
+        headers.insert(String::from(name), String::from(right.trim()));
+        last = Some(String::from(name));
+    }
+}
 
-        headers.insert(String::from(left.trim()), String::from(right.trim()));
+/// In [Strictness::Strict], checks that a line of `n` bytes ended with
+/// `\r\n`, and not with a bare `\n` or extra `\r`s
+fn check_line_ending(line: &str, n: usize, strictness: Strictness) -> Result<(), ServerError> {
+    match strictness {
+        Strictness::Strict if n - line.len() != 2 => Err(malformed(&format!(
+            "line '{}' does not end with '\\r\\n'",
+            line
+        ))),
+        _ => Ok(()),
     }
 }
 
-fn parse_request_line(scnr: &mut BullshitScanner) -> Result<(Proto, Method, String), ServerError> {
-    let words = scnr
-        .next_line()
-        .map(|l| l.0)
-        .map_err(|e| ServerError::new().msg(&format!("{}", e)))?
+fn malformed(msg: &str) -> ServerError {
+    ServerError::bad_request(MalformedRequestError(Some(String::from(msg))))
+}
+
+/// Returns the protocol, the method, the decoded path and the query
+fn parse_request_line(
+    scnr: &mut BullshitScanner,
+    strictness: Strictness,
+) -> Result<(Proto, Method, String, Option<String>), ServerError> {
+    let (line, n) = scnr.next_line_ref().map_err(|e| match e {
+        BullshitError::LineTooLong { max } => {
+            ServerError::uri_too_long(&format!("request line is longer than {} bytes", max))
+        }
+        BullshitError::Utf8(_) => malformed("request line is not valid UTF-8"),
+        e => ServerError::from(e),
+    })?;
+    check_line_ending(line, n, strictness)?;
+    let words = line
         .split_whitespace()
         .map(String::from)
         .collect::<Vec<_>>();
 
     let map_err = |word| {
-        ServerError::wrapping(Box::new(MalformedRequestError(Some(format!(
+        ServerError::bad_request(MalformedRequestError(Some(format!(
             "no {} found in request line",
             word
-        )))))
+        ))))
     };
 
     let proto = (match words.get(2) {
         Some(proto) => match Proto::from(proto) {
-            Proto::Unsupported => Err(ServerError::wrapping(Box::new(UnsupportedProtoError(
-                Some(String::from(proto)),
-            )))),
+            Proto::Other(proto) => Err(ServerError::version_not_supported(UnsupportedProtoError(
+                Some(format!(
+                    "'{}', only HTTP/1.0 and HTTP/1.1 are supported",
+                    proto
+                )),
+            ))),
             proto => Ok(proto),
         },
         None => Err(map_err("protocol")),
     })?;
 
-    let method = (match words.get(0) {
+    let method = (match words.first() {
         Some(method) => match Method::from(method) {
-            Method::Unsupported => Err(ServerError::wrapping(Box::new(UnsupportedMethodError(
-                Some(String::from(method)),
+            Method::Unsupported => Err(ServerError::bad_request(UnsupportedMethodError(Some(
+                String::from(method),
             )))),
             method => Ok(method),
         },
         None => Err(map_err("method")),
     })?;
 
-    let path = (match words.get(1) {
-        Some(path) => Ok(String::from(path)),
+    let (path, query) = (match words.get(1).map(|t| RequestTarget::parse(t)) {
+        Some(RequestTarget::Origin(target)) => match target.split_once('?') {
+            Some((path, query)) => Ok((percent_decode(path)?, Some(String::from(query)))),
+            None => Ok((percent_decode(target)?, None)),
+        },
+        Some(RequestTarget::Absolute(uri)) => Err(ServerError::not_implemented(&format!(
+            "request target '{}' is in absolute-form, which only proxies accept",
+            uri
+        ))),
+        Some(RequestTarget::Authority(authority)) => Err(ServerError::bad_request(
+            MalformedRequestError(Some(format!(
+                "request target '{}' is in authority-form, which is only for CONNECT",
+                authority
+            ))),
+        )),
+        Some(RequestTarget::Asterisk) => {
+            Err(ServerError::bad_request(MalformedRequestError(Some(
+                String::from("request target '*' is in asterisk-form, which is only for OPTIONS"),
+            ))))
+        }
+        Some(RequestTarget::Invalid(target)) => Err(ServerError::bad_request(
+            MalformedRequestError(Some(format!("invalid request target '{}'", target))),
+        )),
         None => Err(map_err("path")),
     })?;
 
-    Ok((proto, method, path))
+    Ok((proto, method, path, query))
+}
+
+/// The forms a request target can take (RFC 9112, section 3.2). Only the
+/// origin-form is served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestTarget<'a> {
+    /// A path, e.g. `/dir/file.txt`
+    Origin(&'a str),
+
+    /// A whole URI, e.g. `http://example.com/file.txt`, sent to proxies
+    Absolute(&'a str),
+
+    /// A host and port, e.g. `example.com:443`, sent with `CONNECT`
+    Authority(&'a str),
+
+    /// `*`, sent with `OPTIONS` for the server as a whole
+    Asterisk,
+
+    /// Anything else
+    Invalid(&'a str),
+}
+
+impl<'a> RequestTarget<'a> {
+    pub fn parse(target: &'a str) -> Self {
+        if target.starts_with('/') {
+            return Self::Origin(target);
+        }
+        if target == "*" {
+            return Self::Asterisk;
+        }
+        let Some((scheme, rest)) = target.split_once(':') else {
+            return Self::Invalid(target);
+        };
+        let is_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+        let is_authority = target.rsplit_once(':').is_some_and(|(host, port)| {
+            let is_host = match host.strip_prefix('[') {
+                Some(ip) => ip.ends_with(']'),
+                None => !host.is_empty() && !host.contains(':'),
+            };
+            is_host && !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit())
+        });
+        match (is_scheme, is_authority) {
+            (true, _) if rest.starts_with("//") => Self::Absolute(target),
+            (_, true) => Self::Authority(target),
+            (true, _) => Self::Absolute(target),
+            _ => Self::Invalid(target),
+        }
+    }
+}
+
+/// A media type, as sent in `Content-Type` and `Accept`, e.g.
+/// `text/plain; charset=utf-8` (RFC 9110, section 8.3.1). The type, the
+/// subtype and the parameter names are case-insensitive, so they are kept in
+/// lowercase. Quoted parameter values are kept unquoted.
+///
+/// ```
+/// use httpfs::parse::MediaType;
+///
+/// let media = "multipart/form-data; boundary=\"a b\"".parse::<MediaType>().unwrap();
+/// assert!(media.is("multipart/form-data"));
+/// assert_eq!(Some("a b"), media.boundary());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaType {
+    pub kind: String,
+    pub subtype: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl MediaType {
+    /// The type and the subtype, without the parameters, e.g. `text/plain`
+    pub fn essence(&self) -> String {
+        format!("{}/{}", self.kind, self.subtype)
+    }
+
+    /// Whether the type and the subtype are `essence`, ignoring case
+    pub fn is(&self, essence: &str) -> bool {
+        essence.split_once('/').is_some_and(|(kind, subtype)| {
+            self.kind.eq_ignore_ascii_case(kind) && self.subtype.eq_ignore_ascii_case(subtype)
+        })
+    }
+
+    /// The value of the first parameter called `name`, ignoring case
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+
+    /// What separates the parts of a `multipart` body
+    pub fn boundary(&self) -> Option<&str> {
+        self.param("boundary")
+    }
+}
+
+impl FromStr for MediaType {
+    type Err = HttpParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |why: &str| HttpParseError(format!("invalid media type '{}': {}", s, why));
+        let (essence, mut rest) = s.split_at(s.find(';').unwrap_or(s.len()));
+        let (kind, subtype) = essence
+            .trim()
+            .split_once('/')
+            .filter(|(kind, subtype)| is_token(kind) && is_token(subtype))
+            .ok_or_else(|| invalid("expected TYPE/SUBTYPE"))?;
+
+        let mut params = Vec::new();
+        while let Some(param) = rest.trim_start().strip_prefix(';') {
+            let param = param.trim_start();
+            if param.is_empty() || param.starts_with(';') {
+                rest = param;
+                continue;
+            }
+            let (name, value) = param
+                .split_once('=')
+                .filter(|(name, _)| is_token(name))
+                .ok_or_else(|| invalid("expected NAME=VALUE parameters"))?;
+            let (value, after) = match value.strip_prefix('"') {
+                Some(quoted) => unquote(quoted).ok_or_else(|| invalid("unterminated quotes"))?,
+                None => {
+                    let (value, after) = value.split_at(value.find(';').unwrap_or(value.len()));
+                    let value = value.trim_end();
+                    if !is_token(value) {
+                        return Err(invalid("parameter values with spaces must be quoted"));
+                    }
+                    (String::from(value), after)
+                }
+            };
+            params.push((name.to_ascii_lowercase(), value));
+            rest = after;
+        }
+        if !rest.trim().is_empty() {
+            return Err(invalid("expected ';' between parameters"));
+        }
+
+        Ok(Self {
+            kind: kind.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            params,
+        })
+    }
+}
+
+impl Display for MediaType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.essence())?;
+        for (name, value) in &self.params {
+            match is_token(value) {
+                true => write!(f, "; {}={}", name, value)?,
+                false => write!(
+                    f,
+                    "; {}=\"{}\"",
+                    name,
+                    value.replace('\\', "\\\\").replace('"', "\\\"")
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The parsed `Content-Type` header in `headers`, if there is a valid one
+pub fn content_type<'a>(
+    headers: impl IntoIterator<Item = (&'a String, &'a String)>,
+) -> Option<MediaType> {
+    headers
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(CONTENT_TYPE))
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// Whether `s` is a token (RFC 9110, section 5.6.2), e.g. a header name
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Reads a quoted string up to its closing quote, which `s` starts after.
+/// Returns the unescaped string and what comes after it.
+fn unquote(s: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &s[i + 1..])),
+            '\\' => value.push(chars.next()?.1),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Decodes the `%XX` escapes in a request path. The decoded path must be valid
+/// UTF-8 and must not contain NUL, which no file name can hold.
+pub fn percent_decode(raw: &str) -> Result<String, ServerError> {
+    let invalid = |msg: &str| {
+        ServerError::bad_request(MalformedRequestError(Some(format!(
+            "{} in path '{}'",
+            msg, raw
+        ))))
+    };
+
+    let mut bytes = Vec::with_capacity(raw.len());
+    let mut rest = raw.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+        // from_str_radix would also take a sign, as in `%+A`
+        let hex = rest
+            .get(..2)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| invalid("invalid percent-encoding"))?;
+        bytes.push(hex);
+        rest = &rest[2..];
+    }
+
+    if bytes.contains(&0) {
+        return Err(invalid("NUL byte"));
+    }
+    String::from_utf8(bytes).map_err(|_| invalid("invalid UTF-8"))
+}
+
+/// Percent-encodes everything in a path except unreserved characters and `/`,
+/// so that it can be used in a URL or an RFC 8187 header parameter
+pub fn percent_encode(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                String::from(b as char)
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::StatusCode;
+
+    #[test]
+    fn test_percent_decode() {
+        for (raw, want) in [
+            ("/hello.txt", "/hello.txt"),
+            ("/a%20b.txt", "/a b.txt"),
+            ("/%F0%9F%98%80.txt", "/\u{1F600}.txt"),
+            ("/%e6%96%87%e4%bb%b6", "/\u{6587}\u{4EF6}"),
+            ("/\u{6587}\u{4EF6}", "/\u{6587}\u{4EF6}"),
+        ] {
+            assert_eq!(want, percent_decode(raw).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_percent_decode_errors() {
+        for raw in ["/%", "/%4", "/%zz", "/%FF", "/a%00b", "/%+A", "/a%+Ab"] {
+            let err = percent_decode(raw).unwrap_err();
+            assert_eq!(StatusCode::BAD_REQUEST, err.status(), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_proto() {
+        for line in ["GET / HTTP/2.0", "GET / HTTP/0.9", "GET / garbage"] {
+            let raw = format!("{}\r\n\r\n", line);
+            let err = parse_http_request(BullshitScanner::new(&mut raw.as_bytes())).unwrap_err();
+            assert_eq!(
+                StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+                err.status(),
+                "{}",
+                line
+            );
+            assert!(err.to_string().contains(&line[6..]), "{}", err);
+        }
+        assert_eq!(
+            Proto::Other(String::from("HTTP/2.0")),
+            Proto::from("HTTP/2.0")
+        );
+    }
+
+    #[test]
+    fn test_request_target() {
+        for (target, want) in [
+            ("/", RequestTarget::Origin("/")),
+            ("/a/b.txt", RequestTarget::Origin("/a/b.txt")),
+            (
+                "http://example.com/a.txt",
+                RequestTarget::Absolute("http://example.com/a.txt"),
+            ),
+            (
+                "https://example.com:8443",
+                RequestTarget::Absolute("https://example.com:8443"),
+            ),
+            ("urn:isbn:123", RequestTarget::Absolute("urn:isbn:123")),
+            (
+                "example.com:443",
+                RequestTarget::Authority("example.com:443"),
+            ),
+            ("[::1]:8080", RequestTarget::Authority("[::1]:8080")),
+            ("*", RequestTarget::Asterisk),
+            ("a.txt", RequestTarget::Invalid("a.txt")),
+            (":80", RequestTarget::Invalid(":80")),
+        ] {
+            assert_eq!(want, RequestTarget::parse(target), "{}", target);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_request_targets() {
+        for (target, want) in [
+            ("http://localhost/a.txt", StatusCode::NOT_IMPLEMENTED),
+            ("localhost:8080", StatusCode::BAD_REQUEST),
+            ("*", StatusCode::BAD_REQUEST),
+            ("a.txt", StatusCode::BAD_REQUEST),
+        ] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            let err = parse_http_request(BullshitScanner::new(&mut raw.as_bytes())).unwrap_err();
+            assert_eq!(want, err.status(), "{}", target);
+        }
+    }
+
+    /// The headers of the request in `raw`
+    fn parse_with(
+        raw: &str,
+        strictness: Strictness,
+    ) -> Result<HashMap<String, String>, ServerError> {
+        let mut bytes = raw.as_bytes();
+        parse_http_request_with(BullshitScanner::new(&mut bytes), strictness).map(|req| req.headers)
+    }
+
+    #[test]
+    fn test_obs_fold() {
+        let raw = "GET / HTTP/1.1\r\nX-Long: first\r\n  second\r\n\tthird \r\nHost: h\r\n\r\n";
+        let headers = parse_with(raw, Strictness::Lenient).unwrap();
+        assert_eq!("first second third", headers["X-Long"]);
+        assert_eq!("h", headers["Host"]);
+
+        let err = parse_with(raw, Strictness::Strict).unwrap_err();
+        assert_eq!(StatusCode::BAD_REQUEST, err.status());
+        assert!(err.to_string().contains("folded"), "{}", err);
+
+        // There is nothing to continue before the first header
+        let raw = "GET / HTTP/1.1\r\n  Host: h\r\n\r\n";
+        for strictness in [Strictness::Lenient, Strictness::Strict] {
+            assert!(parse_with(raw, strictness).is_err(), "{:?}", strictness);
+        }
+    }
+
+    #[test]
+    fn test_header_whitespace() {
+        for (header, lenient, strict) in [
+            ("Host:h", Some("Host"), Some("Host")),
+            ("Host: \th \t", Some("Host"), Some("Host")),
+            ("Host :h", Some("Host"), None),
+            ("Ho st: h", Some("Ho st"), None),
+            (": h", None, None),
+        ] {
+            let raw = format!("GET / HTTP/1.1\r\n{}\r\n\r\n", header);
+            for (strictness, want) in [(Strictness::Lenient, lenient), (Strictness::Strict, strict)]
+            {
+                let got = parse_with(&raw, strictness).ok().map(|headers| {
+                    assert_eq!(Some("h"), headers.values().next().map(String::as_str));
+                    headers.into_keys().next().unwrap()
+                });
+                assert_eq!(want, got.as_deref(), "{:?} {:?}", header, strictness);
+            }
+        }
+    }
+
+    #[test]
+    fn test_line_endings() {
+        for raw in [
+            "GET / HTTP/1.1\nHost: h\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: h\n\r\n",
+            "GET / HTTP/1.1\r\nHost: h\r\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: h\r\n\n",
+        ] {
+            let headers = parse_with(raw, Strictness::Lenient).unwrap();
+            assert_eq!("h", headers["Host"], "{:?}", raw);
+            assert!(parse_with(raw, Strictness::Strict).is_err(), "{:?}", raw);
+        }
+        assert!(parse_with("GET / HTTP/1.1\r\nHost: h\r\n\r\n", Strictness::Strict).is_ok());
+    }
+
+    #[test]
+    fn test_query() {
+        for (target, path, query) in [
+            ("/a.txt", "/a.txt", None),
+            ("/a%20b.txt?x=1&y=%20", "/a b.txt", Some("x=1&y=%20")),
+            ("/dir/?", "/dir/", Some("")),
+        ] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            let mut bytes = raw.as_bytes();
+            let req = parse_http_request(BullshitScanner::new(&mut bytes)).unwrap();
+            assert_eq!(
+                (path, query),
+                (req.file.as_str(), req.query.as_deref()),
+                "{}",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn test_media_type() {
+        let media = "Text/HTML;Charset=UTF-8".parse::<MediaType>().unwrap();
+        assert_eq!("text/html", media.essence());
+        assert!(media.is("text/html") && media.is("TEXT/html") && !media.is("text/plain"));
+        assert_eq!(Some("UTF-8"), media.charset());
+        assert_eq!(None, media.boundary());
+
+        for (raw, want) in [
+            ("text/plain", vec![]),
+            ("text/plain ;  charset=utf-8 ", vec![("charset", "utf-8")]),
+            ("text/plain;;charset=utf-8;", vec![("charset", "utf-8")]),
+            (
+                r#"multipart/form-data; boundary="x; y=\"z\""; a=1"#,
+                vec![("boundary", r#"x; y="z""#), ("a", "1")],
+            ),
+        ] {
+            let media = raw.parse::<MediaType>().unwrap();
+            let params = media
+                .params
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect::<Vec<_>>();
+            assert_eq!(want, params, "{}", raw);
+
+            // What it prints parses back the same
+            assert_eq!(media, media.to_string().parse().unwrap(), "{}", raw);
+        }
+
+        for raw in [
+            "",
+            "text",
+            "text/",
+            "/plain",
+            "text plain/x",
+            "text/plain; charset",
+            "text/plain; charset=utf 8",
+            "text/plain; a=\"unterminated",
+            "text/plain; a=\"b\" c",
+        ] {
+            assert!(raw.parse::<MediaType>().is_err(), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn test_request_content_type() {
+        let content_type = |header: &str| {
+            let raw = format!("POST /a.txt HTTP/1.1\r\n{}\r\n\r\n", header);
+            let mut bytes = raw.as_bytes();
+            parse_http_request(BullshitScanner::new(&mut bytes))
+                .unwrap()
+                .content_type()
+        };
+        assert_eq!(
+            Some("latin1"),
+            content_type("content-type: text/plain; charset=latin1")
+                .unwrap()
+                .charset()
+        );
+        assert_eq!(None, content_type("Content-Type: nonsense"));
+        assert_eq!(None, content_type("Host: h"));
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(
+            "/dir/a%20b%23%F0%9F%98%80.txt",
+            percent_encode("/dir/a b#\u{1F600}.txt")
+        );
+    }
 }
@@ -1,26 +1,63 @@
 use std::{
     collections::HashMap,
     fs::{self, File, OpenOptions},
-    io::{Read, Write},
-    net::{IpAddr, Ipv4Addr, TcpListener, TcpStream},
-    path::{Path, PathBuf},
+    io::{self, ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Component, Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, Barrier, Mutex,
+        mpsc, Arc, Condvar, Mutex,
     },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
+use ring::rand::{SecureRandom, SystemRandom};
 use threadpool::ThreadPool;
 
 use crate::{
     bullshit_scanner::BullshitScanner,
+    discovery::{self, Beacon},
     errors::ServerError,
-    html::template,
-    parse::{parse_http_request, Method, Request},
+    html::write_template,
+    parse::{parse_http_request_with, percent_encode, Method, Proto, Request, Strictness},
+    status::StatusCode,
+    transport::{BindOptions, Bindable, BoundAddr, Listener, SocketOptions, Stream},
 };
 
+#[cfg(unix)]
+use crate::transport::UnixSocket;
+
+use body::Chunked;
+use listing::{Entry, ListingQuery};
+use objects::{Hashing, Objects};
+use usage::{Counting, Usage};
+
+pub use body::BodyLength;
+pub use events::ConnectionEvent;
+pub use pages::{ErrorPage, ErrorPages};
+pub use proxy::Proxy;
+pub use router::{Response, RouteRequest, Router, Upgraded};
+pub use signed::SignedUrls;
+#[cfg(feature = "rayon")]
+pub use spawn::Rayon;
+pub use spawn::{Job, Spawner};
+pub use spool::SpooledBody;
+
+mod body;
+mod date;
+mod events;
+mod listing;
+mod objects;
+mod pages;
+mod proxy;
+mod router;
+mod signed;
+mod spawn;
+mod spool;
+mod usage;
+pub mod websocket;
+
 /// 1MB
 pub const BUFSIZE: usize = 1 << 20;
 
@@ -28,23 +65,228 @@ pub struct Server {
     pub addr: IpAddr,
     pub port: u32,
     pub dir: String,
+
+    /// Also listen on these addresses, on the same [Server::port], e.g.
+    /// `[::]` next to an [Server::addr] of `0.0.0.0` to take both IPv4 and
+    /// IPv6 connections. The [Handle] has the address of each listener.
+    pub extra_addrs: Vec<IpAddr>,
+
     pub n_workers: usize,
+
+    /// Runs the connection handlers instead of a pool of [Server::n_workers]
+    /// threads, see [Spawner]
+    pub spawner: Option<Arc<dyn Spawner>>,
+
+    /// Listen on a unix domain socket at this path instead of on
+    /// [Server::addr] and [Server::port]
+    #[cfg(unix)]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Enables the `/__admin/` endpoints. They are unauthenticated, so only
+    /// turn this on for servers that are not exposed to untrusted clients.
+    pub admin: bool,
+
+    /// Kernel buffer sizes for the listening socket and the connections. The
+    /// defaults are too small to keep a high bandwidth-delay link busy.
+    pub sockets: SocketOptions,
+
+    /// How the listening socket is bound, e.g. with `SO_REUSEPORT` so that
+    /// several servers can share [Server::port]
+    pub bind_options: BindOptions,
+
+    /// Rejects uploads that would make the files in the served directory add
+    /// up to more than this many bytes, with `507 Insufficient Storage`. The
+    /// directory is measured when the server starts, and uploads are counted
+    /// as they happen. Changes made to the directory by anything other than
+    /// the server are not noticed.
+    pub max_dir_bytes: Option<u64>,
+
+    /// Instead of overwriting a file, an upload moves the old version to
+    /// [Server::TRASH_DIR] in the served directory first. Up to this many old
+    /// versions of each file are kept there, numbered from oldest to newest,
+    /// e.g. `.trash/notes.txt.3`.
+    pub keep_versions: Option<usize>,
+
+    /// Stores each distinct upload once, in [Server::OBJECTS_DIR] in the
+    /// served directory, and makes the uploaded file a hard link to it, so
+    /// uploading the same contents again takes no more room on disk. The
+    /// quota still counts the size of every file.
+    pub dedup: bool,
+
+    /// Uploads to the same file are written one at a time. While one is in
+    /// progress, up to this many more wait for their turn, each for at most
+    /// [Server::upload_wait]. Uploads that can't wait are refused with
+    /// `409 Conflict`.
+    pub upload_waiters: usize,
+    pub upload_wait: Duration,
+
+    /// Requests for paths under the prefix of one of these are forwarded to
+    /// its upstream server instead of being served from [Server::dir]. The
+    /// first matching prefix wins.
+    pub proxies: Vec<Proxy>,
+
+    /// How long to wait for an upstream server to connect, and on each read
+    /// from and write to it. Requests it times out on are answered with
+    /// `504 Gateway Timeout` if nothing has been sent to the client yet.
+    pub proxy_timeout: Duration,
+
+    /// Handlers for paths that are not files, e.g. a small API served next to
+    /// the files. Requests that match none of its routes are served from
+    /// [Server::dir].
+    pub router: Router,
+
+    /// Requests for the paths it protects are refused with `403 Forbidden`
+    /// unless they come with a signature from [SignedUrls::sign] that has not
+    /// expired. This applies to routes and proxies too.
+    pub signed_urls: Option<SignedUrls>,
+
+    /// HTML bodies for error responses, for clients that accept them. The
+    /// others get the plain text messages.
+    pub error_pages: Option<ErrorPages>,
+
+    /// Shows where the served directory is on the server's filesystem in the
+    /// bodies of `403` and `404` responses. Off by default, so that clients
+    /// only see the paths they asked for. The logs always have the full
+    /// paths.
+    pub reveal_paths: bool,
+
+    /// Requests with a longer request line are refused with `414 URI Too
+    /// Long`, and requests with a longer header with `431 Request Header
+    /// Fields Too Large`. Lines are read into memory whole, so this bounds
+    /// what a client can make the server hold for each of them.
+    pub max_line_length: usize,
+
+    /// How forgiving the request parser is of sloppy clients. Lenient by
+    /// default.
+    pub strictness: Strictness,
+
+    /// Logs the clients and the paths that were sent the most bytes at this
+    /// interval. The totals since the server started are also served at
+    /// [Server::ADMIN_USAGE_PATH].
+    pub usage_report: Option<Duration>,
+
+    /// Receives a [ConnectionEvent] when a connection is accepted and when
+    /// the server is done with it, e.g. to track sessions, or to wait for the
+    /// server to go quiet instead of sleeping
+    pub connection_events: Option<mpsc::Sender<ConnectionEvent>>,
+
+    /// Announce the server with a [discovery] beacon sent to this address,
+    /// usually [discovery::DEFAULT_ANNOUNCE_ADDR]. Only servers listening on
+    /// a port can be announced.
+    pub announce: Option<SocketAddr>,
+
+    /// Runs once the listeners are bound, before the first connection is
+    /// accepted, e.g. to give up the root privileges needed to bind port 80.
+    /// If it fails, the listeners are closed and [Server::serve] returns its
+    /// error.
+    pub before_accept: Option<BeforeAccept>,
 }
 
+/// See [Server::before_accept]
+pub type BeforeAccept = Box<dyn FnOnce() -> Result<(), ServerError> + Send>;
+
 impl Server {
     pub const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
     pub const DEFAULT_PORT: u32 = 8080;
     pub const DEFAULT_DIR: &'static str = "./";
     pub const DEFAULT_NUM_THREADS: usize = 4;
+    pub const DEFAULT_UPLOAD_WAITERS: usize = 8;
+    pub const DEFAULT_UPLOAD_WAIT: Duration = Duration::from_secs(5);
+    pub const DEFAULT_PROXY_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const DEFAULT_MAX_LINE_LENGTH: usize = 8 << 10;
+
+    /// `GET` returns the current log level, `POST` with a level (`error`,
+    /// `warn`, `info`, `debug`, `trace`, or `off`) as the body changes it.
+    /// The level is set with [log::set_max_level], so the installed logger
+    /// must not filter records more strictly on its own for it to take effect.
+    pub const ADMIN_LOG_LEVEL_PATH: &'static str = "/__admin/loglevel";
+
+    /// `GET` returns the number of requests and the bytes sent for each client
+    /// IP address and each path, the largest first
+    pub const ADMIN_USAGE_PATH: &'static str = "/__admin/usage";
+
+    /// Where old versions of files are kept, see [Server::keep_versions]
+    pub const TRASH_DIR: &'static str = ".trash";
+
+    /// Where uploads are stored, see [Server::dedup]
+    pub const OBJECTS_DIR: &'static str = ".objects";
+
+    /// Directories in the served directory that the server keeps for itself.
+    /// Requests for anything in them are refused, so that clients can't read
+    /// or tamper with what is in them.
+    pub const RESERVED_DIRS: &'static [&'static str] = &[Self::TRASH_DIR, Self::OBJECTS_DIR];
+
+    /// Each request gets an id, which is logged with every line about the
+    /// request and sent back in this header. A client can pick the id by
+    /// sending the header itself.
+    pub const REQUEST_ID_HEADER: &'static str = "X-Request-Id";
+
+    /// Sent in the `Server` header of every response
+    pub const SERVER_HEADER: &'static str = concat!("httpfs/", env!("CARGO_PKG_VERSION"));
+
+    /// Downloads requested with `TE: trailers` are sent chunked, followed by
+    /// this trailer holding the SHA-256 digest of the file in hex
+    pub const CHECKSUM_TRAILER: &'static str = "X-Checksum-SHA256";
 
     pub fn serve(self) -> Result<Handle, ServerError> {
-        ServerRunner {
-            addr: self.addr,
-            dir: self.dir,
-            port: self.port,
-            threads: Arc::new(Mutex::new(ThreadPool::new(self.n_workers))),
+        let quota = match self.max_dir_bytes {
+            Some(max) => Some(Quota::scan(Path::new(&self.dir), max)?),
+            None => None,
+        };
+        let trash = match self.keep_versions {
+            Some(keep) => Some(Trash {
+                dir: fs::canonicalize(&self.dir)?,
+                keep,
+                lock: Mutex::new(()),
+            }),
+            None => None,
+        };
+        let objects = match self.dedup {
+            true => Some(Objects::open(
+                fs::canonicalize(&self.dir)?.join(Self::OBJECTS_DIR),
+            )?),
+            false => None,
+        };
+        let runner = ServerRunner {
+            settings: Arc::new(Settings {
+                dir: self.dir,
+                admin: self.admin,
+                quota,
+                trash,
+                objects,
+                uploads: UploadLocks {
+                    paths: Mutex::new(HashMap::new()),
+                    turn: Condvar::new(),
+                    max_waiters: self.upload_waiters,
+                    wait: self.upload_wait,
+                },
+                proxies: self.proxies,
+                proxy_timeout: self.proxy_timeout,
+                router: self.router,
+                signed_urls: self.signed_urls,
+                error_pages: self.error_pages.map(Arc::new),
+                reveal_paths: self.reveal_paths,
+                max_line_length: self.max_line_length,
+                strictness: self.strictness,
+                usage: Arc::new(Usage::default()),
+            }),
+            threads: self
+                .spawner
+                .unwrap_or_else(|| Arc::new(ThreadPool::new(self.n_workers))),
+            sockets: self.sockets,
+            bind_options: self.bind_options,
+            announce: self.announce,
+            usage_report: self.usage_report,
+            events: self.connection_events,
+            before_accept: self.before_accept,
+        };
+
+        #[cfg(unix)]
+        if let Some(path) = self.unix_socket {
+            return runner.serve(UnixSocket(path));
         }
-        .serve()
+        let ips = [&[self.addr][..], &self.extra_addrs].concat();
+        runner.serve_tcp(&ips, self.port.try_into()?)
     }
 }
 
@@ -54,46 +296,151 @@ impl Default for Server {
             addr: Self::LOCALHOST,
             port: Self::DEFAULT_PORT,
             dir: String::from(Self::DEFAULT_DIR),
+            extra_addrs: Vec::new(),
             n_workers: Self::DEFAULT_NUM_THREADS,
+            spawner: None,
+            #[cfg(unix)]
+            unix_socket: None,
+            admin: false,
+            sockets: SocketOptions::default(),
+            bind_options: BindOptions::default(),
+            max_dir_bytes: None,
+            keep_versions: None,
+            dedup: false,
+            upload_waiters: Self::DEFAULT_UPLOAD_WAITERS,
+            upload_wait: Self::DEFAULT_UPLOAD_WAIT,
+            proxies: Vec::new(),
+            proxy_timeout: Server::DEFAULT_PROXY_TIMEOUT,
+            router: Router::new(),
+            signed_urls: None,
+            error_pages: None,
+            reveal_paths: false,
+            max_line_length: Self::DEFAULT_MAX_LINE_LENGTH,
+            strictness: Strictness::default(),
+            usage_report: None,
+            connection_events: None,
+            announce: None,
+            before_accept: None,
         }
     }
 }
 
-/// Represents a running [Server] that can be shutdown
-#[derive(Debug)]
+/// Represents a running [Server] that can be shutdown. Clones control the same
+/// server, so one thread can wait for it to stop while another shuts it down.
+#[derive(Debug, Clone)]
 pub struct Handle {
     /// The [ServerRunner] thread will poll this shared variable in between
     /// accepting connections. If the value contained within the [mutex](Mutex)
     /// is true, then the server thread will stop accepting requests.
     exit: Arc<AtomicBool>,
-    done: Arc<Barrier>,
-    main: Option<JoinHandle<()>>,
+    status: Arc<(Mutex<Status>, Condvar)>,
+    /// Taken by the first [Handle::join], the others wait on the status
+    main: Arc<Mutex<Option<JoinHandle<()>>>>,
+    bound: Vec<BoundAddr>,
+}
+
+/// How far the [ServerRunner] thread has got, shared by the handles
+#[derive(Debug, Default)]
+struct Status {
+    /// The accept loop has started
+    ready: bool,
+
+    /// The accept loop has ended and the requests in progress have finished
+    stopped: bool,
+
+    /// Why the accept loop ended, if it was not asked to
+    error: Option<io::Error>,
 }
 
 impl Handle {
     pub fn new() -> Self {
         Self {
             exit: Arc::new(AtomicBool::new(false)),
-            done: Arc::new(Barrier::new(2)),
-            main: None,
+            status: Arc::new((Mutex::new(Status::default()), Condvar::new())),
+            main: Arc::new(Mutex::new(None)),
+            bound: Vec::new(),
+        }
+    }
+
+    /// The addresses the server is listening on. Servers started with port 0
+    /// are bound to a port picked by the system, which can be found here.
+    pub fn bound_addrs(&self) -> &[BoundAddr] {
+        &self.bound
+    }
+
+    /// Waits until the server is accepting connections. Fails if it stopped
+    /// before that, or if it is not ready within `timeout`.
+    pub fn wait_ready(&self, timeout: Duration) -> Result<(), ServerError> {
+        let (status, changed) = &*self.status;
+        let (status, waited) = changed
+            .wait_timeout_while(status.lock().unwrap(), timeout, |s| !s.ready && !s.stopped)
+            .unwrap();
+        match &*status {
+            Status { ready: true, .. } => Ok(()),
+            Status {
+                error: Some(err), ..
+            } => Err(ServerError::new().msg(&format!("server failed to start: {}", err))),
+            Status { stopped: true, .. } => {
+                Err(ServerError::new().msg("server stopped before it was ready"))
+            }
+            _ if waited.timed_out() => {
+                Err(ServerError::new().msg(&format!("server was not ready after {:?}", timeout)))
+            }
+            _ => unreachable!("waited until ready or stopped"),
         }
     }
 
     /// Gracefully shutdown the server
     pub fn shutdown(&mut self) {
         self.exit.store(true, Ordering::SeqCst);
-        self.done.wait();
+        let (status, changed) = &*self.status;
+        let _stopped = changed
+            .wait_while(status.lock().unwrap(), |s| !s.stopped)
+            .unwrap();
     }
 
-    /// Waits on the main thread contained within this handle
-    pub fn join(self) {
-        if let Some(main) = self.main {
-            main.join().unwrap();
+    /// `true` from when the server starts accepting connections until it has
+    /// stopped
+    pub fn is_running(&self) -> bool {
+        let status = self.status.0.lock().unwrap();
+        status.ready && !status.stopped
+    }
+
+    /// Waits for the server to stop. Returns the error that stopped it, if it
+    /// stopped on its own instead of being [shut down](Handle::shutdown). Any
+    /// number of clones can wait at the same time.
+    pub fn join(&self) -> Result<(), ServerError> {
+        let main = self.main.lock().unwrap().take();
+        if let Some(main) = main {
+            if main.join().is_err() {
+                self.set_status(|s| {
+                    s.error = Some(io::Error::other("server thread panicked"));
+                    s.stopped = true;
+                });
+            }
+        }
+        let (status, changed) = &*self.status;
+        let status = changed
+            .wait_while(status.lock().unwrap(), |s| !s.stopped)
+            .unwrap();
+        match &status.error {
+            Some(err) => Err(ServerError::transport(io::Error::new(
+                err.kind(),
+                err.to_string(),
+            ))),
+            None => Ok(()),
         }
     }
 
     fn set_main(&mut self, handle: JoinHandle<()>) {
-        self.main = Some(handle);
+        *self.main.lock().unwrap() = Some(handle);
+    }
+
+    /// Updates the [Status] and wakes up the handles waiting on it
+    fn set_status(&self, update: impl FnOnce(&mut Status)) {
+        let (status, changed) = &*self.status;
+        update(&mut status.lock().unwrap());
+        changed.notify_all();
     }
 }
 
@@ -103,122 +450,856 @@ impl Default for Handle {
     }
 }
 
-impl Clone for Handle {
-    /// Note: this does not clone the main thread of the handle, which is not
-    /// clonable. Only the original handle may control its main thread. Cloneed
-    /// versions of the handle can still remotely shutdown the main thread, but
-    /// only the original handle can call [Handle::join]
-    fn clone(&self) -> Self {
-        Self {
-            exit: self.exit.clone(),
-            done: self.done.clone(),
-            main: None,
-        }
+/// Marks the server stopped when the [ServerRunner] thread ends, even if it
+/// panics, so that the handles waiting for it to stop are woken up
+struct StopGuard(Handle);
+
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        let panicked = thread::panicking();
+        self.0.set_status(|s| {
+            if panicked && s.error.is_none() {
+                s.error = Some(io::Error::other("server thread panicked"));
+            }
+            s.stopped = true;
+        });
     }
 }
 
 /// The [ServerRunner] is the object that actually initiates the request
 /// handling thread. It is mod-private, the only way to instantiate it is
 /// through the [Server] public struct.
-#[derive(Debug)]
 struct ServerRunner {
-    addr: IpAddr,
-    port: u32,
+    settings: Arc<Settings>,
+    threads: Arc<dyn Spawner>,
+    sockets: SocketOptions,
+    bind_options: BindOptions,
+    announce: Option<SocketAddr>,
+    usage_report: Option<Duration>,
+    events: Option<mpsc::Sender<ConnectionEvent>>,
+    before_accept: Option<BeforeAccept>,
+}
+
+/// The [Server] options needed by the request handlers, shared with the worker
+/// threads
+#[derive(Debug)]
+struct Settings {
     dir: String,
-    threads: Arc<Mutex<ThreadPool>>,
+    admin: bool,
+    quota: Option<Quota>,
+    trash: Option<Trash>,
+    objects: Option<Objects>,
+    uploads: UploadLocks,
+    proxies: Vec<Proxy>,
+    proxy_timeout: Duration,
+    router: Router,
+    signed_urls: Option<SignedUrls>,
+    error_pages: Option<Arc<ErrorPages>>,
+    reveal_paths: bool,
+    max_line_length: usize,
+    strictness: Strictness,
+    usage: Arc<Usage>,
+}
+
+/// Keeps count of the bytes in the served directory, see
+/// [Server::max_dir_bytes]
+#[derive(Debug)]
+struct Quota {
+    max: u64,
+    used: Mutex<u64>,
+}
+
+impl Quota {
+    /// Measures the directory. Symlinks are not followed, and the
+    /// [Server::OBJECTS_DIR] is skipped because the files link to it.
+    fn scan(dir: &Path, max: u64) -> Result<Self, ServerError> {
+        fn size(path: &Path) -> u64 {
+            match fs::symlink_metadata(path) {
+                Ok(meta) if meta.is_dir() => fs::read_dir(path)
+                    .map(|entries| {
+                        entries
+                            .flatten()
+                            .filter(|e| e.file_name() != Server::OBJECTS_DIR)
+                            .map(|e| size(&e.path()))
+                            .sum()
+                    })
+                    .unwrap_or(0),
+                Ok(meta) if meta.is_file() => meta.len(),
+                _ => 0,
+            }
+        }
+
+        let used = size(dir);
+        log::debug!("Quota: {} of {} bytes used in {}", used, max, dir.display());
+        Ok(Self {
+            max,
+            used: Mutex::new(used),
+        })
+    }
+
+    /// Reserves room for writing `len` bytes to `path`. Uploads overwrite the
+    /// file in place, so only the bytes past its current end are new, unless
+    /// the old file is `kept` in the [Trash]. The reservation is settled
+    /// against the real size of the file when it is dropped.
+    fn reserve<'a>(
+        &'a self,
+        path: &'a Path,
+        len: u64,
+        kept: bool,
+    ) -> Result<Reservation<'a>, ServerError> {
+        let old_len = match kept {
+            true => 0,
+            false => fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        };
+        let growth = len.saturating_sub(old_len);
+        let mut used = self.used.lock().unwrap();
+        if *used + growth > self.max {
+            return Err(ServerError::insufficient_storage(&format!(
+                "writing {} bytes to '{}' would exceed the quota of {} bytes, {} are in use",
+                len,
+                file_name(&path.to_string_lossy()),
+                self.max,
+                *used
+            )));
+        }
+        *used += growth;
+        Ok(Reservation {
+            quota: self,
+            path,
+            old_len,
+            growth,
+        })
+    }
+
+    /// Gives back the room taken by files the server deleted
+    fn release(&self, len: u64) {
+        let mut used = self.used.lock().unwrap();
+        *used = used.saturating_sub(len);
+    }
+}
+
+/// Room reserved in a [Quota] for an upload
+struct Reservation<'a> {
+    quota: &'a Quota,
+    path: &'a Path,
+    old_len: u64,
+    growth: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let new_len = fs::metadata(self.path).map(|m| m.len()).unwrap_or(0);
+        let mut used = self.quota.used.lock().unwrap();
+        *used = (*used - self.growth + new_len).saturating_sub(self.old_len);
+    }
+}
+
+/// Keeps the old versions of overwritten files, see [Server::keep_versions]
+#[derive(Debug)]
+struct Trash {
+    /// The canonical path of the served directory
+    dir: PathBuf,
+    keep: usize,
+
+    /// Held while versions are numbered and pruned, so that two uploads of
+    /// the same file don't pick the same number
+    lock: Mutex<()>,
+}
+
+impl Trash {
+    /// Moves `file` into the trash as its newest version, then deletes the
+    /// oldest versions past the limit. Returns the number of bytes deleted.
+    /// Does nothing if `file` is not a regular file in the served directory.
+    fn keep(&self, file: &Path) -> Result<u64, ServerError> {
+        let rel = match file.strip_prefix(&self.dir) {
+            Ok(rel) if is_regular_file(file) => rel,
+            _ => return Ok(0),
+        };
+        let (name, parent) = match (rel.file_name(), rel.parent()) {
+            (Some(name), Some(parent)) => (name.to_string_lossy(), parent),
+            _ => return Ok(0),
+        };
+        let _lock = self.lock.lock().unwrap();
+
+        let versions_dir = self.dir.join(Server::TRASH_DIR).join(parent);
+        fs::create_dir_all(&versions_dir)?;
+        let prefix = format!("{}.", name);
+        let mut versions = fs::read_dir(&versions_dir)?
+            .flatten()
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()?
+                    .strip_prefix(&prefix)?
+                    .parse::<u64>()
+                    .ok()
+            })
+            .collect::<Vec<_>>();
+        versions.sort_unstable();
+
+        let next = versions.last().map_or(1, |n| n + 1);
+        let version = |n| versions_dir.join(format!("{}{}", prefix, n));
+        fs::rename(file, version(next))?;
+        log::debug!(
+            "Moved the old version of {} to {}",
+            rel.display(),
+            version(next).display()
+        );
+        versions.push(next);
+
+        let mut deleted = 0;
+        let excess = versions.len().saturating_sub(self.keep);
+        for &n in &versions[..excess] {
+            let path = version(n);
+            let len = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            match fs::remove_file(&path) {
+                Ok(()) => deleted += len,
+                Err(e) => log::warn!("Failed to delete {}: {}", path.display(), e),
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+/// Lets one upload at a time write to a file, see [Server::upload_waiters]
+#[derive(Debug)]
+struct UploadLocks {
+    /// The files being uploaded to
+    paths: Mutex<HashMap<PathBuf, Uploads>>,
+    turn: Condvar,
+    max_waiters: usize,
+    wait: Duration,
+}
+
+/// The uploads to one file
+#[derive(Debug, Default)]
+struct Uploads {
+    /// Set while one of them holds the [UploadLock]
+    busy: bool,
+    waiting: usize,
+}
+
+impl UploadLocks {
+    /// Waits for the uploads to `path` that came first. Fails with a
+    /// [ServerError::Conflict] if too many are already waiting, or if the wait
+    /// is over before it is this upload's turn.
+    fn lock<'a>(&'a self, path: &'a Path) -> Result<UploadLock<'a>, ServerError> {
+        let busy = || {
+            ServerError::conflict(&format!(
+                "'{}' is being uploaded by another request",
+                file_name(&path.to_string_lossy())
+            ))
+        };
+        let mut paths = self.paths.lock().unwrap();
+        let uploads = paths.entry(path.to_path_buf()).or_default();
+        if !uploads.busy {
+            uploads.busy = true;
+            return Ok(UploadLock { locks: self, path });
+        } else if uploads.waiting >= self.max_waiters {
+            return Err(busy());
+        }
+        uploads.waiting += 1;
+
+        let deadline = Instant::now() + self.wait;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if !left.is_zero() {
+                paths = self.turn.wait_timeout(paths, left).unwrap().0;
+            }
+            // The entry stays in the map while there are uploads waiting
+            let uploads = paths.get_mut(path).unwrap();
+            if !uploads.busy {
+                uploads.busy = true;
+                uploads.waiting -= 1;
+                return Ok(UploadLock { locks: self, path });
+            } else if left.is_zero() {
+                uploads.waiting -= 1;
+                return Err(busy());
+            }
+        }
+    }
+}
+
+/// Held while an upload writes to its file
+struct UploadLock<'a> {
+    locks: &'a UploadLocks,
+    path: &'a Path,
+}
+
+impl Drop for UploadLock<'_> {
+    fn drop(&mut self) {
+        let mut paths = self.locks.paths.lock().unwrap();
+        match paths.get_mut(self.path) {
+            Some(uploads) if uploads.waiting > 0 => {
+                uploads.busy = false;
+                drop(paths);
+                self.locks.turn.notify_all();
+            }
+            _ => {
+                paths.remove(self.path);
+            }
+        }
+    }
+}
+
+/// Returns `true` for files that are not directories or symlinks
+fn is_regular_file(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.is_file())
 }
 
 impl ServerRunner {
-    fn serve(&self) -> Result<Handle, ServerError> {
-        let addr = self.addr_str();
-        log::info!("Starting server on {}", addr);
+    /// Listens on `addr` alone. TCP goes through [ServerRunner::serve_tcp],
+    /// so this is only used for unix sockets outside of tests.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    fn serve<B: Bindable>(self, addr: B) -> Result<Handle, ServerError> {
+        let listener = self.bind(&addr, &self.bind_options)?;
+        self.run(vec![listener])
+    }
 
-        let listener = TcpListener::bind(addr).map_err(wrap)?;
+    /// Listens on every one of `ips`, on the same port. With port 0, the rest
+    /// get the port the system picks for the first. IPv6 listeners only take
+    /// IPv6 connections then, so that `0.0.0.0` and `::` can go together.
+    fn serve_tcp(self, ips: &[IpAddr], mut port: u16) -> Result<Handle, ServerError> {
+        let opts = BindOptions {
+            v6_only: self.bind_options.v6_only || ips.len() > 1,
+            ..self.bind_options
+        };
+        let mut listeners = Vec::new();
+        for ip in ips {
+            let listener = self.bind(&SocketAddr::new(*ip, port), &opts)?;
+            if let Ok(BoundAddr::Tcp(addr)) = listener.bound_addr() {
+                port = addr.port();
+            }
+            listeners.push(listener);
+        }
+        self.run(listeners)
+    }
+
+    fn bind<B: Bindable>(&self, addr: &B, opts: &BindOptions) -> Result<B::Listener, ServerError> {
+        let listener = addr.bind(opts).map_err(ServerError::transport)?;
         listener
             .set_nonblocking(true)
-            .map_err(ServerError::wrap_err)?;
+            .map_err(ServerError::transport)?;
+        listener
+            .configure(&self.sockets)
+            .map_err(ServerError::transport)?;
+        Ok(listener)
+    }
 
+    /// Accepts connections from all the listeners on one thread, and hands
+    /// them to the [Spawner]
+    fn run<L: Listener>(mut self, listeners: Vec<L>) -> Result<Handle, ServerError> {
+        panics::install_hook();
         let mut handle = Handle::new();
+        for listener in &listeners {
+            let bound = listener.bound_addr().map_err(ServerError::transport)?;
+            log::info!("Starting server on {}", bound);
+            handle.bound.push(bound);
+        }
+        if let Some(before_accept) = self.before_accept.take() {
+            if let Err(e) = before_accept() {
+                listeners.iter().for_each(Listener::close);
+                return Err(e);
+            }
+        }
+        if let (Some(to), Some(bound)) = (self.announce, handle.bound.first()) {
+            self.announce::<L>(bound, to, &handle)?;
+        }
+        if let Some(interval) = self.usage_report {
+            usage::report_every(self.settings.usage.clone(), interval, handle.exit.clone());
+        }
 
         // Spin up a request handler loop in a new thread
-        let (handlec, threadsc, dirc) = (handle.clone(), self.threads.clone(), self.dir.clone());
+        let (handlec, threadsc, settingsc, sockets, eventsc) = (
+            handle.clone(),
+            self.threads.clone(),
+            self.settings.clone(),
+            self.sockets,
+            self.events.clone(),
+        );
         handle.set_main(thread::spawn(move || {
-            for stream in listener.incoming() {
-                let mut stream = match stream {
-                    Ok(stream) => stream,
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                        // Poll the handle exit flag
-                        if handlec.exit.load(Ordering::SeqCst) {
-                            break;
+            let _stopped = StopGuard(handlec.clone());
+            let mut next_id: u64 = 0;
+            handlec.set_status(|s| s.ready = true);
+            'accept: loop {
+                let mut idle = true;
+                for listener in &listeners {
+                    let stream = match listener.accept() {
+                        Ok(stream) => stream,
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
+                        Err(ref e)
+                            if matches!(
+                                e.kind(),
+                                ErrorKind::Interrupted | ErrorKind::ConnectionAborted
+                            ) =>
+                        {
+                            idle = false;
+                            continue;
                         }
-                        thread::sleep(Duration::from_millis(1));
-                        continue;
-                    }
-                    Err(_) => break,
-                };
-
-                log::debug!(
-                    "Connection established with {}",
-                    stream
-                        .peer_addr()
-                        .ok()
-                        .map(|addr| format!("{}", addr))
-                        .unwrap_or_else(|| String::from("..."))
-                );
-
-                let dir = dirc.clone();
-                threadsc.lock().unwrap().execute(move || {
-                    match handle_connection(&mut stream, &dir) {
-                        Ok(_) => {}
                         Err(e) => {
-                            log::info!("{}", e);
-                            write_500(&mut stream, &format!("{}", e));
+                            log::error!("Failed to accept a connection, stopping: {}", e);
+                            handlec.set_status(|s| s.error = Some(e));
+                            break 'accept;
                         }
                     };
-                })
+                    idle = false;
+
+                    let peer = stream.peer();
+                    if let Err(e) = stream.configure(&sockets) {
+                        log::warn!("Failed to set socket options for {}: {}", peer, e);
+                    }
+                    let id = next_id;
+                    next_id += 1;
+                    events::send(
+                        &eventsc,
+                        ConnectionEvent::Opened {
+                            id,
+                            peer: peer.clone(),
+                        },
+                    );
+
+                    let (settings, events) = (settingsc.clone(), eventsc.clone());
+                    threadsc.spawn(Box::new(move || {
+                        let _conn = tracing::info_span!(
+                            "conn",
+                            transport = %L::TRANSPORT,
+                            peer = %peer,
+                            id
+                        )
+                        .entered();
+                        log::debug!("Connection established");
+                        let opened = Instant::now();
+                        let mut stream = Counting::new(stream);
+                        let reply = panics::isolate(&mut stream, |stream, reply| {
+                            handle_connection(stream, &settings, reply)
+                        });
+                        let written = stream.written();
+                        settings.usage.record(&peer, reply.path.as_deref(), written);
+                        // Closes the connection before it is reported closed
+                        drop(stream);
+                        events::send(
+                            &events,
+                            ConnectionEvent::Closed {
+                                id,
+                                peer,
+                                path: reply.path,
+                                bytes_sent: written,
+                                duration: opened.elapsed(),
+                            },
+                        );
+                    }))
+                }
+
+                // Poll the handle exit flag
+                if idle {
+                    if handlec.exit.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
             }
 
-            // Join the request threads
-            threadsc.lock().unwrap().join();
-            handlec.done.wait();
+            listeners.iter().for_each(Listener::close);
+
+            // Join the request threads, the guard then marks the server stopped
+            threadsc.join();
         }));
         Ok(handle)
     }
 
-    fn addr_str(&self) -> String {
-        format!("{}:{}", self.addr, self.port)
+    /// Starts sending [discovery] beacons for the listener until the server
+    /// shuts down
+    fn announce<L: Listener>(
+        &self,
+        bound: &BoundAddr,
+        to: SocketAddr,
+        handle: &Handle,
+    ) -> Result<(), ServerError> {
+        let port = match bound.port() {
+            Some(port) => port,
+            None => {
+                log::warn!(
+                    "Not announcing the server, {} listeners have no port",
+                    L::TRANSPORT
+                );
+                return Ok(());
+            }
+        };
+        let name = fs::canonicalize(&self.settings.dir)
+            .ok()
+            .and_then(|dir| dir.file_name().map(|n| n.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| String::from("httpfs"));
+        let beacon = Beacon {
+            transport: String::from(L::TRANSPORT),
+            port,
+            name,
+        };
+        log::info!("Announcing the server to {} as '{}'", to, beacon.name);
+        discovery::announce(beacon, to, handle.exit.clone()).map_err(ServerError::transport)
     }
 }
 
-/// Routes requests to the appropriate handler
-fn handle_connection(stream: &mut TcpStream, dir: &str) -> Result<(), ServerError> {
+/// Keeps a panicking request handler from taking its worker thread down with
+/// it and leaving the client without a response
+mod panics {
+    use std::{
+        any::Any,
+        backtrace::Backtrace,
+        cell::{Cell, RefCell},
+        panic::{self, AssertUnwindSafe},
+        sync::Once,
+    };
+
+    use super::{write_error, Reply};
+    use crate::{errors::ServerError, transport::Stream};
+
+    thread_local! {
+        /// Set while a handler is running in [isolate]
+        static ISOLATED: Cell<bool> = const { Cell::new(false) };
+
+        /// The report of the last panic caught by [isolate] on this thread
+        static REPORT: RefCell<Option<String>> = const { RefCell::new(None) };
+    }
+
+    /// Installs a panic hook that records a report with a backtrace for panics
+    /// inside [isolate] instead of printing them, so that they can be logged.
+    /// Other panics are passed on to the previous hook.
+    pub fn install_hook() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            let prev = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                if !ISOLATED.with(Cell::get) {
+                    return prev(info);
+                }
+                let location = info
+                    .location()
+                    .map(|l| format!(" at {}", l))
+                    .unwrap_or_default();
+                let report = format!(
+                    "Handler panicked{}: {}\n{}",
+                    location,
+                    message(info.payload()),
+                    Backtrace::force_capture()
+                );
+                REPORT.with(|r| *r.borrow_mut() = Some(report));
+            }));
+        });
+    }
+
+    /// Runs the handler, writing an error response if it fails. If it panics,
+    /// the panic is logged and the client gets a 500. The worker thread
+    /// survives, so the pool stays at full strength. Returns what the handler
+    /// learned about the request.
+    pub fn isolate<S: Stream>(
+        stream: &mut S,
+        handler: impl FnOnce(&mut S, &mut Reply) -> Result<(), ServerError>,
+    ) -> Reply {
+        let mut reply = Reply::default();
+        ISOLATED.with(|i| i.set(true));
+        let res = panic::catch_unwind(AssertUnwindSafe(|| handler(stream, &mut reply)));
+        ISOLATED.with(|i| i.set(false));
+
+        let _req = reply
+            .request_id
+            .as_ref()
+            .map(|id| tracing::info_span!("req", id = %id).entered());
+        let err = match res {
+            Ok(Ok(())) => return reply,
+            Ok(Err(e)) => {
+                log::info!("{}", e);
+                e
+            }
+            Err(payload) => {
+                let report = REPORT
+                    .with(|r| r.borrow_mut().take())
+                    .unwrap_or_else(|| format!("Handler panicked: {}", message(&*payload)));
+                log::error!("{}", report);
+                ServerError::new().msg("request handler panicked")
+            }
+        };
+        write_error(stream, &reply, &err);
+        reply
+    }
+
+    fn message(payload: &(dyn Any + Send)) -> &str {
+        payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("...")
+    }
+}
+
+/// What the response writers need to know about the request they answer. The
+/// default is for responses to requests that could not be parsed.
+#[derive(Debug, Default)]
+pub(crate) struct Reply {
+    proto: Proto,
+
+    /// Sent back in the [Server::REQUEST_ID_HEADER]
+    request_id: Option<String>,
+
+    /// The requested path, which the usage is counted for
+    path: Option<String>,
+
+    /// Set when the client accepts the [Server::error_pages]
+    pages: Option<Arc<ErrorPages>>,
+}
+
+/// Routes requests to the appropriate handler. Once the request is parsed,
+/// `reply` is filled in for the responses to it, including error responses.
+fn handle_connection(
+    stream: &mut impl Stream,
+    settings: &Settings,
+    reply: &mut Reply,
+) -> Result<(), ServerError> {
+    let dir = settings.dir.as_str();
+    let peer = stream.peer();
     // let mut reader = BufReader::with_capacity(BUFSIZE, stream.as_ref());
-    let scnr = BullshitScanner::new(stream);
-    let mut req = parse_http_request(scnr)?;
+    let scnr = BullshitScanner::new(stream).max_line_length(settings.max_line_length);
+    let mut req = parse_http_request_with(scnr, settings.strictness)?;
+    let id = request_id(&req);
+    let _req = tracing::info_span!(
+        "req",
+        id = %id,
+        method = ?req.method,
+        path = %req.file
+    )
+    .entered();
     log::info!("{}", req);
+    reply.proto = req.proto.clone();
+    reply.request_id = Some(id);
+    reply.path = Some(req.file.clone());
+    reply.pages = settings
+        .error_pages
+        .clone()
+        .filter(|_| pages::accepts_html(&req.headers));
+    let reply = &*reply;
+
+    if settings.admin && req.file == Server::ADMIN_LOG_LEVEL_PATH {
+        let mut body = String::new();
+        req.body
+            .read_to_string(&mut body)
+            .map_err(ServerError::transport)?;
+        let set = matches!(req.method, Method::POST);
+        return handle_log_level(stream, reply, set.then_some(body.trim()));
+    }
+    if settings.admin && req.file == Server::ADMIN_USAGE_PATH {
+        let usage = settings.usage.report(None);
+        return write_response(
+            stream,
+            reply,
+            StatusCode::OK,
+            usage.len().try_into()?,
+            "text/plain",
+            Some(&mut usage.as_bytes()),
+        );
+    }
+
+    if let Some(signed) = &settings.signed_urls {
+        if signed.protects(&req.file) {
+            signed.verify(&req.file, req.query.as_deref(), SystemTime::now())?;
+        }
+    }
+
+    match settings.router.find(req.method, &req.file) {
+        router::Routed::Found(handler, params) => {
+            let mut res = handler(&mut RouteRequest {
+                method: req.method,
+                path: &req.file,
+                headers: &req.headers,
+                params,
+                body: &mut req.body,
+            })?;
+            let upgrade = match res.upgrade.take() {
+                Some(upgrade) => upgrade,
+                None => return write_routed(stream, reply, res),
+            };
+
+            // What is left of the body is not for the new protocol
+            io::copy(&mut req.body, &mut io::sink()).map_err(ServerError::transport)?;
+            let requested = upgrade_requested(&req);
+            let buffered = req.body.into_inner().buffered().to_vec();
+            return write_upgraded(stream, reply, res, upgrade, requested, buffered);
+        }
+        router::Routed::WrongMethod(allowed) => {
+            return write_wrong_method(stream, reply, &allowed);
+        }
+        router::Routed::NotFound => {}
+    }
+
+    if let Some(proxy) = proxy::find(&settings.proxies, &req.file) {
+        let id = reply.request_id.as_deref().unwrap_or_default();
+        let mut upstream = proxy.send(&mut req, &peer, id, settings.proxy_timeout)?;
+        return proxy.relay(&mut upstream, stream);
+    }
 
     let filename = req.file.as_str();
+    let query = req.query.clone();
+    let checksum = reply.proto == Proto::HTTP1_1 && accepts_trailers(&req);
     match Requested::parse(dir, &req) {
-        Requested::Dir(file) => write_dir_listing(stream, &file),
+        Requested::Dir(file) => write_dir_listing(stream, reply, &file, query.as_deref()),
         Requested::File(file) => match open_file(&file) {
-            Ok((name, fh)) => write_file(stream, fh, &name),
-            Err(_) => write_404(stream, filename, dir),
+            Ok((name, fh)) => write_file(stream, reply, fh, &name, checksum),
+            Err(_) => write_404(stream, reply, filename, settings),
         },
         Requested::Upload(filename) => {
-            accept_file_upload(&filename, &mut req.body)?;
-            write_response::<File>(stream, "201 Created", 0, "", None)
+            let path = Path::new(&filename);
+            let _lock = settings.uploads.lock(path)?;
+            let kept = settings.trash.is_some() && is_regular_file(path);
+            let _reservation = match &settings.quota {
+                Some(quota) => Some(quota.reserve(path, req.body.limit(), kept)?),
+                None => None,
+            };
+            if let Some(trash) = settings.trash.as_ref().filter(|_| kept) {
+                let deleted = trash.keep(path)?;
+                if let Some(quota) = &settings.quota {
+                    quota.release(deleted);
+                }
+            }
+            match &settings.objects {
+                Some(objects) => {
+                    check_upload_target(path)?;
+                    objects.store(path, &mut req.body)?
+                }
+                None => accept_file_upload(&filename, &mut req.body)?,
+            }
+            write_response::<File>(stream, reply, StatusCode::CREATED, 0, "", None)
         }
-        Requested::None => write_404(stream, filename, dir),
-        Requested::NotAllowed(filename) => write_not_allowed(stream, &filename, dir),
+        Requested::None => write_404(stream, reply, filename, settings),
+        Requested::NotAllowed(filename) => write_not_allowed(stream, reply, &filename, settings),
+        Requested::Reserved(filename) => write_reserved(stream, reply, &filename),
     }
 }
 
+/// Sends the response of a [Router] handler
+fn write_routed(stream: &mut impl Write, reply: &Reply, res: Response) -> Result<(), ServerError> {
+    let headers = res
+        .headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    write_response_with_headers(
+        stream,
+        reply,
+        res.status,
+        BodyLength::Known(res.body.len().try_into()?),
+        Some(headers),
+        Some(&mut res.body.as_slice()),
+    )
+}
+
+/// Writes the head and the body of a response with a [Response::upgrade],
+/// then hands the connection to `upgrade`. `requested` is the protocol the client
+/// asked to switch to, if any, and `buffered` what it sent after the request.
+fn write_upgraded(
+    stream: &mut impl Stream,
+    reply: &Reply,
+    res: Response,
+    upgrade: Box<router::Upgrade>,
+    requested: Option<String>,
+    buffered: Vec<u8>,
+) -> Result<(), ServerError> {
+    let mut headers = res
+        .headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect::<Vec<_>>();
+    let status = match &requested {
+        Some(protocol) => {
+            headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Connection"));
+            headers.push(("Connection", "Upgrade"));
+            if !headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("Upgrade"))
+            {
+                headers.push(("Upgrade", protocol));
+            }
+            StatusCode::SWITCHING_PROTOCOLS
+        }
+        None => res.status,
+    };
+    write_response_with_headers(
+        stream,
+        reply,
+        status,
+        BodyLength::Close,
+        Some(headers),
+        Some(&mut res.body.as_slice()),
+    )?;
+
+    log::debug!("Handing the connection over to the route");
+    upgrade(&mut Upgraded::new(stream, buffered)).map_err(ServerError::transport)?;
+    stream.flush().map_err(ServerError::transport)
+}
+
+/// Answers a request for a routed path with a method that it has no route for
+fn write_wrong_method(
+    stream: &mut impl Write,
+    reply: &Reply,
+    allowed: &[Method],
+) -> Result<(), ServerError> {
+    let allowed = allowed
+        .iter()
+        .map(|method| format!("{:?}", method))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let body = format!("Allowed methods: {}\n", allowed);
+    write_response_with_headers(
+        stream,
+        reply,
+        StatusCode::METHOD_NOT_ALLOWED,
+        BodyLength::Known(body.len().try_into()?),
+        Some(vec![
+            ("Allow", allowed.as_str()),
+            ("Content-Type", "text/plain"),
+        ]),
+        Some(&mut body.as_bytes()),
+    )
+}
+
+/// Reports the global log level, changing it first if a new level is given.
+/// See [Server::ADMIN_LOG_LEVEL_PATH].
+fn handle_log_level(
+    stream: &mut impl Write,
+    reply: &Reply,
+    new_level: Option<&str>,
+) -> Result<(), ServerError> {
+    if let Some(new_level) = new_level {
+        match new_level.parse::<log::LevelFilter>() {
+            Ok(level) => {
+                log::set_max_level(level);
+                log::info!("Log level set to {}", level);
+            }
+            Err(_) => {
+                return write_400(
+                    stream,
+                    reply,
+                    &format!("Invalid log level '{}'\n", new_level),
+                )
+            }
+        }
+    }
+
+    let level = format!("{}\n", log::max_level().as_str().to_lowercase());
+    write_response(
+        stream,
+        reply,
+        StatusCode::OK,
+        level.len().try_into()?,
+        "text/plain",
+        Some(&mut stringreader::StringReader::new(level.as_str())),
+    )
+}
+
 /// Represents the file server operation that the user is requesting
 enum Requested {
     Dir(String),
     File(String),
     Upload(String),
     NotAllowed(String),
+    Reserved(String),
     None,
 }
 
@@ -229,30 +1310,32 @@ impl Requested {
             .ok()
             .unwrap_or_else(|| PathBuf::from(dir));
 
-        let file = dir.join(req.file.trim_start_matches('/'));
-        let file = file
-            .canonicalize()
-            .ok()
-            .unwrap_or(file)
-            .to_string_lossy()
-            .to_string();
+        // Nothing outside the directory is looked at, not even to see whether
+        // it exists
+        let Some(path) = Self::lexical_path(&dir, &req.file) else {
+            let file = dir.join(req.file.trim_start_matches(['/', '\\']));
+            return Self::NotAllowed(file.to_string_lossy().to_string());
+        };
+        let path = path.canonicalize().ok().unwrap_or(path);
+        let file = path.to_string_lossy().to_string();
 
         log::debug!("Computed request file path: '{}'", file);
 
-        // Check if the user is allowed to access this file (for either reading
-        // or writing)
-        if Self::file_not_allowed(&file, &dir.to_string_lossy()) {
+        // Symlinks inside the directory may still lead out of it
+        if Self::file_not_allowed(&path, &dir) {
             return Self::NotAllowed(file);
         }
+        if Self::file_reserved(&path, &dir) {
+            return Self::Reserved(file);
+        }
 
         match req.method {
             Method::POST => Self::Upload(file),
             Method::Unsupported => Self::None,
             Method::GET => {
-                let p = Path::new(&file);
-                if p.is_dir() {
+                if path.is_dir() {
                     Self::Dir(file)
-                } else if p.is_file() {
+                } else if path.is_file() {
                     Self::File(file)
                 } else {
                     Self::None
@@ -261,97 +1344,242 @@ impl Requested {
         }
     }
 
-    /// Returns `true` if this file is located outside the dir being served,
-    /// `false` otherwise
-    fn file_not_allowed(file: &str, dir: &str) -> bool {
-        let mut collect = Vec::with_capacity(64);
-        for segment in file.split('/') {
+    /// Maps the request path onto `dir` without touching the filesystem.
+    /// Both `/` and `\\` separate segments, `.` segments are dropped and `..`
+    /// segments remove the one before. Returns [None] if the path climbs out
+    /// of `dir` at any point, or has a segment that is not a plain file name,
+    /// e.g. a Windows drive like `C:`.
+    fn lexical_path(dir: &Path, path: &str) -> Option<PathBuf> {
+        let mut segments = Vec::new();
+        for segment in path.split(['/', '\\']) {
             match segment {
-                "" | "." => continue,
+                "" | "." => {}
                 ".." => {
-                    if collect.len() > 1 {
-                        collect.pop();
+                    segments.pop()?;
+                }
+                segment => {
+                    let mut components = Path::new(segment).components();
+                    match (components.next(), components.next()) {
+                        (Some(Component::Normal(name)), None) if name == segment => {
+                            segments.push(segment)
+                        }
+                        _ => return None,
+                    }
+                    if cfg!(windows) && segment.contains(':') {
+                        return None;
                     }
                 }
-                segment => collect.push(segment),
             }
         }
-        let file = format!("/{}", collect.join("/"));
-        !file.starts_with(dir)
+        Some(
+            segments
+                .iter()
+                .fold(dir.to_path_buf(), |path, s| path.join(s)),
+        )
+    }
+
+    /// Returns `true` if this file is located outside the dir being served,
+    /// `false` otherwise. Symlinks are followed, in the file's path or in that
+    /// of its nearest existing parent for files that do not exist yet.
+    fn file_not_allowed(file: &Path, dir: &Path) -> bool {
+        let resolved = file
+            .ancestors()
+            .find_map(|ancestor| ancestor.canonicalize().ok());
+        match resolved {
+            Some(resolved) => !resolved.starts_with(dir),
+            None => true,
+        }
+    }
+
+    /// Returns `true` if this file is in one of the [Server::RESERVED_DIRS].
+    /// They are matched regardless of case, for case-insensitive filesystems.
+    fn file_reserved(file: &Path, dir: &Path) -> bool {
+        let first = file
+            .strip_prefix(dir)
+            .ok()
+            .and_then(|rest| rest.components().next());
+        match first {
+            Some(Component::Normal(name)) => Server::RESERVED_DIRS
+                .iter()
+                .any(|reserved| name.eq_ignore_ascii_case(reserved)),
+            _ => false,
+        }
     }
 }
 
 /// Saves the given file with the provided file name
 fn accept_file_upload(filename: &str, body: &mut dyn Read) -> Result<(), ServerError> {
-    let path = Path::new(filename);
-    if path.is_dir() {
-        return Err(ServerError::writing_to_directory());
-    } else if path.is_symlink() {
-        return Err(ServerError::writing_to_symlink());
-    }
+    check_upload_target(Path::new(filename))?;
 
     let mut fh = OpenOptions::new()
         .write(true)
         .create(true)
-        .open(filename)
-        .map_err(wrap)?;
+        .truncate(false)
+        .open(filename)?;
 
-    std::io::copy(body, &mut fh).map(|_| ()).map_err(wrap)
+    std::io::copy(body, &mut fh)
+        .map(|_| ())
+        .map_err(ServerError::from)
 }
 
-fn write_dir_listing(stream: &mut TcpStream, dir: &str) -> Result<(), ServerError> {
+/// Refuses uploads that would replace a directory or a symlink
+fn check_upload_target(path: &Path) -> Result<(), ServerError> {
+    if path.is_dir() {
+        Err(ServerError::writing_to_directory())
+    } else if path.is_symlink() {
+        Err(ServerError::writing_to_symlink())
+    } else {
+        Ok(())
+    }
+}
+
+/// Lists the directory, sorted, filtered and paginated as the query of the
+/// request asks, see [ListingQuery]. The pages before and after it are linked
+/// in the page and in a `Link` header.
+///
+/// The page is streamed with chunked encoding as it's templated, so only its
+/// entries are held in memory, never the whole document.
+fn write_dir_listing(
+    stream: &mut impl Write,
+    reply: &Reply,
+    dir: &str,
+    query: Option<&str>,
+) -> Result<(), ServerError> {
     log::debug!("Listing directory {}", dir);
+    let query = ListingQuery::parse(query)?;
 
     // Gather a list of files and inject it into the template
-    let template = template(
-        fs::read_dir(dir)
-            .map_err(wrap)?
-            .flat_map(Result::ok)
-            .map(|file| (file.file_type(), file))
-            .filter(|(ft, _)| ft.as_ref().map(|t| !t.is_symlink()).unwrap_or(false))
-            .map(|(ft, f)| {
-                (
-                    ft.map(|x| x.is_dir()).unwrap_or(false),
-                    String::from(f.file_name().to_string_lossy()),
-                )
-            })
-            .map(|(ft, f)| if ft { format!("{}/", f) } else { f }),
-    );
+    let page = listing::read(Path::new(dir), &query)?;
 
-    write_response(
+    let link = page.link_header();
+    let mut headers = vec![("Content-Type", "text/html")];
+    if let Some(link) = &link {
+        headers.push(("Link", link));
+    }
+    write_head(
         stream,
-        "200 OK",
-        template.len().try_into().map_err(wrap)?,
-        "text/html",
-        Some(&mut stringreader::StringReader::new(template.as_str())),
-    )
+        reply,
+        StatusCode::OK,
+        BodyLength::Unknown,
+        Some(headers),
+    )?;
+
+    // Buffered so that each link isn't a chunk of its own
+    let write_page = |out: &mut dyn Write| {
+        let mut out = io::BufWriter::new(out);
+        write_template(
+            &mut out,
+            page.entries.iter().map(Entry::link),
+            page.prev.as_deref(),
+            page.next.as_deref(),
+        )?;
+        out.flush()
+    };
+    let written = if reply.proto == Proto::HTTP1_0 {
+        write_page(stream)
+    } else {
+        let mut chunked = Chunked::new(&mut *stream);
+        write_page(&mut chunked).and_then(|_| chunked.finish(&[]))
+    };
+    written.map_err(ServerError::transport)
 }
 
 fn open_file(file: &str) -> Result<(String, File), ServerError> {
-    let fh = File::open(file).map_err(wrap)?;
+    let fh = File::open(file)?;
     log::debug!("Opening file {}", file);
     Ok((String::from(file), fh))
 }
 
+/// Writes the status line, the headers and the body, framed according to its
+/// [BodyLength]. HTTP/1.0 has no chunked encoding, so a body of unknown length
+/// is sent as is and ends when the connection is closed.
 fn write_response_with_headers(
-    stream: &mut TcpStream,
-    status: &str,
-    body_length: u64,
-    headers: Option<HashMap<&str, &str>>,
+    stream: &mut impl Write,
+    reply: &Reply,
+    status: StatusCode,
+    body_length: BodyLength,
+    headers: Option<Vec<(&str, &str)>>,
     body: Option<&mut impl Read>,
+) -> Result<(), ServerError> {
+    write_head(stream, reply, status, body_length, headers)?;
+    match (body, body_length) {
+        (Some(body), BodyLength::Known(len)) => {
+            std::io::copy(&mut body.take(len), stream).map_err(ServerError::transport)?;
+            stream.flush().map_err(ServerError::transport)
+        }
+        (Some(body), BodyLength::Unknown) if reply.proto == Proto::HTTP1_0 => {
+            std::io::copy(body, stream).map_err(ServerError::transport)?;
+            stream.flush().map_err(ServerError::transport)
+        }
+        (Some(body), BodyLength::Close) => {
+            std::io::copy(body, stream).map_err(ServerError::transport)?;
+            stream.flush().map_err(ServerError::transport)
+        }
+        (Some(body), BodyLength::Unknown) => {
+            let mut chunked = Chunked::new(&mut *stream);
+            std::io::copy(body, &mut chunked).map_err(ServerError::transport)?;
+            chunked.finish(&[]).map_err(ServerError::transport)
+        }
+        (None, BodyLength::Unknown) if reply.proto == Proto::HTTP1_0 => Ok(()),
+        (None, BodyLength::Unknown) => Chunked::new(stream)
+            .finish(&[])
+            .map_err(ServerError::transport),
+        (None, BodyLength::Known(_) | BodyLength::Close) => Ok(()),
+    }
+}
+
+/// Writes the status line and the headers, with the framing headers for the
+/// [BodyLength]. Responses to HTTP/1.0 requests say so in the status line.
+///
+/// `Date`, `Server`, `Connection` and the [Server::REQUEST_ID_HEADER] are
+/// added unless the handler set them.
+/// Each connection carries one request, so `Connection` is always `close`.
+fn write_head(
+    stream: &mut impl Write,
+    reply: &Reply,
+    status: StatusCode,
+    body_length: BodyLength,
+    headers: Option<Vec<(&str, &str)>>,
 ) -> Result<(), ServerError> {
     let headers = headers.unwrap_or_default();
     log::debug!(
-        "Writing response {}, length {}, headers {:?}",
+        "Writing response {}, length {:?}, headers {:?}",
         status,
         body_length,
         headers
     );
 
-    let mut out = vec![format!("HTTP/1.1 {}", status)];
+    let http1_0 = reply.proto == Proto::HTTP1_0;
+    let version = if http1_0 { "HTTP/1.0" } else { "HTTP/1.1" };
+    let mut out = vec![format!("{} {}", version, status)];
 
-    if !headers.contains_key("Content-Length") {
-        out.push(format!("Content-Length: {}", body_length));
+    let date = date::http_date(SystemTime::now());
+    for (key, value) in [
+        ("Date", Some(date.as_str())),
+        ("Server", Some(Server::SERVER_HEADER)),
+        ("Connection", Some("close")),
+        (Server::REQUEST_ID_HEADER, reply.request_id.as_deref()),
+    ] {
+        match value {
+            Some(value) if !headers.iter().any(|(k, _)| k.eq_ignore_ascii_case(key)) => {
+                out.push(format!("{}: {}", key, value))
+            }
+            _ => {}
+        }
+    }
+
+    match body_length {
+        BodyLength::Known(len)
+            if !headers
+                .iter()
+                .any(|(k, _)| k.eq_ignore_ascii_case("Content-Length")) =>
+        {
+            out.push(format!("Content-Length: {}", len))
+        }
+        BodyLength::Known(_) => {}
+        BodyLength::Unknown if http1_0 => {}
+        BodyLength::Close => {}
+        BodyLength::Unknown => out.push(String::from("Transfer-Encoding: chunked")),
     }
 
     for (key, value) in headers.iter() {
@@ -362,91 +1590,211 @@ fn write_response_with_headers(
     out.push(String::from(""));
     let out = out.join("\r\n");
 
-    stream.write(out.as_bytes()).map_err(wrap)?;
-    stream.flush().map_err(wrap)?;
-
-    match body {
-        Some(body) => {
-            std::io::copy(body, stream).map_err(wrap)?;
-            stream.flush().map_err(wrap)
-        }
-        None => Ok(()),
-    }
+    stream
+        .write_all(out.as_bytes())
+        .map_err(ServerError::transport)?;
+    stream.flush().map_err(ServerError::transport)
 }
 
 /// Writes a response to the stream
 fn write_response<R: Read>(
-    stream: &mut TcpStream,
-    status: &str,
+    stream: &mut impl Write,
+    reply: &Reply,
+    status: StatusCode,
     body_length: u64,
     content_type: &str,
     body: Option<&mut R>,
 ) -> Result<(), ServerError> {
     write_response_with_headers(
         stream,
+        reply,
         status,
-        body_length,
-        Some(HashMap::from([("Content-Type", content_type)])),
+        BodyLength::Known(body_length),
+        Some(vec![("Content-Type", content_type)]),
         body,
     )
 }
 
-fn wrap<E: std::error::Error + 'static>(err: E) -> ServerError {
-    ServerError::wrap_err(err)
-}
+/// Writes a file response. With `checksum`, the file is sent chunked and
+/// followed by the [Server::CHECKSUM_TRAILER].
+fn write_file(
+    stream: &mut impl Write,
+    reply: &Reply,
+    mut fh: File,
+    filename: &str,
+    checksum: bool,
+) -> Result<(), ServerError> {
+    let mimetype = parse_mimetype(filename);
+    let disposition = content_disposition(file_name(filename));
+    let mut headers = vec![
+        ("Content-Type", mimetype.as_str()),
+        ("Content-Disposition", disposition.as_str()),
+    ];
+    if !checksum {
+        let len = BodyLength::Known(fh.metadata()?.len());
+        return write_response_with_headers(
+            stream,
+            reply,
+            StatusCode::OK,
+            len,
+            Some(headers),
+            Some(&mut fh),
+        );
+    }
 
-/// Writes a file response
-fn write_file(stream: &mut TcpStream, mut fh: File, filename: &str) -> Result<(), ServerError> {
-    write_response_with_headers(
+    headers.push(("Trailer", Server::CHECKSUM_TRAILER));
+    write_head(
         stream,
-        "200 OK",
-        fh.metadata().map_err(wrap)?.len(),
-        Some(HashMap::from([
-            ("Content-Type", parse_mimetype(filename).as_str()),
-            (
-                "Content-Disposition",
-                &format!(
-                    r#"attachment; filename="{}""#,
-                    filename.split('/').last().unwrap_or(filename)
-                ),
-            ),
-        ])),
-        Some(&mut fh),
+        reply,
+        StatusCode::OK,
+        BodyLength::Unknown,
+        Some(headers),
+    )?;
+    let mut hashing = Hashing::new(Chunked::new(&mut *stream));
+    std::io::copy(&mut fh, &mut hashing).map_err(ServerError::transport)?;
+    let (chunked, digest) = hashing.finish();
+    chunked
+        .finish(&[(Server::CHECKSUM_TRAILER, &digest)])
+        .map_err(ServerError::transport)
+}
+
+/// Returns the client's [Server::REQUEST_ID_HEADER] if it is a usable id, at
+/// most 128 visible ASCII characters, otherwise a new random one
+fn request_id<R: Read>(req: &Request<R>) -> String {
+    let theirs = req
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(Server::REQUEST_ID_HEADER))
+        .map(|(_, id)| id.as_str())
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()));
+    if let Some(id) = theirs {
+        return String::from(id);
+    }
+
+    let mut bytes = [0; 8];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        log::warn!("Failed to generate a random request id");
+    }
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns the protocol the client asked to switch to, if it sent
+/// `Connection: Upgrade` and an `Upgrade` header. HTTP/1.0 has no upgrades.
+fn upgrade_requested<R: Read>(req: &Request<R>) -> Option<String> {
+    let header = |wanted: &str| {
+        req.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.as_str())
+    };
+    let connection = header("Connection")?
+        .split(',')
+        .any(|option| option.trim().eq_ignore_ascii_case("upgrade"));
+    match header("Upgrade") {
+        Some(protocol) if connection && req.proto == Proto::HTTP1_1 => Some(String::from(protocol)),
+        _ => None,
+    }
+}
+
+/// Returns `true` if the client said it accepts trailers with `TE: trailers`
+fn accepts_trailers<R: Read>(req: &Request<R>) -> bool {
+    req.headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("TE")
+            && value
+                .split(',')
+                .any(|te| te.trim().eq_ignore_ascii_case("trailers"))
+    })
+}
+
+/// Header values must be ASCII, so names that aren't plain ASCII get an
+/// approximate `filename` and the exact UTF-8 name in `filename*` (RFC 6266)
+fn content_disposition(name: &str) -> String {
+    let plain = name
+        .chars()
+        .all(|c| c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\');
+    if plain {
+        return format!(r#"attachment; filename="{}""#, name);
+    }
+    let fallback = name
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    format!(
+        r#"attachment; filename="{}"; filename*=UTF-8''{}"#,
+        fallback,
+        percent_encode(name)
     )
 }
 
-fn write_500(stream: &mut TcpStream, msg: &str) {
-    if let Err(e) = write_response(
-        stream,
-        "500 Internal Server Error",
-        msg.len().try_into().unwrap_or(0),
-        "text/plain",
-        Some(&mut stringreader::StringReader::new(msg)),
-    ) {
+/// Writes an error response with the status matching the [ServerError]
+fn write_error(stream: &mut impl Write, reply: &Reply, err: &ServerError) {
+    if let Err(e) = write_error_message(stream, reply, err.status(), &format!("{}\n", err)) {
         log::debug!("{}", e);
     };
 }
 
-/// Writes a '404 Not Found' response
-fn write_404(stream: &mut TcpStream, filename: &str, dir: &str) -> Result<(), ServerError> {
-    let body = format!(
-        "File '{}' could not be found on the server (directory being served is {})\n",
-        filename, dir
-    );
+/// Writes an error response, with the client's [ErrorPage] for the status if
+/// there is one, and with the plain text message otherwise
+fn write_error_message(
+    stream: &mut impl Write,
+    reply: &Reply,
+    status: StatusCode,
+    msg: &str,
+) -> Result<(), ServerError> {
+    let page = reply.pages.as_ref().and_then(|pages| {
+        pages.render(&ErrorPage {
+            status,
+            message: msg.trim_end(),
+            path: reply.path.as_deref(),
+        })
+    });
+    let (body, content_type) = match &page {
+        Some(page) => (page.as_str(), "text/html"),
+        None => (msg, "text/plain"),
+    };
+    write_response(
+        stream,
+        reply,
+        status,
+        body.len().try_into()?,
+        content_type,
+        Some(&mut body.as_bytes()),
+    )
+}
 
+/// Writes a '400 Bad Request' response
+fn write_400(stream: &mut impl Write, reply: &Reply, msg: &str) -> Result<(), ServerError> {
     write_response(
         stream,
-        "404 Not Found",
-        body.len().try_into().map_err(|e| {
-            ServerError::new()
-                .msg("bad numerical conversion")
-                .wrap(Box::new(e))
-        })?,
+        reply,
+        StatusCode::BAD_REQUEST,
+        msg.len().try_into()?,
         "text/plain",
-        Some(&mut stringreader::StringReader::new(body.as_str())),
+        Some(&mut stringreader::StringReader::new(msg)),
     )
 }
 
+/// Writes a '404 Not Found' response
+fn write_404(
+    stream: &mut impl Write,
+    reply: &Reply,
+    filename: &str,
+    settings: &Settings,
+) -> Result<(), ServerError> {
+    log::debug!("'{}' is not in {}", filename, abs_path(&settings.dir));
+    let body = match settings.reveal_paths {
+        true => format!(
+            "File '{}' could not be found on the server (directory being served is {})\n",
+            filename, settings.dir
+        ),
+        false => format!("File '{}' could not be found on the server\n", filename),
+    };
+    write_error_message(stream, reply, StatusCode::NOT_FOUND, &body)
+}
+
 fn abs_path(file: &str) -> String {
     Path::new(file)
         .canonicalize()
@@ -455,34 +1803,59 @@ fn abs_path(file: &str) -> String {
         .unwrap_or_else(|| String::from(file))
 }
 
-fn write_not_allowed(stream: &mut TcpStream, filename: &str, dir: &str) -> Result<(), ServerError> {
-    let body = format!(
-        concat!(
-            "File '{}' is located outside the directory that is being served\r\n\r\n",
-            "Only files in directory '{}' may be accessed\r\n"
+/// Writes a '403 Forbidden' response for a path that leads out of the served
+/// directory
+fn write_not_allowed(
+    stream: &mut impl Write,
+    reply: &Reply,
+    filename: &str,
+    settings: &Settings,
+) -> Result<(), ServerError> {
+    let (file, dir) = (abs_path(filename), abs_path(&settings.dir));
+    log::info!("Refusing '{}', it is outside of {}", file, dir);
+    let body = match settings.reveal_paths {
+        true => format!(
+            concat!(
+                "File '{}' is located outside the directory that is being served\r\n\r\n",
+                "Only files in directory '{}' may be accessed\r\n"
+            ),
+            file, dir
+        ),
+        false => format!(
+            "File '{}' is located outside the directory that is being served\r\n",
+            reply.path.as_deref().unwrap_or_default()
         ),
-        abs_path(filename),
-        abs_path(dir)
+    };
+    write_error_message(stream, reply, StatusCode::FORBIDDEN, &body)
+}
+
+/// Refuses requests for the [Server::RESERVED_DIRS]
+fn write_reserved(
+    stream: &mut impl Write,
+    reply: &Reply,
+    filename: &str,
+) -> Result<(), ServerError> {
+    log::info!("Refusing '{}', it is reserved by the server", filename);
+    let body = format!(
+        "File '{}' is reserved by the server\r\n",
+        reply.path.as_deref().unwrap_or_default()
     );
+    write_error_message(stream, reply, StatusCode::FORBIDDEN, &body)
+}
 
-    write_response(
-        stream,
-        "403 Forbidden",
-        body.len().try_into().map_err(|e| {
-            ServerError::new()
-                .msg("bad numerical conversion")
-                .wrap(Box::new(e))
-        })?,
-        "text/plain",
-        Some(&mut stringreader::StringReader::new(body.as_str())),
-    )
+/// Returns the last component of the path
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
 }
 
 /// Parses the mime type from a non-exhaustive list
 fn parse_mimetype(filename: &str) -> String {
-    match filename.split('/').last().unwrap_or(filename) {
+    match file_name(filename) {
         "Makefile" => mime::TEXT_PLAIN,
-        other => match other.split('.').last() {
+        other => match other.split('.').next_back() {
             Some(x) => match x {
                 "png" => mime::IMAGE_PNG,
                 "jpg" => mime::IMAGE_JPEG,
@@ -504,3 +1877,399 @@ fn parse_mimetype(filename: &str) -> String {
     }
     .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::slow::SlowStream;
+    use std::{
+        io::{BufRead, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    /// Returns the status line the client receives after the handler runs
+    fn isolated_status(
+        handler: impl FnOnce(&mut TcpStream, &mut Reply) -> Result<(), ServerError>,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        panics::install_hook();
+        panics::isolate(&mut stream, handler);
+        drop(stream);
+
+        let mut status = String::new();
+        BufReader::new(client).read_line(&mut status).unwrap();
+        String::from(status.trim_end())
+    }
+
+    /// Writes a response without headers and returns it, leaving out the
+    /// `Date` and `Server` headers
+    fn written(proto: Proto, body_length: BodyLength, body: &str) -> String {
+        let mut out = Vec::new();
+        write_response_with_headers(
+            &mut out,
+            &Reply {
+                proto,
+                ..Default::default()
+            },
+            StatusCode::OK,
+            body_length,
+            None,
+            Some(&mut body.as_bytes()),
+        )
+        .unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .split_inclusive("\r\n")
+            .filter(|line| !line.starts_with("Date: ") && !line.starts_with("Server: "))
+            .collect()
+    }
+
+    #[test]
+    fn test_unknown_length_is_chunked() {
+        assert_eq!(
+            concat!(
+                "HTTP/1.1 200 OK\r\nConnection: close\r\nTransfer-Encoding: chunked\r\n\r\n",
+                "9\r\ngenerated\r\n0\r\n\r\n"
+            ),
+            written(Proto::HTTP1_1, BodyLength::Unknown, "generated")
+        );
+    }
+
+    #[test]
+    fn test_http1_0_unknown_length_is_close_delimited() {
+        assert_eq!(
+            "HTTP/1.0 200 OK\r\nConnection: close\r\n\r\ngenerated",
+            written(Proto::HTTP1_0, BodyLength::Unknown, "generated")
+        );
+    }
+
+    #[test]
+    fn test_known_length_is_not_exceeded() {
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 4\r\n\r\ngrow",
+            written(Proto::HTTP1_1, BodyLength::Known(4), "grown since")
+        );
+    }
+
+    #[test]
+    fn test_standard_headers() {
+        let mut out = Vec::new();
+        write_head(
+            &mut out,
+            &Reply::default(),
+            StatusCode::OK,
+            BodyLength::Known(0),
+            Some(vec![("server", "custom")]),
+        )
+        .unwrap();
+        let head = String::from_utf8(out).unwrap();
+        assert!(
+            head.contains("\r\nDate: ") && head.contains(" GMT\r\n"),
+            "{}",
+            head
+        );
+        assert!(head.contains("\r\nserver: custom\r\n"), "{}", head);
+        assert!(!head.contains(Server::SERVER_HEADER), "{}", head);
+    }
+
+    #[test]
+    fn test_given_length_is_not_repeated() {
+        let mut out = Vec::new();
+        write_head(
+            &mut out,
+            &Reply::default(),
+            StatusCode::OK,
+            BodyLength::Known(4),
+            Some(vec![("content-length", "4")]),
+        )
+        .unwrap();
+        let head = String::from_utf8(out).unwrap();
+        assert_eq!(
+            1,
+            head.to_lowercase().matches("content-length").count(),
+            "{}",
+            head
+        );
+    }
+
+    #[test]
+    fn test_handler_panic_gets_500() {
+        assert_eq!(
+            "HTTP/1.1 500 Internal Server Error",
+            isolated_status(|_, _| panic!("boom"))
+        );
+    }
+
+    #[test]
+    fn test_handler_error_gets_its_status() {
+        assert_eq!(
+            "HTTP/1.1 404 Not Found",
+            isolated_status(|_, _| Err(ServerError::not_found("/nope")))
+        );
+    }
+
+    #[test]
+    fn test_traversal_is_rejected() {
+        let dir = Path::new("/srv/files");
+        for attack in [
+            "/..",
+            "/../etc/passwd",
+            "/../../../../etc/passwd",
+            "/a/../../etc/passwd",
+            "/a/b/../../../etc/passwd",
+            "/./../etc/passwd",
+            "//../etc/passwd",
+            "/..\\etc\\passwd",
+            "/a\\..\\..\\etc\\passwd",
+            "\\..\\etc\\passwd",
+            "/a/..\\../etc/passwd",
+            "/../files/a.txt",
+            "/../files-other/a.txt",
+        ] {
+            assert_eq!(None, Requested::lexical_path(dir, attack), "{}", attack);
+        }
+    }
+
+    #[test]
+    fn test_paths_inside_are_normalized() {
+        let dir = Path::new("/srv/files");
+        for (path, want) in [
+            ("/", "/srv/files"),
+            ("/a.txt", "/srv/files/a.txt"),
+            ("//a.txt", "/srv/files/a.txt"),
+            ("/./a.txt", "/srv/files/a.txt"),
+            ("/a/../b.txt", "/srv/files/b.txt"),
+            ("/a/b/../../c.txt", "/srv/files/c.txt"),
+            ("/a\\b.txt", "/srv/files/a/b.txt"),
+            ("/a/./b/.", "/srv/files/a/b"),
+            ("/.../a.txt", "/srv/files/.../a.txt"),
+            ("/..a/b..", "/srv/files/..a/b.."),
+        ] {
+            assert_eq!(
+                Some(PathBuf::from(want)),
+                Requested::lexical_path(dir, path),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_out_of_the_dir_are_not_followed() {
+        let root = std::env::temp_dir().join(format!("httpfs-symlinks-{}", std::process::id()));
+        let (dir, outside) = (root.join("served"), root.join("outside"));
+        fs::create_dir_all(&dir).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("link")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        for path in ["/link/secret.txt", "/link/new.txt", "/link/new/deeper.txt"] {
+            let lexical = Requested::lexical_path(&dir, path).unwrap();
+            assert!(Requested::file_not_allowed(&lexical, &dir), "{}", path);
+        }
+        let inside = Requested::lexical_path(&dir, "/new/deeper.txt").unwrap();
+        assert!(!Requested::file_not_allowed(&inside, &dir));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_reserved_dirs() {
+        let dir = Path::new("/srv/files");
+        for (path, want) in [
+            ("/.objects", true),
+            ("/.objects/ab/abcd", true),
+            ("/x/../.objects/ab/abcd", true),
+            ("/.OBJECTS/ab/abcd", true),
+            ("/.trash/notes.txt.1", true),
+            ("/sub/.objects/ab/abcd", false),
+            ("/.objects.txt", false),
+            ("/", false),
+        ] {
+            let lexical = Requested::lexical_path(dir, path).unwrap();
+            assert_eq!(want, Requested::file_reserved(&lexical, dir), "{}", path);
+        }
+    }
+
+    /// Settings with every optional feature off
+    fn settings(dir: &str) -> Settings {
+        Settings {
+            dir: String::from(dir),
+            admin: false,
+            quota: None,
+            trash: None,
+            objects: None,
+            uploads: UploadLocks {
+                paths: Mutex::new(HashMap::new()),
+                turn: Condvar::new(),
+                max_waiters: 0,
+                wait: Duration::ZERO,
+            },
+            proxies: Vec::new(),
+            proxy_timeout: Server::DEFAULT_PROXY_TIMEOUT,
+            router: Router::new(),
+            signed_urls: None,
+            error_pages: None,
+            reveal_paths: false,
+            max_line_length: Server::DEFAULT_MAX_LINE_LENGTH,
+            strictness: Strictness::default(),
+            usage: Arc::new(Usage::default()),
+        }
+    }
+
+    /// Handles `request` over a loopback connection that moves a few bytes
+    /// per read and write, and returns the response
+    fn handled_slowly(settings: &Settings, request: &str, max: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let request = String::from(request);
+        let client = thread::spawn(move || {
+            client.write_all(request.as_bytes()).unwrap();
+            let mut res = String::new();
+            let _ = client.read_to_string(&mut res);
+            res
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = SlowStream::new(stream)
+            .delay(Duration::from_micros(10))
+            .max_read(max)
+            .max_write(max);
+        let res = handle_connection(&mut stream, settings, &mut Reply::default());
+        drop(stream);
+        let response = client.join().unwrap();
+        assert!(res.is_ok(), "{:?}\n{}", res.err(), response);
+        response
+    }
+
+    #[test]
+    fn test_partial_reads_and_writes() {
+        let root = std::env::temp_dir().join(format!("httpfs-slow-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.txt"), "hello world\n").unwrap();
+        let settings = settings(root.to_str().unwrap());
+
+        for max in [1, 2, 3, 7, 64] {
+            let get = |extra: &str| {
+                let request = format!("GET /a.txt HTTP/1.1\r\nHost: localhost{}\r\n\r\n", extra);
+                handled_slowly(&settings, &request, max)
+            };
+            let res = get("");
+            assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}: {}", max, res);
+            assert!(
+                res.contains("\r\nContent-Length: 12\r\n"),
+                "{}: {}",
+                max,
+                res
+            );
+            assert!(res.ends_with("\r\n\r\nhello world\n"), "{}: {}", max, res);
+
+            let res = get("\r\nTE: trailers");
+            assert!(
+                res.contains("\r\n\r\nc\r\nhello world\n\r\n0\r\n"),
+                "{}: {}",
+                max,
+                res
+            );
+
+            let res = handled_slowly(
+                &settings,
+                "POST /b.txt HTTP/1.1\r\nContent-Length: 9\r\n\r\nuploaded\n",
+                max,
+            );
+            assert!(
+                res.starts_with("HTTP/1.1 201 Created\r\n"),
+                "{}: {}",
+                max,
+                res
+            );
+            assert_eq!(
+                "uploaded\n",
+                fs::read_to_string(root.join("b.txt")).unwrap()
+            );
+
+            let res = handled_slowly(&settings, "GET /nope.txt HTTP/1.1\r\n\r\n", max);
+            assert!(
+                res.starts_with("HTTP/1.1 404 Not Found\r\n"),
+                "{}: {}",
+                max,
+                res
+            );
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// A listener whose every accept fails, or panics
+    struct Broken {
+        panics: bool,
+    }
+
+    impl Listener for Broken {
+        type Stream = TcpStream;
+        const TRANSPORT: &'static str = "broken";
+
+        fn accept(&self) -> io::Result<TcpStream> {
+            if self.panics {
+                panic!("listener panicked");
+            }
+            Err(io::Error::other("listener broke"))
+        }
+
+        fn set_nonblocking(&self, _: bool) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn bound_addr(&self) -> io::Result<BoundAddr> {
+            Ok(BoundAddr::Tcp(SocketAddr::new(Server::LOCALHOST, 0)))
+        }
+    }
+
+    impl Bindable for Broken {
+        type Listener = Broken;
+
+        fn bind(&self, _: &BindOptions) -> io::Result<Broken> {
+            Ok(Broken {
+                panics: self.panics,
+            })
+        }
+    }
+
+    fn broken_runner() -> ServerRunner {
+        ServerRunner {
+            settings: Arc::new(settings("./")),
+            threads: Arc::new(ThreadPool::new(1)),
+            sockets: SocketOptions::default(),
+            bind_options: BindOptions::default(),
+            announce: None,
+            usage_report: None,
+            events: None,
+            before_accept: None,
+        }
+    }
+
+    #[test]
+    fn test_accept_errors_reach_join() {
+        let mut handle = broken_runner().serve(Broken { panics: false }).unwrap();
+        handle.wait_ready(Duration::from_secs(5)).unwrap();
+
+        // Shutting down a server that has already stopped returns right away
+        handle.shutdown();
+        let err = handle.join().unwrap_err();
+        assert!(err.to_string().contains("listener broke"), "{}", err);
+    }
+
+    #[test]
+    fn test_shutdown_after_accept_panics() {
+        let mut handle = broken_runner().serve(Broken { panics: true }).unwrap();
+
+        // Doesn't wait forever for a thread that is gone
+        handle.shutdown();
+        assert!(!handle.is_running());
+        let err = handle.join().unwrap_err();
+        assert!(err.to_string().contains("panicked"), "{}", err);
+    }
+}
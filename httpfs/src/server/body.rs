@@ -0,0 +1,93 @@
+//!
+//! Framing for response bodies. A body whose length is known up front is
+//! sent as is, after a `Content-Length` header. Otherwise it is sent with
+//! `Transfer-Encoding: chunked`, as it is read.
+//!
+
+use std::io::{self, Write};
+
+/// How long a response body is, which decides how it is framed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyLength {
+    /// Sent with `Content-Length`. Only this many bytes are read from the
+    /// body.
+    Known(u64),
+
+    /// Sent with `Transfer-Encoding: chunked` until the body reaches EOF
+    Unknown,
+
+    /// Sent without framing headers, the body ends when the connection is
+    /// closed
+    Close,
+}
+
+/// Writes each buffer it is given as one chunk. [Chunked::finish] must be
+/// called after the last one to end the body, and can send trailer fields
+/// whose names were announced in the `Trailer` header.
+pub(super) struct Chunked<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Chunked<W> {
+    pub(super) fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Writes the last, empty chunk, followed by the trailers
+    pub(super) fn finish(mut self, trailers: &[(&str, &str)]) -> io::Result<()> {
+        self.inner.write_all(b"0\r\n")?;
+        for (name, value) in trailers {
+            write!(self.inner, "{}: {}\r\n", name, value)?;
+        }
+        self.inner.write_all(b"\r\n")?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for Chunked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunked() {
+        let mut out = Vec::new();
+        let mut chunked = Chunked::new(&mut out);
+        for buf in [&b"hello"[..], b"", b", chunked world!"] {
+            chunked.write_all(buf).unwrap();
+        }
+        chunked.finish(&[]).unwrap();
+        assert_eq!(
+            "5\r\nhello\r\n10\r\n, chunked world!\r\n0\r\n\r\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_trailers() {
+        let mut out = Vec::new();
+        let mut chunked = Chunked::new(&mut out);
+        chunked.write_all(b"hi").unwrap();
+        chunked.finish(&[("X-One", "1"), ("X-Two", "2")]).unwrap();
+        assert_eq!(
+            "2\r\nhi\r\n0\r\nX-One: 1\r\nX-Two: 2\r\n\r\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//!
+//! Notifications about the connections the server handles, see
+//! [Server::connection_events](super::Server::connection_events)
+//!
+
+use std::{sync::mpsc::Sender, time::Duration};
+
+/// Sent when a connection is accepted and when the server is done with it.
+/// The `id` is the same in both, and counts up from 0 across all the
+/// server's listeners.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Opened {
+        id: u64,
+        peer: String,
+    },
+    Closed {
+        id: u64,
+        peer: String,
+
+        /// The requested path, if the request could be parsed
+        path: Option<String>,
+
+        /// The bytes written to the connection, headers included
+        bytes_sent: u64,
+
+        /// How long the connection was open for
+        duration: Duration,
+    },
+}
+
+/// Sends the event if there is someone listening. A receiver that was
+/// dropped only means nobody is interested anymore.
+pub(super) fn send(events: &Option<Sender<ConnectionEvent>>, event: ConnectionEvent) {
+    if let Some(events) = events {
+        let _ = events.send(event);
+    }
+}
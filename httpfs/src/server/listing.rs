@@ -0,0 +1,352 @@
+//!
+//! Sorting, filtering and pagination of directory listings, as asked for in
+//! the query of the request, e.g. `?sort=size&order=desc&filter=*.rs&page=2`
+//!
+
+use std::{fs, io, path::Path, time::SystemTime};
+
+use crate::{
+    errors::{MalformedRequestError, ServerError},
+    parse::{percent_decode, percent_encode},
+};
+
+/// Entries per page when the query doesn't say
+pub(super) const DEFAULT_PER_PAGE: usize = 1000;
+
+/// The most entries a page can have, whatever the query says
+pub(super) const MAX_PER_PAGE: usize = 10_000;
+
+/// What the entries of a listing are sorted by
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(super) enum SortBy {
+    /// The order the filesystem returns them in
+    #[default]
+    Unsorted,
+    Name,
+
+    /// Directories count as empty
+    Size,
+    Modified,
+}
+
+/// The query of a directory `GET`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct ListingQuery {
+    /// `sort=name|size|mtime`
+    pub(super) sort: SortBy,
+
+    /// `order=asc|desc`. Sorts by name if there is no `sort`.
+    pub(super) desc: bool,
+
+    /// `filter=GLOB`, where `*` matches any characters and `?` matches one.
+    /// Directories are listed whether they match or not, so that they can
+    /// still be navigated to.
+    pub(super) filter: Option<String>,
+
+    /// `page=N`, counting from 1
+    pub(super) page: usize,
+
+    /// `per_page=M`, up to [MAX_PER_PAGE]
+    pub(super) per_page: usize,
+}
+
+impl Default for ListingQuery {
+    fn default() -> Self {
+        Self {
+            sort: SortBy::default(),
+            desc: false,
+            filter: None,
+            page: 1,
+            per_page: DEFAULT_PER_PAGE,
+        }
+    }
+}
+
+impl ListingQuery {
+    /// Parses the query of a request. Parameters it doesn't know are ignored.
+    pub(super) fn parse(query: Option<&str>) -> Result<Self, ServerError> {
+        let invalid = |key: &str, value: &str| {
+            ServerError::bad_request(MalformedRequestError(Some(format!(
+                "invalid listing parameter '{}={}'",
+                key, value
+            ))))
+        };
+
+        let mut listing = Self::default();
+        let params = query
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='));
+        for (key, value) in params {
+            match key {
+                "sort" => {
+                    listing.sort = match value {
+                        "name" => SortBy::Name,
+                        "size" => SortBy::Size,
+                        "mtime" => SortBy::Modified,
+                        _ => return Err(invalid(key, value)),
+                    }
+                }
+                "order" => {
+                    listing.desc = match value {
+                        "asc" => false,
+                        "desc" => true,
+                        _ => return Err(invalid(key, value)),
+                    }
+                }
+                "filter" => listing.filter = Some(percent_decode(value)?).filter(|f| !f.is_empty()),
+                "page" => {
+                    listing.page = value
+                        .parse()
+                        .ok()
+                        .filter(|page| *page > 0)
+                        .ok_or_else(|| invalid(key, value))?
+                }
+                "per_page" => {
+                    listing.per_page = value
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| invalid(key, value))?
+                        .min(MAX_PER_PAGE)
+                }
+                _ => {}
+            }
+        }
+        if listing.desc && listing.sort == SortBy::Unsorted {
+            listing.sort = SortBy::Name;
+        }
+        Ok(listing)
+    }
+
+    /// The query for `page` of the same listing
+    pub(super) fn for_page(&self, page: usize) -> String {
+        let sort = match self.sort {
+            SortBy::Unsorted => None,
+            SortBy::Name => Some("name"),
+            SortBy::Size => Some("size"),
+            SortBy::Modified => Some("mtime"),
+        };
+        let mut query = Vec::new();
+        query.extend(sort.map(|sort| format!("sort={}", sort)));
+        query.extend(self.desc.then(|| String::from("order=desc")));
+        query.extend(
+            self.filter
+                .as_deref()
+                .map(|filter| format!("filter={}", percent_encode(filter))),
+        );
+        query.push(format!("page={}", page));
+        query.push(format!("per_page={}", self.per_page));
+        format!("?{}", query.join("&"))
+    }
+}
+
+/// One page of a listing
+#[derive(Debug)]
+pub(super) struct Page {
+    pub(super) entries: Vec<Entry>,
+
+    /// The queries for the pages before and after it, if there are any
+    pub(super) prev: Option<String>,
+    pub(super) next: Option<String>,
+}
+
+impl Page {
+    /// The value of a `Link` header pointing to the pages before and after
+    /// this one (RFC 8288)
+    pub(super) fn link_header(&self) -> Option<String> {
+        let links = [("prev", &self.prev), ("next", &self.next)]
+            .into_iter()
+            .filter_map(|(rel, query)| Some(format!("<{}>; rel=\"{}\"", query.as_ref()?, rel)))
+            .collect::<Vec<_>>();
+        (!links.is_empty()).then(|| links.join(", "))
+    }
+}
+
+/// A file or a directory in a listing
+#[derive(Debug)]
+pub(super) struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Entry {
+    /// The name, with a `/` after it for directories
+    pub(super) fn link(&self) -> String {
+        match self.is_dir {
+            true => format!("{}/", self.name),
+            false => self.name.clone(),
+        }
+    }
+}
+
+/// Reads the page of the entries of `dir` that `query` asks for, leaving out
+/// symlinks. Unsorted listings only keep the entries up to the end of the
+/// page. Sorted ones have to read all of them, but their metadata is only
+/// read when they are sorted by it.
+pub(super) fn read(dir: &Path, query: &ListingQuery) -> io::Result<Page> {
+    let needs_meta = matches!(query.sort, SortBy::Size | SortBy::Modified);
+    let skip = (query.page - 1).saturating_mul(query.per_page);
+    let entries = fs::read_dir(dir)?
+        .flatten()
+        .filter_map(|entry| {
+            let file_type = entry.file_type().ok().filter(|t| !t.is_symlink())?;
+            let meta = match needs_meta {
+                true => entry.metadata().ok(),
+                false => None,
+            };
+            Some(Entry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                is_dir: file_type.is_dir(),
+                size: meta
+                    .as_ref()
+                    .filter(|meta| meta.is_file())
+                    .map_or(0, |meta| meta.len()),
+                modified: meta.and_then(|meta| meta.modified().ok()),
+            })
+        })
+        .filter(|entry| {
+            entry.is_dir
+                || query
+                    .filter
+                    .as_deref()
+                    .is_none_or(|pattern| glob_match(pattern, &entry.name))
+        });
+    let mut entries = match query.sort {
+        // One more, to know if there is a next page
+        SortBy::Unsorted => entries.skip(skip).take(query.per_page + 1).collect(),
+        _ => sorted(entries.collect(), query)
+            .into_iter()
+            .skip(skip)
+            .take(query.per_page + 1)
+            .collect::<Vec<_>>(),
+    };
+
+    let next = entries.len() > query.per_page;
+    entries.truncate(query.per_page);
+    Ok(Page {
+        entries,
+        prev: (query.page > 1).then(|| query.for_page(query.page - 1)),
+        next: next.then(|| query.for_page(query.page + 1)),
+    })
+}
+
+fn sorted(mut entries: Vec<Entry>, query: &ListingQuery) -> Vec<Entry> {
+    match query.sort {
+        SortBy::Unsorted => {}
+        SortBy::Name => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+        SortBy::Size => entries.sort_by(|a, b| a.size.cmp(&b.size).then(a.name.cmp(&b.name))),
+        SortBy::Modified => {
+            entries.sort_by(|a, b| a.modified.cmp(&b.modified).then(a.name.cmp(&b.name)))
+        }
+    }
+    if query.desc {
+        entries.reverse();
+    }
+    entries
+}
+
+/// Whether `name` matches `pattern`, in which `*` matches any characters and
+/// `?` matches one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (
+        pattern.chars().collect::<Vec<_>>(),
+        name.chars().collect::<Vec<_>>(),
+    );
+    let (mut p, mut n) = (0, 0);
+
+    // Where to go back to when what follows the last `*` stops matching
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        for (pattern, name, want) in [
+            ("*.rs", "main.rs", true),
+            ("*.rs", "main.rs.bak", false),
+            ("*.rs", ".rs", true),
+            ("main.?s", "main.rs", true),
+            ("main.?s", "main.s", false),
+            ("*a*b*", "xxaxxbxx", true),
+            ("*a*b", "xxaxxbxxc", false),
+            ("**", "", true),
+            ("", "a", false),
+            ("caf\u{E9}*", "caf\u{E9}.txt", true),
+        ] {
+            assert_eq!(want, glob_match(pattern, name), "{} {}", pattern, name);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(ListingQuery::default(), ListingQuery::parse(None).unwrap());
+        let query =
+            ListingQuery::parse(Some("sort=size&order=desc&filter=*%201.rs&x=y&page=3")).unwrap();
+        assert_eq!(
+            ListingQuery {
+                sort: SortBy::Size,
+                desc: true,
+                filter: Some(String::from("* 1.rs")),
+                page: 3,
+                per_page: DEFAULT_PER_PAGE,
+            },
+            query
+        );
+        assert_eq!(
+            "?sort=size&order=desc&filter=%2A%201.rs&page=2&per_page=1000",
+            query.for_page(2)
+        );
+        assert_eq!(
+            query,
+            ListingQuery::parse(Some(&query.for_page(3)[1..])).unwrap()
+        );
+        assert_eq!(
+            MAX_PER_PAGE,
+            ListingQuery::parse(Some("per_page=1000000"))
+                .unwrap()
+                .per_page
+        );
+        assert_eq!(
+            SortBy::Name,
+            ListingQuery::parse(Some("order=desc")).unwrap().sort
+        );
+        for query in [
+            "sort=colour",
+            "order=sideways",
+            "filter=%zz",
+            "page=0",
+            "page=-1",
+            "per_page=0",
+            "per_page=many",
+        ] {
+            assert!(ListingQuery::parse(Some(query)).is_err(), "{}", query);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//!
+//! Content-addressed storage for uploads, see
+//! [Server::dedup](super::Server::dedup). Each distinct upload is stored once
+//! in [Server::OBJECTS_DIR](super::Server::OBJECTS_DIR), named after its
+//! SHA-256 digest, and the uploaded file is a hard link to the object.
+//!
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use ring::digest::{Context, SHA256};
+
+use crate::errors::ServerError;
+
+/// Uploads in progress are written to files with this prefix in the object
+/// directory
+const TMP_PREFIX: &str = "tmp-";
+
+#[derive(Debug)]
+pub(super) struct Objects {
+    dir: PathBuf,
+    next_tmp: AtomicU64,
+
+    /// Held while an object is added and linked, so that one upload can't
+    /// replace an object that another is linking to
+    lock: Mutex<()>,
+}
+
+impl Objects {
+    /// Opens the object store in `dir`, creating it if needed, and cleans up
+    /// after earlier runs: partial uploads are deleted, and on unix so are the
+    /// objects that no file links to any more.
+    pub(super) fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)?.flatten() {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with(TMP_PREFIX) {
+                remove(&path);
+            } else if path.is_dir() {
+                fs::read_dir(&path)?
+                    .flatten()
+                    .map(|object| object.path())
+                    .filter(|object| unlinked(object))
+                    .for_each(|object| remove(&object));
+            }
+        }
+        Ok(Self {
+            dir,
+            next_tmp: AtomicU64::new(0),
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Stores `body` and replaces the file at `path` with a link to it. The
+    /// file is replaced in one step, so readers see either the old or the new
+    /// contents.
+    pub(super) fn store(&self, path: &Path, body: &mut dyn Read) -> Result<(), ServerError> {
+        let tmp = self.tmp();
+        let stored = self
+            .write(&tmp, body)
+            .and_then(|digest| self.link(&tmp, &digest, path));
+        if tmp.exists() {
+            remove(&tmp);
+        }
+        stored
+    }
+
+    /// Writes `body` to `tmp` and returns its digest in hex
+    fn write(&self, tmp: &Path, body: &mut dyn Read) -> Result<String, ServerError> {
+        let mut writer = Hashing::new(File::create(tmp)?);
+        io::copy(body, &mut writer)?;
+        Ok(writer.finish().1)
+    }
+
+    /// Moves `tmp` into the store as the object for `digest`, unless there
+    /// already is one, then links `path` to the object
+    fn link(&self, tmp: &Path, digest: &str, path: &Path) -> Result<(), ServerError> {
+        let object = self.dir.join(&digest[..2]).join(digest);
+        let staged = self.tmp();
+
+        let _lock = self.lock.lock().unwrap();
+        if object.exists() {
+            log::debug!("Upload to {} is a copy of {}", path.display(), digest);
+        } else {
+            fs::create_dir_all(self.dir.join(&digest[..2]))?;
+            fs::rename(tmp, &object)?;
+        }
+        if let Err(e) = fs::hard_link(&object, &staged) {
+            log::warn!("Failed to link to {}, copying it instead: {}", digest, e);
+            fs::copy(&object, &staged)?;
+        }
+        let renamed = fs::rename(&staged, path);
+
+        // Renaming onto another link to the same file succeeds without doing
+        // anything, e.g. when a file is uploaded again unchanged
+        if staged.exists() {
+            remove(&staged);
+        }
+        renamed.map_err(ServerError::from)
+    }
+
+    fn tmp(&self) -> PathBuf {
+        let n = self.next_tmp.fetch_add(1, Ordering::SeqCst);
+        self.dir.join(format!("{}{}", TMP_PREFIX, n))
+    }
+}
+
+/// Computes the SHA-256 digest of what is written through it
+pub(super) struct Hashing<W: Write> {
+    inner: W,
+    ctx: Context,
+}
+
+impl<W: Write> Hashing<W> {
+    pub(super) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            ctx: Context::new(&SHA256),
+        }
+    }
+
+    /// Returns the writer and the digest in hex
+    pub(super) fn finish(self) -> (W, String) {
+        let digest = self.ctx.finish();
+        let hex = digest
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        (self.inner, hex)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.ctx.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Returns `true` if no file outside the store links to `object`
+#[cfg(unix)]
+fn unlinked(object: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    fs::symlink_metadata(object).is_ok_and(|m| m.is_file() && m.nlink() == 1)
+}
+
+/// Link counts are not available, so objects are never considered unused
+#[cfg(not(unix))]
+fn unlinked(_: &Path) -> bool {
+    false
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        log::warn!("Failed to delete {}: {}", path.display(), e);
+    }
+}
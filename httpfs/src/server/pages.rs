@@ -0,0 +1,186 @@
+//!
+//! Custom bodies for error responses, see
+//! [Server::error_pages](super::Server::error_pages)
+//!
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Formatter},
+    fs, io,
+    path::Path,
+};
+
+use crate::{html::escape, parse::MediaType, status::StatusCode};
+
+/// What an error page is rendered from
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorPage<'a> {
+    pub status: StatusCode,
+
+    /// The message that clients without the page get as plain text
+    pub message: &'a str,
+
+    /// The requested path, if the request could be parsed
+    pub path: Option<&'a str>,
+}
+
+type Render = dyn Fn(&ErrorPage<'_>) -> String + Send + Sync;
+
+/// HTML pages for error responses, by status code. They are sent to clients
+/// that accept `text/html`, e.g. browsers. Other clients, and errors with a
+/// status that has no page, get the plain text message as before.
+///
+/// ```
+/// use httpfs::server::ErrorPages;
+/// use httpfs::StatusCode;
+///
+/// let pages = ErrorPages::new().page(StatusCode::NOT_FOUND, |page| {
+///     format!("<h1>Nothing at {}</h1>", page.path.unwrap_or("this address"))
+/// });
+/// ```
+#[derive(Default)]
+pub struct ErrorPages {
+    pages: HashMap<StatusCode, Box<Render>>,
+}
+
+impl ErrorPages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renders the page for `status` with `render`. What it returns is sent
+    /// as is, so it must escape the parts of the [ErrorPage] it uses.
+    pub fn page<F>(mut self, status: StatusCode, render: F) -> Self
+    where
+        F: Fn(&ErrorPage<'_>) -> String + Send + Sync + 'static,
+    {
+        self.pages.insert(status, Box::new(render));
+        self
+    }
+
+    /// Renders the page for `status` from a template, in which `{STATUS}`,
+    /// `{REASON}`, `{MESSAGE}` and `{PATH}` are replaced with the HTML
+    /// escaped values of the error
+    pub fn template(self, status: StatusCode, template: String) -> Self {
+        self.page(status, move |page| {
+            template
+                .replace("{STATUS}", &page.status.as_u16().to_string())
+                .replace(
+                    "{REASON}",
+                    page.status.canonical_reason().unwrap_or_default(),
+                )
+                .replace("{MESSAGE}", &escape(page.message))
+                .replace("{PATH}", &escape(page.path.unwrap_or_default()))
+        })
+    }
+
+    /// Loads a [template](ErrorPages::template) from each file in `dir` that
+    /// is named after a status code, e.g. `404.html`. The files are read
+    /// once, so changes to them need a restart.
+    pub fn from_dir(dir: &Path) -> io::Result<Self> {
+        let mut pages = Self::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let status = path
+                .file_name()
+                .and_then(|name| name.to_str()?.strip_suffix(".html")?.parse().ok())
+                .and_then(StatusCode::from_u16);
+            if let Some(status) = status {
+                log::debug!("Using {} for {} responses", path.display(), status);
+                pages = pages.template(status, fs::read_to_string(&path)?);
+            }
+        }
+        Ok(pages)
+    }
+
+    pub(super) fn render(&self, page: &ErrorPage<'_>) -> Option<String> {
+        self.pages.get(&page.status).map(|render| render(page))
+    }
+}
+
+impl Debug for ErrorPages {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut statuses = self.pages.keys().collect::<Vec<_>>();
+        statuses.sort();
+        f.debug_list().entries(statuses).finish()
+    }
+}
+
+/// Whether the `Accept` header lists `text/html`, and doesn't refuse it with
+/// `q=0`
+pub(super) fn accepts_html(headers: &HashMap<String, String>) -> bool {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Accept"))
+        .flat_map(|(_, value)| value.split(','))
+        .filter_map(|range| range.parse::<MediaType>().ok())
+        .any(|range| {
+            range.is("text/html")
+                && !range
+                    .param("q")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template() {
+        let pages = ErrorPages::new().template(
+            StatusCode::NOT_FOUND,
+            String::from("<h1>{STATUS} {REASON}</h1><p>{PATH}: {MESSAGE}</p>"),
+        );
+        let page = ErrorPage {
+            status: StatusCode::NOT_FOUND,
+            message: "no <such> file",
+            path: Some("/a&b"),
+        };
+        assert_eq!(
+            Some("<h1>404 Not Found</h1><p>/a&amp;b: no &lt;such&gt; file</p>"),
+            pages.render(&page).as_deref()
+        );
+        let page = ErrorPage {
+            status: StatusCode::FORBIDDEN,
+            ..page
+        };
+        assert_eq!(None, pages.render(&page));
+    }
+
+    #[test]
+    fn test_from_dir() {
+        let dir = std::env::temp_dir().join(format!("httpfs-pages-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("404.html"), "missing {PATH}").unwrap();
+        fs::write(dir.join("style.css"), "h1 {}").unwrap();
+        fs::write(dir.join("42.html"), "not a status").unwrap();
+
+        let pages = ErrorPages::from_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!("[StatusCode(404)]", format!("{:?}", pages));
+        let page = ErrorPage {
+            status: StatusCode::NOT_FOUND,
+            message: "",
+            path: Some("/a.txt"),
+        };
+        assert_eq!(Some("missing /a.txt"), pages.render(&page).as_deref());
+    }
+
+    #[test]
+    fn test_accepts_html() {
+        for (accept, want) in [
+            ("text/html", true),
+            ("text/html,application/xhtml+xml,*/*;q=0.8", true),
+            ("application/json, TEXT/HTML; q=0.5", true),
+            ("text/html;q=0", false),
+            ("*/*", false),
+            ("text/plain", false),
+        ] {
+            let headers = HashMap::from([(String::from("accept"), String::from(accept))]);
+            assert_eq!(want, accepts_html(&headers), "{}", accept);
+        }
+        assert!(!accepts_html(&HashMap::new()));
+    }
+}
@@ -0,0 +1,258 @@
+//!
+//! Forwarding requests to another HTTP server, see
+//! [Server::proxies](super::Server::proxies). The request body is streamed to
+//! the upstream server as it arrives, and the response is streamed back to
+//! the client byte for byte.
+//!
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, ErrorKind, Read, Take, Write},
+    net::{SocketAddr, TcpStream},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    errors::ServerError,
+    parse::{percent_encode, Method, Proto, Request},
+};
+
+/// Headers that only apply to one connection, which are not forwarded
+const HOP_BY_HOP: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Forwards the requests for the paths under `prefix` to `upstream`, with the
+/// prefix removed, e.g. `/api/users` is forwarded as `/users` for the prefix
+/// `/api`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Proxy {
+    pub prefix: String,
+    pub upstream: SocketAddr,
+}
+
+impl Proxy {
+    /// Returns the path to request from upstream if `path` is under the
+    /// prefix
+    fn strip<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let prefix = self.prefix.trim_end_matches('/');
+        match path.strip_prefix(prefix)? {
+            "" => Some("/"),
+            rest if rest.starts_with('/') => Some(rest),
+            _ => None,
+        }
+    }
+
+    /// Sends the request to the upstream server, streaming its body, and
+    /// returns the connection to read the response from. The request keeps
+    /// its HTTP version, so the response is one the client can read.
+    ///
+    /// Connecting, and each read from and write to the upstream server, time
+    /// out after `timeout`.
+    ///
+    /// The body is sent with a `Content-Length` of its own, so bodies that are
+    /// chunked, or whose length isn't given, are refused with `411 Length
+    /// Required`.
+    pub(super) fn send<R: Read>(
+        &self,
+        req: &mut Request<Take<R>>,
+        peer: &str,
+        request_id: &str,
+        timeout: Duration,
+    ) -> Result<TcpStream, ServerError> {
+        if req.header("Transfer-Encoding").is_some() {
+            return Err(ServerError::length_required(
+                "chunked request bodies can't be proxied, send a Content-Length",
+            ));
+        }
+        let post = matches!(req.method, Method::POST);
+        if post && req.header("Content-Length").is_none() {
+            return Err(ServerError::length_required(
+                "uploads must have a Content-Length to be proxied",
+            ));
+        }
+
+        let path = self.strip(&req.file).unwrap_or("/");
+        let mut upstream =
+            TcpStream::connect_timeout(&self.upstream, timeout).map_err(|e| self.unreachable(e))?;
+        upstream
+            .set_read_timeout(Some(timeout))
+            .and_then(|_| upstream.set_write_timeout(Some(timeout)))
+            .map_err(|e| self.unreachable(e))?;
+        log::debug!("Forwarding to http://{}{}", self.upstream, path);
+
+        let mut head = format!(
+            "{} {} {}\r\nHost: {}\r\nConnection: close\r\n",
+            match req.method {
+                Method::POST => "POST",
+                _ => "GET",
+            },
+            match &req.query {
+                Some(query) => format!("{}?{}", percent_encode(path), query),
+                None => percent_encode(path),
+            },
+            match req.proto {
+                Proto::HTTP1_0 => "HTTP/1.0",
+                _ => "HTTP/1.1",
+            },
+            self.upstream
+        );
+        let mut forwarded_for = None;
+        for (name, value) in &req.headers {
+            if name.eq_ignore_ascii_case("X-Forwarded-For") {
+                forwarded_for = Some(value.as_str());
+            } else if name.eq_ignore_ascii_case("Host") {
+                head += &format!("X-Forwarded-Host: {}\r\n", value);
+            } else if !name.eq_ignore_ascii_case(super::Server::REQUEST_ID_HEADER)
+                && !name.eq_ignore_ascii_case("Content-Length")
+                && !HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h))
+            {
+                head += &format!("{}: {}\r\n", name, value);
+            }
+        }
+        if let Ok(peer) = peer.parse::<SocketAddr>() {
+            head += &match forwarded_for {
+                Some(earlier) => format!("X-Forwarded-For: {}, {}\r\n", earlier, peer.ip()),
+                None => format!("X-Forwarded-For: {}\r\n", peer.ip()),
+            };
+        }
+        // The length the body is read with, instead of the client's header
+        let len = req.body.limit();
+        if post || len > 0 {
+            head += &format!("Content-Length: {}\r\n", len);
+        }
+        head += &format!(
+            "{}: {}\r\n\r\n",
+            super::Server::REQUEST_ID_HEADER,
+            request_id
+        );
+
+        upstream
+            .write_all(head.as_bytes())
+            .map_err(|e| self.unreachable(e))?;
+
+        // Copied by hand to tell the client's errors from upstream's
+        let mut buf = [0; 8 << 10];
+        loop {
+            let n = match req.body.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ServerError::transport(e)),
+            };
+            upstream
+                .write_all(&buf[..n])
+                .map_err(|e| self.unreachable(e))?;
+        }
+        upstream.flush().map_err(|e| self.unreachable(e))?;
+        Ok(upstream)
+    }
+
+    /// Streams the upstream response to the client until upstream closes the
+    /// connection. Upstream failing before the response has started is
+    /// reported to the client, after that the connection is just closed.
+    pub(super) fn relay(
+        &self,
+        upstream: &mut TcpStream,
+        client: &mut impl Write,
+    ) -> Result<(), ServerError> {
+        let mut buf = [0; 8 << 10];
+        let mut started = false;
+        loop {
+            let n = match upstream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if !started => return Err(self.unreachable(e)),
+                Err(e) => return Err(ServerError::transport(e)),
+            };
+            client
+                .write_all(&buf[..n])
+                .map_err(ServerError::transport)?;
+            started = true;
+        }
+        client.flush().map_err(ServerError::transport)
+    }
+
+    /// `504 Gateway Timeout` for timeouts, `502 Bad Gateway` for the rest
+    fn unreachable(&self, err: io::Error) -> ServerError {
+        let msg = format!("upstream {} failed: {}", self.upstream, err);
+        match err.kind() {
+            // Timed out reads and writes fail with WouldBlock on unix
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => ServerError::gateway_timeout(&msg),
+            _ => ServerError::bad_gateway(&msg),
+        }
+    }
+}
+
+/// Returns the proxy for the path, if there is one
+pub(super) fn find<'a>(proxies: &'a [Proxy], path: &str) -> Option<&'a Proxy> {
+    proxies.iter().find(|proxy| proxy.strip(path).is_some())
+}
+
+impl FromStr for Proxy {
+    type Err = String;
+
+    /// Parses `PREFIX=HOST:PORT`, e.g. `/api=127.0.0.1:9000`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, upstream) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=ADDR, got '{}'", s))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("proxy prefix '{}' must start with '/'", prefix));
+        }
+        Ok(Self {
+            prefix: String::from(prefix),
+            upstream: upstream
+                .parse()
+                .map_err(|e| format!("invalid upstream address '{}': {}", upstream, e))?,
+        })
+    }
+}
+
+impl Display for Proxy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.prefix, self.upstream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip() {
+        let proxy = "/api/=127.0.0.1:9000".parse::<Proxy>().unwrap();
+        for (path, want) in [
+            ("/api", Some("/")),
+            ("/api/", Some("/")),
+            ("/api/users/1", Some("/users/1")),
+            ("/apis", None),
+            ("/", None),
+        ] {
+            assert_eq!(want, proxy.strip(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Ok(Proxy {
+                prefix: String::from("/api"),
+                upstream: "127.0.0.1:9000".parse().unwrap(),
+            }),
+            "/api=127.0.0.1:9000".parse()
+        );
+        for bad in ["/api", "api=127.0.0.1:9000", "/api=localhost"] {
+            assert!(bad.parse::<Proxy>().is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//!
+//! Handlers for requests that are not about files, see
+//! [Server::router](super::Server::router). Routes are matched in the order
+//! they were added. Requests that match no route are served from the
+//! directory, as if there was no router.
+//!
+//! ```no_run
+//! use httpfs::server::{Response, Router, Server};
+//! use httpfs::StatusCode;
+//!
+//! let router = Router::new()
+//!     .get("/health", |_| Ok(Response::text(StatusCode::OK, "ok\n")))
+//!     .get("/users/{id}", |req| {
+//!         Ok(Response::text(StatusCode::OK, format!("user {}\n", req.params["id"])))
+//!     });
+//! let _handle = Server { router, ..Default::default() }.serve().unwrap();
+//! ```
+//!
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Formatter},
+    io::{self, Cursor, Read, Write},
+};
+
+use crate::{
+    errors::ServerError,
+    parse::{self, MediaType, Method},
+    status::StatusCode,
+    transport::Stream,
+};
+
+type Handler = dyn Fn(&mut RouteRequest<'_>) -> Result<Response, ServerError> + Send + Sync;
+pub(super) type Upgrade = dyn FnOnce(&mut Upgraded<'_>) -> io::Result<()>;
+
+/// What a route handler is given of the request
+pub struct RouteRequest<'a> {
+    pub method: Method,
+    pub path: &'a str,
+    pub headers: &'a HashMap<String, String>,
+
+    /// The values captured by the `{name}` and `{*name}` parts of the route
+    pub params: HashMap<String, String>,
+
+    /// Reads at most `Content-Length` bytes
+    pub body: &'a mut dyn Read,
+}
+
+impl RouteRequest<'_> {
+    /// The parsed `Content-Type` header, if there is a valid one
+    pub fn content_type(&self) -> Option<MediaType> {
+        parse::content_type(self.headers)
+    }
+}
+
+/// A response from a route handler. It is sent with a `Content-Length`, and
+/// the headers the server adds to every response, unless it takes over the
+/// connection with [Response::upgrade].
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub(super) upgrade: Option<Box<Upgrade>>,
+}
+
+impl Response {
+    /// A response without a body
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            upgrade: None,
+        }
+    }
+
+    /// A `text/plain` response
+    pub fn text(status: StatusCode, body: impl Into<String>) -> Self {
+        Self {
+            body: body.into().into_bytes(),
+            ..Self::new(status)
+        }
+        .header("Content-Type", "text/plain")
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// The parsed `Content-Type` header, if there is a valid one
+    pub fn content_type(&self) -> Option<MediaType> {
+        parse::content_type(self.headers.iter().map(|(name, value)| (name, value)))
+    }
+
+    /// Hands the connection to `f` once the head and the body are written.
+    /// The connection is closed when `f` returns.
+    ///
+    /// If the request asked to switch protocols, with `Connection: Upgrade`
+    /// and an `Upgrade` header, the status is replaced by
+    /// `101 Switching Protocols` and the `Upgrade` header is the response's,
+    /// or else the request's. Otherwise the response is sent without a
+    /// `Content-Length`, so `f` can keep writing to its body.
+    ///
+    /// ```no_run
+    /// use std::io::{Read, Write};
+    /// use httpfs::server::{Response, Router};
+    /// use httpfs::StatusCode;
+    ///
+    /// let echo = Router::new().get("/echo", |_| {
+    ///     Ok(Response::new(StatusCode::OK).upgrade(|conn| {
+    ///         let mut buf = [0; 1024];
+    ///         loop {
+    ///             match conn.read(&mut buf)? {
+    ///                 0 => return Ok(()),
+    ///                 n => conn.write_all(&buf[..n])?,
+    ///             }
+    ///         }
+    ///     }))
+    /// });
+    /// ```
+    pub fn upgrade<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut Upgraded<'_>) -> io::Result<()> + 'static,
+    {
+        self.upgrade = Some(Box::new(f));
+        self
+    }
+}
+
+impl Debug for Response {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("body", &self.body.len())
+            .field("upgrade", &self.upgrade.is_some())
+            .finish()
+    }
+}
+
+/// The connection of a [Response::upgrade]. Reading returns the bytes the
+/// client sent after the request, including those that the server had
+/// already buffered while parsing it.
+pub struct Upgraded<'a> {
+    buffered: Cursor<Vec<u8>>,
+    stream: &'a mut dyn Stream,
+}
+
+impl<'a> Upgraded<'a> {
+    pub(super) fn new(stream: &'a mut dyn Stream, buffered: Vec<u8>) -> Self {
+        Self {
+            buffered: Cursor::new(buffered),
+            stream,
+        }
+    }
+
+    /// Describes the remote end of the connection
+    pub fn peer(&self) -> String {
+        self.stream.peer()
+    }
+}
+
+impl Read for Upgraded<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.buffered.read(buf)? {
+            0 => self.stream.read(buf),
+            n => Ok(n),
+        }
+    }
+}
+
+impl Write for Upgraded<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Maps methods and paths to handlers. A path pattern is made of segments
+/// separated by `/`, each of which is one of
+///
+/// - text, which matches itself
+/// - `{name}`, which matches any one segment and captures it as `name`
+/// - `{*name}`, which matches the rest of the path, slashes included, and
+///   captures it as `name`. It can only be the last segment.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+struct Route {
+    method: Method,
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Box<Handler>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Param(String),
+    Rest(String),
+}
+
+/// The outcome of looking up a request in a [Router]
+pub(super) enum Routed<'a> {
+    Found(&'a Handler, HashMap<String, String>),
+
+    /// The path matches a route, but not for this method. Holds the methods
+    /// that the path has routes for.
+    WrongMethod(Vec<Method>),
+    NotFound,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route for `GET` requests
+    ///
+    /// # Panics
+    ///
+    /// If the pattern is invalid, e.g. `{*name}` is not its last segment.
+    pub fn get<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&mut RouteRequest<'_>) -> Result<Response, ServerError> + Send + Sync + 'static,
+    {
+        self.route(Method::GET, pattern, handler)
+    }
+
+    /// Adds a route for `POST` requests
+    ///
+    /// # Panics
+    ///
+    /// If the pattern is invalid, e.g. `{*name}` is not its last segment.
+    pub fn post<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&mut RouteRequest<'_>) -> Result<Response, ServerError> + Send + Sync + 'static,
+    {
+        self.route(Method::POST, pattern, handler)
+    }
+
+    fn route<F>(mut self, method: Method, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&mut RouteRequest<'_>) -> Result<Response, ServerError> + Send + Sync + 'static,
+    {
+        let segments = parse_pattern(pattern).unwrap_or_else(|e| panic!("{}", e));
+        self.routes.push(Route {
+            method,
+            pattern: String::from(pattern),
+            segments,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub(super) fn find(&self, method: Method, path: &str) -> Routed<'_> {
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            if let Some(params) = captures(&route.segments, path) {
+                if route.method == method {
+                    return Routed::Found(route.handler.as_ref(), params);
+                }
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method);
+                }
+            }
+        }
+        match allowed.is_empty() {
+            true => Routed::NotFound,
+            false => Routed::WrongMethod(allowed),
+        }
+    }
+}
+
+impl Debug for Router {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.routes
+                    .iter()
+                    .map(|r| format!("{:?} {}", r.method, r.pattern)),
+            )
+            .finish()
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Result<Vec<Segment>, String> {
+    let invalid = |msg: &str| format!("invalid route '{}': {}", pattern, msg);
+    let parts = pattern
+        .strip_prefix('/')
+        .ok_or_else(|| invalid("must start with '/'"))?
+        .split('/')
+        .collect::<Vec<_>>();
+
+    let mut segments = Vec::with_capacity(parts.len());
+    for (i, part) in parts.iter().enumerate() {
+        let segment = match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+            Some(name) => match name.strip_prefix('*') {
+                Some(_) if i + 1 < parts.len() => return Err(invalid("'{*..}' must be last")),
+                Some(name) => Segment::Rest(String::from(name)),
+                None => Segment::Param(String::from(name)),
+            },
+            None if part.contains(['{', '}']) => {
+                return Err(invalid("a capture must be a whole segment"))
+            }
+            None => Segment::Text(String::from(*part)),
+        };
+        if let Segment::Param(name) | Segment::Rest(name) = &segment {
+            if name.is_empty() {
+                return Err(invalid("captures need a name"));
+            }
+        }
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+/// Returns the captures if `path` matches the pattern
+fn captures(segments: &[Segment], path: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut rest = path.strip_prefix('/')?;
+    for (i, segment) in segments.iter().enumerate() {
+        let last = i + 1 == segments.len();
+        if let Segment::Rest(name) = segment {
+            params.insert(name.clone(), String::from(rest));
+            return Some(params);
+        }
+        let (part, tail) = match rest.split_once('/') {
+            Some((part, tail)) if !last => (part, tail),
+            Some(_) => return None,
+            None if last => (rest, ""),
+            None => return None,
+        };
+        match segment {
+            Segment::Text(text) if text == part => {}
+            Segment::Param(name) if !part.is_empty() => {
+                params.insert(name.clone(), String::from(part));
+            }
+            _ => return None,
+        }
+        rest = tail;
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(_: &mut RouteRequest<'_>) -> Result<Response, ServerError> {
+        Ok(Response::new(StatusCode::NO_CONTENT))
+    }
+
+    #[test]
+    fn test_response_content_type() {
+        let res = Response::text(StatusCode::OK, "hi");
+        assert!(res.content_type().unwrap().is("text/plain"));
+        let res = Response::new(StatusCode::OK).header("content-type", "text/csv; charset=utf-16");
+        assert_eq!(Some("utf-16"), res.content_type().unwrap().charset());
+        assert_eq!(None, Response::new(StatusCode::OK).content_type());
+    }
+
+    #[test]
+    fn test_captures() {
+        let params = |pairs: &[(&str, &str)]| {
+            Some(
+                pairs
+                    .iter()
+                    .map(|(k, v)| (String::from(*k), String::from(*v)))
+                    .collect::<HashMap<_, _>>(),
+            )
+        };
+        for (pattern, path, want) in [
+            ("/", "/", params(&[])),
+            ("/", "/a", None),
+            ("/health", "/health", params(&[])),
+            ("/health", "/health/", None),
+            ("/health", "/healthy", None),
+            (
+                "/files/{name}",
+                "/files/a.txt",
+                params(&[("name", "a.txt")]),
+            ),
+            ("/files/{name}", "/files/", None),
+            ("/files/{name}", "/files/a/b", None),
+            (
+                "/u/{user}/files/{name}",
+                "/u/me/files/a.txt",
+                params(&[("user", "me"), ("name", "a.txt")]),
+            ),
+            (
+                "/static/{*path}",
+                "/static/a/b.css",
+                params(&[("path", "a/b.css")]),
+            ),
+            ("/static/{*path}", "/static/", params(&[("path", "")])),
+            ("/static/{*path}", "/static", None),
+        ] {
+            let segments = parse_pattern(pattern).unwrap();
+            assert_eq!(want, captures(&segments, path), "{} {}", pattern, path);
+        }
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        for pattern in ["health", "/{*rest}/more", "/a{b}", "/{}", "/{*}"] {
+            assert!(parse_pattern(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let router = Router::new()
+            .get("/items/{id}", ok)
+            .post("/items/{id}", ok)
+            .post("/upload", ok);
+
+        assert!(matches!(
+            router.find(Method::POST, "/items/7"),
+            Routed::Found(_, params) if params["id"] == "7"
+        ));
+        assert!(matches!(
+            router.find(Method::GET, "/upload"),
+            Routed::WrongMethod(allowed) if allowed == [Method::POST]
+        ));
+        assert!(matches!(
+            router.find(Method::GET, "/other"),
+            Routed::NotFound
+        ));
+    }
+}
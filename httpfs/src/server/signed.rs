@@ -0,0 +1,206 @@
+//!
+//! Time-limited links to protected paths, see
+//! [Server::signed_urls](super::Server::signed_urls). A link carries its
+//! expiry and an HMAC-SHA256 over the path and the expiry in its query, e.g.
+//! `/private/report.pdf?expires=1790380799&sig=9f86d0...`.
+//!
+//! Paths are compared after `.` and `..` segments are resolved, the way the
+//! files are looked up, and regardless of case, since on case-insensitive
+//! filesystems `/PRIVATE/report.pdf` is the same file. Symlinks are not
+//! resolved, so a link from outside a protected prefix to a file inside it
+//! makes the file public.
+//!
+
+use std::{
+    fmt::{self, Debug, Formatter},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ring::hmac;
+
+use crate::{errors::ServerError, parse::percent_encode};
+
+/// Protects the paths under `prefixes`: requests for them are only served
+/// with a valid, unexpired signature, which [SignedUrls::sign] adds to a
+/// path.
+#[derive(Clone)]
+pub struct SignedUrls {
+    key: hmac::Key,
+    pub prefixes: Vec<String>,
+}
+
+impl SignedUrls {
+    pub fn new(secret: &[u8], prefixes: Vec<String>) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            prefixes,
+        }
+    }
+
+    /// Returns the path with the query that grants access to it until
+    /// `expires`
+    pub fn sign(&self, path: &str, expires: SystemTime) -> String {
+        let expires = expires
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        format!(
+            "{}?expires={}&sig={}",
+            percent_encode(path),
+            expires,
+            hex(hmac::sign(&self.key, message(path, expires).as_bytes()).as_ref())
+        )
+    }
+
+    /// Whether requests for the (decoded) path need a signature
+    pub(super) fn protects(&self, path: &str) -> bool {
+        let path = segments(path);
+        self.prefixes.iter().any(|prefix| {
+            let prefix = segments(prefix);
+            prefix.len() <= path.len()
+                && prefix
+                    .iter()
+                    .zip(&path)
+                    .all(|(a, b)| a.to_lowercase() == b.to_lowercase())
+        })
+    }
+
+    /// Checks the signature in the query of a request for `path`
+    pub(super) fn verify(
+        &self,
+        path: &str,
+        query: Option<&str>,
+        now: SystemTime,
+    ) -> Result<(), ServerError> {
+        let param = |name: &str| {
+            query?
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value)
+        };
+        let (expires, sig) = match (param("expires"), param("sig")) {
+            (Some(expires), Some(sig)) => (expires, sig),
+            _ => return Err(ServerError::forbidden("this path needs a signed link")),
+        };
+        let expires = expires
+            .parse::<u64>()
+            .map_err(|_| ServerError::forbidden("invalid link expiry"))?;
+        let sig = unhex(sig).ok_or_else(|| ServerError::forbidden("invalid link signature"))?;
+
+        hmac::verify(&self.key, message(path, expires).as_bytes(), &sig)
+            .map_err(|_| ServerError::forbidden("invalid link signature"))?;
+        if UNIX_EPOCH + Duration::from_secs(expires) < now {
+            return Err(ServerError::forbidden("the link has expired"));
+        }
+        Ok(())
+    }
+}
+
+impl Debug for SignedUrls {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignedUrls")
+            .field("prefixes", &self.prefixes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What is signed: the normalized path and the expiry
+fn message(path: &str, expires: u64) -> String {
+    format!("/{}\n{}", segments(path).join("/"), expires)
+}
+
+/// The segments of a path, with `.` and `..` resolved. `..` never goes
+/// above the root.
+fn segments(path: &str) -> Vec<&str> {
+    let mut segments = Vec::new();
+    for segment in path.split(['/', '\\']) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    segments
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix would also take a sign, as in `+A`
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protects() {
+        let signed = SignedUrls::new(b"secret", vec![String::from("/private/")]);
+        for (path, want) in [
+            ("/private", true),
+            ("/private/a.txt", true),
+            ("/privateer.txt", false),
+            ("/a.txt", false),
+            ("/public/../private/a.txt", true),
+            ("/./private//a.txt", true),
+            ("/private/../a.txt", false),
+            ("/PRIVATE/a.txt", true),
+            ("/Private/a.txt", true),
+        ] {
+            assert_eq!(want, signed.protects(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signed = SignedUrls::new(b"secret", vec![String::from("/private")]);
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let url = signed.sign("/private/a b.txt", now + Duration::from_secs(60));
+        let (path, query) = url.split_once('?').unwrap();
+        assert_eq!("/private/a%20b.txt", path);
+
+        let verify = |path, query, now| signed.verify(path, query, now).is_ok();
+        assert!(verify("/private/a b.txt", Some(query), now));
+        assert!(verify(
+            "/private/a b.txt",
+            Some(query),
+            now + Duration::from_secs(60)
+        ));
+        assert!(!verify(
+            "/private/a b.txt",
+            Some(query),
+            now + Duration::from_secs(61)
+        ));
+        assert!(verify("/private/./a b.txt", Some(query), now));
+        assert!(!verify("/private/other.txt", Some(query), now));
+        assert!(!verify("/private/a b.txt", None, now));
+
+        // Tampering with the expiry, or signing with another secret
+        let later = query.replace("expires=1000060", "expires=2000000");
+        assert!(!verify("/private/a b.txt", Some(&later), now));
+        let other = SignedUrls::new(b"other", Vec::new()).sign("/private/a b.txt", now);
+        let other = other.split_once('?').unwrap().1;
+        assert!(!verify("/private/a b.txt", Some(other), now));
+        assert!(!verify("/private/a b.txt", Some("expires=1&sig=zz"), now));
+    }
+
+    #[test]
+    fn test_unhex() {
+        assert_eq!(Some(vec![0x0a, 0xff]), unhex("0aFF"));
+        for hex in ["0", "zz", "+A", "-1", "\u{e9}0"] {
+            assert_eq!(None, unhex(hex), "{}", hex);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//!
+//! Where the connections are handled, see
+//! [Server::spawner](super::Server::spawner)
+//!
+
+#[cfg(feature = "rayon")]
+use std::sync::{Arc, Condvar, Mutex};
+
+use threadpool::ThreadPool;
+
+/// Runs the connection handlers. The server hands each accepted connection to
+/// [Spawner::spawn], and calls [Spawner::join] once it stops accepting them.
+///
+/// By default this is a [ThreadPool] with [Server::n_workers](super::Server::n_workers)
+/// threads. A work-stealing pool suits servers whose handlers split up CPU
+/// heavy work, see `Rayon` with the `rayon` feature.
+pub trait Spawner: Send + Sync {
+    /// Runs the job, usually on another thread
+    fn spawn(&self, job: Job);
+
+    /// Waits until every job spawned so far has finished
+    fn join(&self);
+}
+
+/// The work done for one connection
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+impl Spawner for ThreadPool {
+    fn spawn(&self, job: Job) {
+        self.execute(job)
+    }
+
+    fn join(&self) {
+        ThreadPool::join(self)
+    }
+}
+
+/// Runs the jobs on a [rayon::ThreadPool]. The pool has no way to wait for its
+/// jobs, so they are counted until they finish.
+///
+/// ```
+/// # use std::sync::Arc;
+/// let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+/// let srv = httpfs::Server {
+///     spawner: Some(Arc::new(httpfs::server::Rayon::new(pool))),
+///     ..Default::default()
+/// };
+/// ```
+#[cfg(feature = "rayon")]
+pub struct Rayon {
+    pool: rayon::ThreadPool,
+    pending: Arc<(Mutex<usize>, Condvar)>,
+}
+
+#[cfg(feature = "rayon")]
+impl Rayon {
+    pub fn new(pool: rayon::ThreadPool) -> Self {
+        Self {
+            pool,
+            pending: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    /// The number of jobs that have been spawned and have not finished yet
+    pub fn pending(&self) -> usize {
+        *self.pending.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl Spawner for Rayon {
+    fn spawn(&self, job: Job) {
+        /// Counts the job as finished when dropped, even if it panicked
+        struct Finished(Arc<(Mutex<usize>, Condvar)>);
+
+        impl Drop for Finished {
+            fn drop(&mut self) {
+                let (pending, done) = &*self.0;
+                *pending.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+                done.notify_all();
+            }
+        }
+
+        *self.pending.0.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        let finished = Finished(self.pending.clone());
+        self.pool.spawn(move || {
+            let _finished = finished;
+            job()
+        });
+    }
+
+    fn join(&self) {
+        let (pending, done) = &*self.pending;
+        let pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+        drop(done.wait_while(pending, |n| *n > 0));
+    }
+}
@@ -0,0 +1,186 @@
+//!
+//! Request bodies that are read in full before they are handled, without
+//! holding large ones in memory, see [SpooledBody]
+//!
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Numbers the spool files of this process
+static NEXT_SPOOL: AtomicU64 = AtomicU64::new(0);
+
+/// A whole request body, kept in memory up to a threshold and in a temporary
+/// file beyond it. The file is deleted when the body is dropped, on unix as
+/// soon as it is created, so it does not outlive a crash either.
+///
+/// It can be read any number of times, e.g. to verify a checksum before the
+/// body is committed:
+///
+/// ```no_run
+/// use std::io::{self, Seek, SeekFrom};
+/// use httpfs::server::{Response, Router, SpooledBody};
+/// use httpfs::StatusCode;
+///
+/// let router = Router::new().post("/upload", |req| {
+///     let mut body = SpooledBody::new(req.body)?;
+///     let words = io::read_to_string(&mut body)?.split_whitespace().count();
+///     body.seek(SeekFrom::Start(0))?;
+///     // ... store the body
+///     Ok(Response::text(StatusCode::CREATED, format!("{} words\n", words)))
+/// });
+/// ```
+#[derive(Debug)]
+pub struct SpooledBody {
+    inner: Spool,
+    len: u64,
+}
+
+#[derive(Debug)]
+enum Spool {
+    Memory(Cursor<Vec<u8>>),
+    File(File, PathBuf),
+}
+
+impl SpooledBody {
+    /// Bodies up to this many bytes are kept in memory by [SpooledBody::new]
+    pub const DEFAULT_THRESHOLD: usize = 1 << 20; // 1MB
+
+    /// Reads all of `body`, spilling it to the system's temporary directory
+    /// if it is larger than [SpooledBody::DEFAULT_THRESHOLD]
+    pub fn new(body: &mut dyn Read) -> io::Result<Self> {
+        Self::with_threshold(body, Self::DEFAULT_THRESHOLD, &std::env::temp_dir())
+    }
+
+    /// Reads all of `body`, spilling it to a file in `dir` if it is larger
+    /// than `threshold` bytes
+    pub fn with_threshold(body: &mut dyn Read, threshold: usize, dir: &Path) -> io::Result<Self> {
+        let mut memory = Vec::new();
+        body.take(threshold as u64 + 1).read_to_end(&mut memory)?;
+        if memory.len() <= threshold {
+            return Ok(Self {
+                len: memory.len() as u64,
+                inner: Spool::Memory(Cursor::new(memory)),
+            });
+        }
+
+        // Built first so that the file is deleted if writing to it fails
+        let (file, path) = spool_file(dir)?;
+        let mut spooled = Self {
+            len: 0,
+            inner: Spool::File(file, path),
+        };
+        if let Spool::File(file, path) = &mut spooled.inner {
+            file.write_all(&memory)?;
+            spooled.len = memory.len() as u64 + io::copy(body, file)?;
+            file.seek(SeekFrom::Start(0))?;
+            log::debug!(
+                "Spooled a body of {} bytes to {}",
+                spooled.len,
+                path.display()
+            );
+        }
+        Ok(spooled)
+    }
+
+    /// The length of the body in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the body went over the threshold and is in a file
+    pub fn is_spilled(&self) -> bool {
+        matches!(self.inner, Spool::File(..))
+    }
+}
+
+/// Creates a new file in `dir`, which is deleted right away where open files
+/// can be
+fn spool_file(dir: &Path) -> io::Result<(File, PathBuf)> {
+    let path = dir.join(format!(
+        "httpfs-spool-{}-{}",
+        std::process::id(),
+        NEXT_SPOOL.fetch_add(1, Ordering::SeqCst)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    #[cfg(unix)]
+    fs::remove_file(&path)?;
+    Ok((file, path))
+}
+
+impl Read for SpooledBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            Spool::Memory(cursor) => cursor.read(buf),
+            Spool::File(file, _) => file.read(buf),
+        }
+    }
+}
+
+impl Seek for SpooledBody {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match &mut self.inner {
+            Spool::Memory(cursor) => cursor.seek(pos),
+            Spool::File(file, _) => file.seek(pos),
+        }
+    }
+}
+
+impl Drop for SpooledBody {
+    fn drop(&mut self) {
+        if let Spool::File(_, path) = &self.inner {
+            if path.exists() {
+                if let Err(e) = fs::remove_file(path) {
+                    log::warn!("Failed to delete {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spooled_body() {
+        let dir = std::env::temp_dir();
+        for (len, spilled) in [
+            (0, false),
+            (10, false),
+            (64, false),
+            (65, true),
+            (10000, true),
+        ] {
+            let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let mut body = SpooledBody::with_threshold(&mut data.as_slice(), 64, &dir).unwrap();
+            assert_eq!((len as u64, spilled), (body.len(), body.is_spilled()));
+
+            // It can be read again after a seek
+            for _ in 0..2 {
+                let mut got = Vec::new();
+                body.read_to_end(&mut got).unwrap();
+                assert_eq!(data, got, "{}", len);
+                body.seek(SeekFrom::Start(0)).unwrap();
+            }
+
+            let path = match &body.inner {
+                Spool::File(_, path) => Some(path.clone()),
+                Spool::Memory(_) => None,
+            };
+            drop(body);
+            assert!(!path.is_some_and(|path| path.exists()), "{}", len);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//!
+//! Bytes sent and requests served, by client and by path, see
+//! [Server::usage_report](super::Server::usage_report) and
+//! [Server::ADMIN_USAGE_PATH](super::Server::ADMIN_USAGE_PATH)
+//!
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::transport::{SocketOptions, Stream};
+
+/// Each table keeps at most this many keys, the rest are added up under
+/// [OTHER], so that clients can't grow them without bounds
+const MAX_KEYS: usize = 10_000;
+const OTHER: &str = "(other)";
+
+/// How many clients and paths the periodic report lists
+const REPORT_TOP: usize = 10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Tally {
+    requests: u64,
+    bytes: u64,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Usage {
+    tables: Mutex<Tables>,
+}
+
+#[derive(Debug, Default)]
+struct Tables {
+    clients: HashMap<String, Tally>,
+    paths: HashMap<String, Tally>,
+}
+
+impl Usage {
+    /// Counts a request from `peer`, and the bytes sent back to it. Clients
+    /// are told apart by IP address, not port. Requests that could not be
+    /// parsed have no path.
+    pub(super) fn record(&self, peer: &str, path: Option<&str>, bytes: u64) {
+        let client = match peer.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip().to_string(),
+            Err(_) => String::from(peer),
+        };
+        let mut tables = self.tables.lock().unwrap();
+        add(&mut tables.clients, client, bytes);
+        if let Some(path) = path {
+            add(&mut tables.paths, String::from(path), bytes);
+        }
+    }
+
+    /// Lists the `top` clients and paths that were sent the most bytes, or
+    /// all of them
+    pub(super) fn report(&self, top: Option<usize>) -> String {
+        let tables = self.tables.lock().unwrap();
+        let mut out = String::new();
+        for (title, table) in [("clients", &tables.clients), ("paths", &tables.paths)] {
+            let mut rows = table.iter().collect::<Vec<_>>();
+            rows.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(b.0)));
+            let _ = writeln!(out, "{}:", title);
+            for (key, tally) in rows.iter().take(top.unwrap_or(usize::MAX)) {
+                let _ = writeln!(
+                    out,
+                    "  {} requests={} bytes={}",
+                    key, tally.requests, tally.bytes
+                );
+            }
+        }
+        out
+    }
+
+    #[cfg(test)]
+    fn tally(&self, client: &str, path: &str) -> (Tally, Tally) {
+        let tables = self.tables.lock().unwrap();
+        (
+            tables.clients.get(client).copied().unwrap_or_default(),
+            tables.paths.get(path).copied().unwrap_or_default(),
+        )
+    }
+}
+
+fn add(table: &mut HashMap<String, Tally>, key: String, bytes: u64) {
+    let key = match table.contains_key(&key) || table.len() < MAX_KEYS {
+        true => key,
+        false => String::from(OTHER),
+    };
+    let tally = table.entry(key).or_default();
+    tally.requests += 1;
+    tally.bytes += bytes;
+}
+
+/// Logs the top clients and paths every `interval` until `exit` is set
+pub(super) fn report_every(usage: Arc<Usage>, interval: Duration, exit: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let tick = Duration::from_millis(50);
+        loop {
+            let next = Instant::now() + interval;
+            while Instant::now() < next {
+                if exit.load(Ordering::SeqCst) {
+                    return;
+                }
+                thread::sleep(tick);
+            }
+            log::info!("Usage so far\n{}", usage.report(Some(REPORT_TOP)));
+        }
+    });
+}
+
+/// Counts the bytes written to the stream
+pub(super) struct Counting<S: Stream> {
+    inner: S,
+    written: u64,
+}
+
+impl<S: Stream> Counting<S> {
+    pub(super) fn new(inner: S) -> Self {
+        Self { inner, written: 0 }
+    }
+
+    pub(super) fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl<S: Stream> Read for Counting<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Stream> Write for Counting<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Stream> Stream for Counting<S> {
+    fn peer(&self) -> String {
+        self.inner.peer()
+    }
+
+    fn configure(&self, opts: &SocketOptions) -> io::Result<()> {
+        self.inner.configure(opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let usage = Usage::default();
+        usage.record("10.0.0.1:5000", Some("/a.txt"), 100);
+        usage.record("10.0.0.1:5001", Some("/a.txt"), 50);
+        usage.record("10.0.0.2:5000", None, 10);
+
+        let tally = |requests, bytes| Tally { requests, bytes };
+        assert_eq!(
+            (tally(2, 150), tally(2, 150)),
+            usage.tally("10.0.0.1", "/a.txt")
+        );
+        assert_eq!(tally(1, 10), usage.tally("10.0.0.2", "").0);
+        assert_eq!(
+            "clients:\n  10.0.0.1 requests=2 bytes=150\npaths:\n  /a.txt requests=2 bytes=150\n",
+            usage.report(Some(1))
+        );
+    }
+
+    #[test]
+    fn test_tables_are_bounded() {
+        let usage = Usage::default();
+        for i in 0..MAX_KEYS + 5 {
+            usage.record("10.0.0.1:5000", Some(&format!("/{}", i)), 1);
+        }
+        let tables = usage.tables.lock().unwrap();
+        assert_eq!(MAX_KEYS + 1, tables.paths.len());
+        assert_eq!(5, tables.paths[OTHER].requests);
+    }
+}
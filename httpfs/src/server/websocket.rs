@@ -0,0 +1,651 @@
+//!
+//! WebSockets (RFC 6455) on top of [Response::upgrade]. A route accepts the
+//! handshake with [accept], and talks to the client through a [WebSocket]:
+//!
+//! ```no_run
+//! use httpfs::server::{websocket::{self, Message}, Router};
+//!
+//! let echo = Router::new().get("/ws", |req| {
+//!     websocket::accept(req, |ws| loop {
+//!         match ws.recv()? {
+//!             msg @ (Message::Text(_) | Message::Binary(_)) => ws.send(&msg)?,
+//!             Message::Close(_) => return Ok(()),
+//!             _ => {}
+//!         }
+//!     })
+//! });
+//! ```
+//!
+
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io::{self, ErrorKind, Read, Write},
+};
+
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use super::{Response, RouteRequest, Upgraded};
+use crate::{
+    errors::{ServerError, WebSocketHandshakeError},
+    status::StatusCode,
+};
+
+/// Appended to the `Sec-WebSocket-Key` to compute the `Sec-WebSocket-Accept`
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The largest message [WebSocket::recv] accepts, fragments included
+pub const MAX_MESSAGE_SIZE: usize = 16 << 20; // 16MB
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Close codes (RFC 6455, section 7.4.1)
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const TOO_BIG: u16 = 1009;
+}
+
+/// A complete message. Fragmented messages are put back together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+
+    /// The close code and the reason, if the peer gave one
+    Close(Option<(u16, String)>),
+}
+
+/// One frame of a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Whether this is the last frame of the message
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Reads a frame, unmasking its payload. Fails if the payload is longer
+    /// than `max`.
+    pub fn read_from(r: &mut impl Read, max: usize) -> io::Result<(Self, bool)> {
+        let mut head = [0; 2];
+        r.read_exact(&mut head)?;
+        if head[0] & 0x70 != 0 {
+            return Err(protocol_error("reserved bits are set"));
+        }
+        let (fin, opcode) = (head[0] & 0x80 != 0, head[0] & 0x0F);
+        let masked = head[1] & 0x80 != 0;
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                r.read_exact(&mut len)?;
+                u64::from(u16::from_be_bytes(len))
+            }
+            127 => {
+                let mut len = [0; 8];
+                r.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => u64::from(len),
+        };
+        if opcode >= CLOSE && (!fin || len > 125) {
+            return Err(protocol_error(
+                "control frames must be whole and at most 125 bytes",
+            ));
+        }
+        if len > max as u64 {
+            return Err(ProtocolError::io(close_code::TOO_BIG, "message is too big"));
+        }
+
+        let mut mask = [0; 4];
+        if masked {
+            r.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0; len as usize];
+        r.read_exact(&mut payload)?;
+        apply_mask(&mut payload, mask);
+        Ok((
+            Self {
+                fin,
+                opcode,
+                payload,
+            },
+            masked,
+        ))
+    }
+
+    /// Writes the frame, masking its payload with `mask` if there is one.
+    /// Clients must mask the frames they send, servers must not.
+    pub fn write_to(&self, w: &mut impl Write, mask: Option<[u8; 4]>) -> io::Result<()> {
+        let mut out = Vec::with_capacity(self.payload.len() + 14);
+        out.push(u8::from(self.fin) << 7 | self.opcode);
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match self.payload.len() {
+            len @ 0..=125 => out.push(mask_bit | len as u8),
+            len @ 126..=0xFFFF => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let start = out.len();
+        if let Some(mask) = mask {
+            out.extend_from_slice(&mask);
+        }
+        out.extend_from_slice(&self.payload);
+        if let Some(mask) = mask {
+            apply_mask(&mut out[start + 4..], mask);
+        }
+        w.write_all(&out)?;
+        w.flush()
+    }
+}
+
+/// Which end of the connection a [WebSocket] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Server,
+    Client,
+}
+
+/// Sends and receives [Messages](Message) over a stream on which the
+/// handshake is done
+pub struct WebSocket<S: Read + Write> {
+    stream: S,
+    role: Role,
+    closed: bool,
+
+    /// The opcode and the payload so far of a fragmented message. Control
+    /// frames can come between its fragments.
+    partial: Option<(u8, Vec<u8>)>,
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    /// The server end, which expects masked frames and sends them unmasked
+    pub fn server(stream: S) -> Self {
+        Self {
+            stream,
+            role: Role::Server,
+            closed: false,
+            partial: None,
+        }
+    }
+
+    /// The client end, which masks the frames it sends
+    pub fn client(stream: S) -> Self {
+        Self {
+            stream,
+            role: Role::Client,
+            closed: false,
+            partial: None,
+        }
+    }
+
+    /// Reads the next message. Pings are answered before they are returned,
+    /// and a close is echoed back, after which the connection should be
+    /// dropped.
+    ///
+    /// A [ProtocolError] closes the connection with its close code before it
+    /// is returned.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        let res = self.read_message();
+        if let Err(e) = &res {
+            let code = e.get_ref().and_then(|e| e.downcast_ref::<ProtocolError>());
+            if let (Some(err), false) = (code, self.closed) {
+                let _ = self.close(err.code, "");
+            }
+        }
+        res
+    }
+
+    fn read_message(&mut self) -> io::Result<Message> {
+        loop {
+            let left = MAX_MESSAGE_SIZE - self.partial.as_ref().map_or(0, |m| m.1.len());
+            let (frame, masked) = Frame::read_from(&mut self.stream, left)?;
+            if masked != (self.role == Role::Server) {
+                return Err(protocol_error(
+                    "frames from clients must be masked, and only them",
+                ));
+            }
+
+            let (opcode, payload) = match (frame.opcode, &mut self.partial) {
+                (PING, _) => {
+                    self.write_frame(PONG, frame.payload.clone())?;
+                    return Ok(Message::Ping(frame.payload));
+                }
+                (PONG, _) => return Ok(Message::Pong(frame.payload)),
+                (CLOSE, _) => return self.closing(frame.payload),
+                (TEXT | BINARY, None) if !frame.fin => {
+                    self.partial = Some((frame.opcode, frame.payload));
+                    continue;
+                }
+                (TEXT | BINARY, None) => (frame.opcode, frame.payload),
+                (CONTINUATION, Some((_, payload))) => {
+                    payload.extend_from_slice(&frame.payload);
+                    if !frame.fin {
+                        continue;
+                    }
+                    self.partial.take().unwrap()
+                }
+                (CONTINUATION, None) => return Err(protocol_error("continuation of nothing")),
+                (TEXT | BINARY, Some(_)) => {
+                    return Err(protocol_error("new message in a fragmented one"))
+                }
+                (opcode, _) => {
+                    return Err(protocol_error(&format!("unknown opcode {:#x}", opcode)))
+                }
+            };
+            return match opcode {
+                TEXT => String::from_utf8(payload)
+                    .map(Message::Text)
+                    .map_err(|_| not_utf8()),
+                _ => Ok(Message::Binary(payload)),
+            };
+        }
+    }
+
+    /// Answers a close frame, unless it answers ours
+    fn closing(&mut self, payload: Vec<u8>) -> io::Result<Message> {
+        let reason = match payload.len() {
+            0 => None,
+            1 => return Err(protocol_error("close payload of one byte")),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                let reason = String::from_utf8(payload[2..].to_vec()).map_err(|_| not_utf8())?;
+                Some((code, reason))
+            }
+        };
+        if !self.closed {
+            self.closed = true;
+            let code = reason
+                .as_ref()
+                .map_or(Vec::new(), |r| r.0.to_be_bytes().to_vec());
+            self.write_frame(CLOSE, code)?;
+        }
+        Ok(Message::Close(reason))
+    }
+
+    /// Sends a message in a single frame
+    pub fn send(&mut self, msg: &Message) -> io::Result<()> {
+        match msg {
+            Message::Text(text) => self.write_frame(TEXT, text.as_bytes().to_vec()),
+            Message::Binary(data) => self.write_frame(BINARY, data.clone()),
+            Message::Ping(data) => self.write_frame(PING, data.clone()),
+            Message::Pong(data) => self.write_frame(PONG, data.clone()),
+            Message::Close(Some((code, reason))) => self.close(*code, reason),
+            Message::Close(None) => {
+                self.closed = true;
+                self.write_frame(CLOSE, Vec::new())
+            }
+        }
+    }
+
+    /// Starts the closing handshake. The peer answers with a
+    /// [Message::Close], which [WebSocket::recv] returns.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        self.closed = true;
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        payload.truncate(125);
+        self.write_frame(CLOSE, payload)
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: Vec<u8>) -> io::Result<()> {
+        if opcode >= CLOSE && payload.len() > 125 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "control frames are at most 125 bytes",
+            ));
+        }
+        let mask = match self.role {
+            Role::Server => None,
+            Role::Client => Some(random_mask()?),
+        };
+        Frame {
+            fin: true,
+            opcode,
+            payload,
+        }
+        .write_to(&mut self.stream, mask)
+    }
+
+    /// Returns the underlying stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// Accepts the WebSocket handshake of the request, and hands the connection
+/// to `f` once the `101 Switching Protocols` is sent. Requests that are not
+/// WebSocket handshakes get a `400 Bad Request`, or a `426 Upgrade Required`
+/// if they are for another version of the protocol.
+pub fn accept<F>(req: &RouteRequest<'_>, f: F) -> Result<Response, ServerError>
+where
+    F: FnOnce(&mut WebSocket<&mut Upgraded<'_>>) -> io::Result<()> + 'static,
+{
+    let header = |wanted: &str| {
+        req.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.trim())
+    };
+    let has_token = |name: &str, token: &str| {
+        header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    let invalid =
+        |msg: &str| ServerError::bad_request(WebSocketHandshakeError(Some(String::from(msg))));
+    if !has_token("Connection", "upgrade") || !has_token("Upgrade", "websocket") {
+        return Err(invalid(
+            "expected 'Connection: Upgrade' and 'Upgrade: websocket'",
+        ));
+    }
+    if header("Sec-WebSocket-Version") != Some("13") {
+        return Ok(Response::text(
+            StatusCode::UPGRADE_REQUIRED,
+            "only version 13 is supported\n",
+        )
+        .header("Sec-WebSocket-Version", "13"));
+    }
+    let key = match header("Sec-WebSocket-Key") {
+        Some(key) if !key.is_empty() => key,
+        _ => return Err(invalid("missing 'Sec-WebSocket-Key'")),
+    };
+
+    Ok(Response::new(StatusCode::SWITCHING_PROTOCOLS)
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Accept", &accept_key(key))
+        .upgrade(move |conn| f(&mut WebSocket::server(conn))))
+}
+
+/// The `Sec-WebSocket-Accept` for a `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    let hash = digest::digest(
+        &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, GUID).as_bytes(),
+    );
+    base64(hash.as_ref())
+}
+
+/// A random `Sec-WebSocket-Key` for a client handshake
+pub fn client_key() -> io::Result<String> {
+    let mut key = [0; 16];
+    SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| io::Error::other("failed to generate a random key"))?;
+    Ok(base64(&key))
+}
+
+fn random_mask() -> io::Result<[u8; 4]> {
+    let mut mask = [0; 4];
+    SystemRandom::new()
+        .fill(&mut mask)
+        .map_err(|_| io::Error::other("failed to generate a random mask"))?;
+    Ok(mask)
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+/// Standard base64 with padding, which the handshake headers use
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(char::from(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize])),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+/// A violation of the protocol by the peer. It is returned as the inner
+/// error of an [ErrorKind::InvalidData] [io::Error].
+#[derive(Debug)]
+pub struct ProtocolError {
+    /// The close code the connection is closed with
+    pub code: u16,
+    msg: String,
+}
+
+impl ProtocolError {
+    fn io(code: u16, msg: &str) -> io::Error {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            Self {
+                code,
+                msg: String::from(msg),
+            },
+        )
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "WebSocket protocol error {}: {}", self.code, self.msg)
+    }
+}
+
+impl Error for ProtocolError {}
+
+fn protocol_error(msg: &str) -> io::Error {
+    ProtocolError::io(close_code::PROTOCOL_ERROR, msg)
+}
+
+fn not_utf8() -> io::Error {
+    ProtocolError::io(close_code::INVALID_DATA, "text is not valid UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A stream that reads `input` and collects what is written
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Pipe {
+        fn new(input: Vec<u8>) -> Self {
+            Self {
+                input: Cursor::new(input),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        Frame {
+            fin,
+            opcode,
+            payload: payload.to_vec(),
+        }
+        .write_to(&mut out, Some([1, 2, 3, 4]))
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // The example from RFC 6455, section 1.3
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept_key("dGhlIHNhbXBsZSBub25jZQ==")
+        );
+    }
+
+    #[test]
+    fn test_base64() {
+        for (bytes, want) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(want, base64(bytes.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        for len in [0, 5, 125, 126, 0xFFFF, 0x10000] {
+            for mask in [None, Some([0xA, 0xB, 0xC, 0xD])] {
+                let want = Frame {
+                    fin: len % 2 == 0,
+                    opcode: BINARY,
+                    payload: (0..len).map(|i| i as u8).collect(),
+                };
+                let mut out = Vec::new();
+                want.write_to(&mut out, mask).unwrap();
+                let (got, masked) = Frame::read_from(&mut out.as_slice(), usize::MAX).unwrap();
+                assert_eq!(want, got, "{} {:?}", len, mask);
+                assert_eq!(mask.is_some(), masked);
+            }
+        }
+
+        // The unmasked and masked "Hello" from RFC 6455, section 5.7
+        let mut out = Vec::new();
+        Frame {
+            fin: true,
+            opcode: TEXT,
+            payload: b"Hello".to_vec(),
+        }
+        .write_to(&mut out, Some([0x37, 0xfa, 0x21, 0x3d]))
+        .unwrap();
+        assert_eq!(
+            [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58],
+            out.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_recv() {
+        let input = [
+            frame(false, TEXT, b"Hel"),
+            frame(true, PING, b"?"),
+            frame(true, CONTINUATION, b"lo"),
+            frame(true, BINARY, &[1, 2]),
+            frame(true, CLOSE, &[0x03, 0xE8, b'b', b'y', b'e']),
+        ]
+        .concat();
+        let mut ws = WebSocket::server(Pipe::new(input));
+        assert_eq!(Message::Ping(b"?".to_vec()), ws.recv().unwrap());
+        assert_eq!(Message::Text(String::from("Hello")), ws.recv().unwrap());
+        assert_eq!(Message::Binary(vec![1, 2]), ws.recv().unwrap());
+        assert_eq!(
+            Message::Close(Some((close_code::NORMAL, String::from("bye")))),
+            ws.recv().unwrap()
+        );
+
+        // The ping is answered, and the close echoed, unmasked
+        let output = ws.into_inner().output;
+        let mut written = output.as_slice();
+        let mut next = || Frame::read_from(&mut written, 125).unwrap();
+        let (pong, masked) = next();
+        assert_eq!(
+            (PONG, b"?".to_vec(), false),
+            (pong.opcode, pong.payload, masked)
+        );
+        let (close, _) = next();
+        assert_eq!((CLOSE, vec![0x03, 0xE8]), (close.opcode, close.payload));
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        let mut unmasked = Vec::new();
+        Frame {
+            fin: true,
+            opcode: TEXT,
+            payload: b"hi".to_vec(),
+        }
+        .write_to(&mut unmasked, None)
+        .unwrap();
+
+        for (input, code) in [
+            (unmasked, close_code::PROTOCOL_ERROR),
+            (frame(true, CONTINUATION, b"?"), close_code::PROTOCOL_ERROR),
+            (frame(false, PING, b"?"), close_code::PROTOCOL_ERROR),
+            (frame(true, PING, &[0; 126]), close_code::PROTOCOL_ERROR),
+            (frame(true, 0x3, b"?"), close_code::PROTOCOL_ERROR),
+            (frame(true, TEXT, &[0xFF]), close_code::INVALID_DATA),
+            (
+                frame(true, BINARY, &vec![0; MAX_MESSAGE_SIZE + 1]),
+                close_code::TOO_BIG,
+            ),
+        ] {
+            let mut ws = WebSocket::server(Pipe::new(input));
+            let err = ws.recv().unwrap_err();
+            assert_eq!(ErrorKind::InvalidData, err.kind());
+
+            let output = ws.into_inner().output;
+            let (close, _) = Frame::read_from(&mut output.as_slice(), 125).unwrap();
+            assert_eq!(
+                (CLOSE, code.to_be_bytes().to_vec()),
+                (close.opcode, close.payload),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_client_masks() {
+        let mut ws = WebSocket::client(Pipe::new(Vec::new()));
+        ws.send(&Message::Text(String::from("hi"))).unwrap();
+        let output = ws.into_inner().output;
+        let (frame, masked) = Frame::read_from(&mut output.as_slice(), 125).unwrap();
+        assert!(masked);
+        assert_eq!(b"hi".to_vec(), frame.payload);
+    }
+}
@@ -0,0 +1,141 @@
+//!
+//! HTTP status codes. A [StatusCode] is a number between 100 and 999, and
+//! displays as the code followed by its canonical reason phrase, the way it
+//! appears in a status line, e.g. `404 Not Found`.
+//!
+
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StatusCode(u16);
+
+impl StatusCode {
+    pub const CONTINUE: Self = Self(100);
+    pub const SWITCHING_PROTOCOLS: Self = Self(101);
+    pub const OK: Self = Self(200);
+    pub const CREATED: Self = Self(201);
+    pub const NO_CONTENT: Self = Self(204);
+    pub const PARTIAL_CONTENT: Self = Self(206);
+    pub const MOVED_PERMANENTLY: Self = Self(301);
+    pub const FOUND: Self = Self(302);
+    pub const NOT_MODIFIED: Self = Self(304);
+    pub const BAD_REQUEST: Self = Self(400);
+    pub const UNAUTHORIZED: Self = Self(401);
+    pub const FORBIDDEN: Self = Self(403);
+    pub const NOT_FOUND: Self = Self(404);
+    pub const METHOD_NOT_ALLOWED: Self = Self(405);
+    pub const CONFLICT: Self = Self(409);
+    pub const LENGTH_REQUIRED: Self = Self(411);
+    pub const PAYLOAD_TOO_LARGE: Self = Self(413);
+    pub const URI_TOO_LONG: Self = Self(414);
+    pub const RANGE_NOT_SATISFIABLE: Self = Self(416);
+    pub const UPGRADE_REQUIRED: Self = Self(426);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: Self = Self(431);
+    pub const INTERNAL_SERVER_ERROR: Self = Self(500);
+    pub const NOT_IMPLEMENTED: Self = Self(501);
+    pub const BAD_GATEWAY: Self = Self(502);
+    pub const SERVICE_UNAVAILABLE: Self = Self(503);
+    pub const GATEWAY_TIMEOUT: Self = Self(504);
+    pub const HTTP_VERSION_NOT_SUPPORTED: Self = Self(505);
+    pub const INSUFFICIENT_STORAGE: Self = Self(507);
+
+    /// Returns [None] for numbers that are not three digits
+    pub fn from_u16(code: u16) -> Option<Self> {
+        (100..1000).contains(&code).then_some(Self(code))
+    }
+
+    pub fn as_u16(self) -> u16 {
+        self.0
+    }
+
+    /// The reason phrase for the codes in this module, [None] for others
+    pub fn canonical_reason(self) -> Option<&'static str> {
+        Some(match self.0 {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            304 => "Not Modified",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            411 => "Length Required",
+            413 => "Payload Too Large",
+            414 => "URI Too Long",
+            416 => "Range Not Satisfiable",
+            426 => "Upgrade Required",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            505 => "HTTP Version Not Supported",
+            507 => "Insufficient Storage",
+            _ => return None,
+        })
+    }
+
+    pub fn is_informational(self) -> bool {
+        (100..200).contains(&self.0)
+    }
+
+    pub fn is_success(self) -> bool {
+        (200..300).contains(&self.0)
+    }
+
+    pub fn is_redirection(self) -> bool {
+        (300..400).contains(&self.0)
+    }
+
+    pub fn is_client_error(self) -> bool {
+        (400..500).contains(&self.0)
+    }
+
+    pub fn is_server_error(self) -> bool {
+        (500..600).contains(&self.0)
+    }
+}
+
+impl Display for StatusCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.canonical_reason() {
+            Some(reason) => write!(f, "{} {}", self.0, reason),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!("404 Not Found", StatusCode::NOT_FOUND.to_string());
+        assert_eq!(
+            "507 Insufficient Storage",
+            StatusCode::INSUFFICIENT_STORAGE.to_string()
+        );
+        assert_eq!("599", StatusCode::from_u16(599).unwrap().to_string());
+    }
+
+    #[test]
+    fn test_classes() {
+        assert_eq!(None, StatusCode::from_u16(99));
+        assert_eq!(None, StatusCode::from_u16(1000));
+        assert!(StatusCode::CREATED.is_success());
+        assert!(StatusCode::NOT_MODIFIED.is_redirection());
+        assert!(StatusCode::CONFLICT.is_client_error());
+        assert!(!StatusCode::CONFLICT.is_server_error());
+        assert!(StatusCode::from_u16(599).unwrap().is_server_error());
+        assert!(StatusCode::SWITCHING_PROTOCOLS.is_informational());
+    }
+}
@@ -0,0 +1,475 @@
+//!
+//! The sockets the server can accept connections on. The server is written
+//! against the [Listener] and [Stream] traits, so adding a transport is a
+//! matter of implementing them (and [Bindable] for its address type).
+//!
+
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+};
+
+pub mod framed;
+#[cfg(all(test, feature = "server"))]
+pub(crate) mod slow;
+
+/// Kernel buffer sizes for a socket, in bytes. [None] keeps the system
+/// default.
+///
+/// The sizes are set with `SO_RCVBUF` and `SO_SNDBUF`, which only exist on
+/// unix. Elsewhere they are ignored. The kernel may round the sizes, e.g.
+/// Linux doubles them to leave room for its own bookkeeping.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SocketOptions {
+    pub recv_buffer: Option<usize>,
+    pub send_buffer: Option<usize>,
+}
+
+impl SocketOptions {
+    #[cfg(unix)]
+    fn apply(&self, socket: &impl std::os::unix::io::AsRawFd) -> io::Result<()> {
+        let set = |opt, size: usize| {
+            let size = libc::c_int::try_from(size).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "buffer size too large")
+            })?;
+            setsockopt(socket, libc::SOL_SOCKET, opt, size)
+        };
+        if let Some(size) = self.recv_buffer {
+            set(libc::SO_RCVBUF, size)?;
+        }
+        if let Some(size) = self.send_buffer {
+            set(libc::SO_SNDBUF, size)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn apply<T>(&self, _socket: &T) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sets a socket option that takes a c_int
+#[cfg(unix)]
+fn setsockopt(
+    socket: &impl std::os::unix::io::AsRawFd,
+    level: libc::c_int,
+    opt: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: the fd is open for as long as `socket` is borrowed, and the
+    // option value is a c_int as the options used here expect
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            opt,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// How a [Listener] is bound to its address, see [Bindable::bind]
+///
+/// The options only exist for TCP on unix, elsewhere they are ignored and
+/// [TcpListener::bind] decides. Unix domain sockets ignore them too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BindOptions {
+    /// `SO_REUSEADDR`, so that a restarted server can bind its address while
+    /// connections of the previous one are still in `TIME_WAIT`. On by
+    /// default, as with [TcpListener::bind].
+    pub reuse_addr: bool,
+
+    /// `SO_REUSEPORT`, so that several servers can listen on the same
+    /// address, and the kernel spreads the connections between them. Every
+    /// one of them must set it. Off by default.
+    pub reuse_port: bool,
+
+    /// `IPV6_V6ONLY`, so that an IPv6 listener does not take IPv4
+    /// connections too, and another listener can bind the same port on
+    /// `0.0.0.0`. Off by default, which leaves it to the system. IPv4
+    /// listeners ignore it.
+    pub v6_only: bool,
+}
+
+impl Default for BindOptions {
+    fn default() -> Self {
+        Self {
+            reuse_addr: true,
+            reuse_port: false,
+            v6_only: false,
+        }
+    }
+}
+
+/// A connection accepted by a [Listener]
+pub trait Stream: Read + Write + Send + 'static {
+    /// Describes the remote end of the connection, for logging
+    fn peer(&self) -> String;
+
+    /// Applies the socket options to the connection
+    fn configure(&self, _opts: &SocketOptions) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A bound socket that accepts [Streams](Stream)
+pub trait Listener: Send + 'static {
+    type Stream: Stream;
+
+    /// Short name of the transport, used in logs
+    const TRANSPORT: &'static str;
+
+    fn accept(&self) -> io::Result<Self::Stream>;
+
+    /// In nonblocking mode, [Listener::accept] returns
+    /// [io::ErrorKind::WouldBlock] when there is no pending connection
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    /// The address the listener is bound to. With port 0, this is where the
+    /// system picked port can be found.
+    fn bound_addr(&self) -> io::Result<BoundAddr>;
+
+    /// Applies the socket options to the listening socket. On most platforms
+    /// accepted connections inherit them, and the receive buffer must be set
+    /// before the connection is established for TCP to advertise a window
+    /// larger than the default.
+    fn configure(&self, _opts: &SocketOptions) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once the server has stopped accepting connections
+    fn close(&self) {}
+}
+
+/// The address a [Listener] is bound to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BoundAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl BoundAddr {
+    /// The port, if the transport has ports
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Self::Tcp(addr) => Some(addr.port()),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl Display for BoundAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// An address that a [Listener] can be bound to
+pub trait Bindable {
+    type Listener: Listener;
+
+    fn bind(&self, opts: &BindOptions) -> io::Result<Self::Listener>;
+}
+
+impl Stream for TcpStream {
+    fn peer(&self) -> String {
+        self.peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|_| String::from("..."))
+    }
+
+    fn configure(&self, opts: &SocketOptions) -> io::Result<()> {
+        opts.apply(self)
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+    const TRANSPORT: &'static str = "tcp";
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        // Accepted sockets inherit nonblocking mode on some platforms
+        let (stream, _) = TcpListener::accept(self)?;
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpListener::set_nonblocking(self, nonblocking)
+    }
+
+    fn bound_addr(&self) -> io::Result<BoundAddr> {
+        self.local_addr().map(BoundAddr::Tcp)
+    }
+
+    fn configure(&self, opts: &SocketOptions) -> io::Result<()> {
+        opts.apply(self)
+    }
+}
+
+impl Bindable for SocketAddr {
+    type Listener = TcpListener;
+
+    #[cfg(unix)]
+    fn bind(&self, opts: &BindOptions) -> io::Result<TcpListener> {
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        let cvt = |res: libc::c_int| match res {
+            -1 => Err(io::Error::last_os_error()),
+            res => Ok(res),
+        };
+        let domain = match self {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        // SAFETY: socket() returns a new fd that nothing else owns, the
+        // listener closes it if any of the steps below fail
+        let listener =
+            unsafe { TcpListener::from_raw_fd(cvt(libc::socket(domain, libc::SOCK_STREAM, 0))?) };
+        let fd = listener.as_raw_fd();
+        // SAFETY: fd is open, F_SETFD takes an int
+        cvt(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+        let reuse_addr = opts.reuse_addr.into();
+        setsockopt(&listener, libc::SOL_SOCKET, libc::SO_REUSEADDR, reuse_addr)?;
+        if opts.reuse_port {
+            setsockopt(&listener, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
+        if opts.v6_only && self.is_ipv6() {
+            setsockopt(&listener, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 1)?;
+        }
+
+        // SAFETY: the sockaddr structs are plain data, for which all zeroes
+        // is valid. bind() reads no more than the length it is given.
+        let res = unsafe {
+            match self {
+                SocketAddr::V4(addr) => {
+                    let mut raw: libc::sockaddr_in = std::mem::zeroed();
+                    raw.sin_family = libc::AF_INET as libc::sa_family_t;
+                    raw.sin_port = addr.port().to_be();
+                    raw.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                    libc::bind(
+                        fd,
+                        &raw as *const libc::sockaddr_in as *const libc::sockaddr,
+                        std::mem::size_of_val(&raw) as libc::socklen_t,
+                    )
+                }
+                SocketAddr::V6(addr) => {
+                    let mut raw: libc::sockaddr_in6 = std::mem::zeroed();
+                    raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    raw.sin6_port = addr.port().to_be();
+                    raw.sin6_flowinfo = addr.flowinfo();
+                    raw.sin6_addr.s6_addr = addr.ip().octets();
+                    raw.sin6_scope_id = addr.scope_id();
+                    libc::bind(
+                        fd,
+                        &raw as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                        std::mem::size_of_val(&raw) as libc::socklen_t,
+                    )
+                }
+            }
+        };
+        cvt(res)?;
+        // SAFETY: fd is an open, bound socket
+        cvt(unsafe { libc::listen(fd, libc::SOMAXCONN) })?;
+        Ok(listener)
+    }
+
+    #[cfg(not(unix))]
+    fn bind(&self, _opts: &BindOptions) -> io::Result<TcpListener> {
+        TcpListener::bind(self)
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::UnixSocket;
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        io,
+        os::unix::net::{UnixListener, UnixStream},
+        path::PathBuf,
+    };
+
+    use super::{BindOptions, Bindable, BoundAddr, Listener, SocketOptions, Stream};
+
+    /// The path of a unix domain socket. Binding fails if the file already
+    /// exists. The file is removed when the server shuts down.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct UnixSocket(pub PathBuf);
+
+    impl Stream for UnixStream {
+        fn peer(&self) -> String {
+            // Clients usually don't bind their end of the socket, so there is
+            // rarely a path to show
+            self.peer_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|p| p.display().to_string()))
+                .unwrap_or_else(|| String::from("unnamed"))
+        }
+
+        fn configure(&self, opts: &SocketOptions) -> io::Result<()> {
+            opts.apply(self)
+        }
+    }
+
+    impl Listener for UnixListener {
+        type Stream = UnixStream;
+        const TRANSPORT: &'static str = "unix";
+
+        fn accept(&self) -> io::Result<UnixStream> {
+            let (stream, _) = UnixListener::accept(self)?;
+            stream.set_nonblocking(false)?;
+            Ok(stream)
+        }
+
+        fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            UnixListener::set_nonblocking(self, nonblocking)
+        }
+
+        fn bound_addr(&self) -> io::Result<BoundAddr> {
+            let addr = self.local_addr()?;
+            addr.as_pathname()
+                .map(|path| BoundAddr::Unix(PathBuf::from(path)))
+                .ok_or_else(|| io::Error::other("socket has no path"))
+        }
+
+        fn configure(&self, opts: &SocketOptions) -> io::Result<()> {
+            opts.apply(self)
+        }
+
+        fn close(&self) {
+            if let Some(path) = self
+                .local_addr()
+                .ok()
+                .and_then(|a| a.as_pathname().map(PathBuf::from))
+            {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    log::debug!("Failed to remove socket file {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    impl Bindable for UnixSocket {
+        type Listener = UnixListener;
+
+        fn bind(&self, _opts: &BindOptions) -> io::Result<UnixListener> {
+            UnixListener::bind(&self.0)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+
+    use super::*;
+
+    fn get(socket: &impl AsRawFd, opt: libc::c_int) -> usize {
+        get_at(socket, libc::SOL_SOCKET, opt)
+    }
+
+    fn get_at(socket: &impl AsRawFd, level: libc::c_int, opt: libc::c_int) -> usize {
+        let mut size: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: the fd is open and the buffers match the option's size
+        let res = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                opt,
+                &mut size as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(0, res);
+        size as usize
+    }
+
+    #[test]
+    fn test_configure_buffer_sizes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let defaults = (
+            get(&listener, libc::SO_RCVBUF),
+            get(&listener, libc::SO_SNDBUF),
+        );
+        let size = 16 * 1024;
+        Listener::configure(
+            &listener,
+            &SocketOptions {
+                recv_buffer: Some(size),
+                send_buffer: Some(size),
+            },
+        )
+        .unwrap();
+
+        // The kernel may round the sizes up, so only check that they changed
+        // and are at least what was asked for
+        let got = (
+            get(&listener, libc::SO_RCVBUF),
+            get(&listener, libc::SO_SNDBUF),
+        );
+        assert_ne!(defaults, got);
+        assert!(got.0 >= size && got.1 >= size, "got {:?}", got);
+    }
+
+    #[test]
+    fn test_bind_options() {
+        let any = SocketAddr::from(([127, 0, 0, 1], 0));
+        let listener = any.bind(&BindOptions::default()).unwrap();
+        assert_eq!(1, get(&listener, libc::SO_REUSEADDR));
+        assert_eq!(0, get(&listener, libc::SO_REUSEPORT));
+        let addr = listener.local_addr().unwrap();
+        assert_eq!(
+            io::ErrorKind::AddrInUse,
+            addr.bind(&BindOptions::default()).unwrap_err().kind()
+        );
+        drop(listener);
+
+        // With SO_REUSEPORT on both, they share the address
+        let opts = BindOptions {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let first = any.bind(&opts).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = addr.bind(&opts).unwrap();
+        assert_eq!(addr, second.local_addr().unwrap());
+        TcpStream::connect(addr).unwrap();
+
+        let v6 = SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, 0));
+        if let Ok(listener) = v6.bind(&BindOptions::default()) {
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_v6_only() {
+        let v4 = SocketAddr::from(([0, 0, 0, 0], 0));
+        let v4 = v4.bind(&BindOptions::default()).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port));
+        let opts = BindOptions {
+            v6_only: true,
+            ..Default::default()
+        };
+        if let Ok(v6) = v6.bind(&opts) {
+            assert_eq!(1, get_at(&v6, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY));
+        }
+    }
+}
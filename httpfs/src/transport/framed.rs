@@ -0,0 +1,157 @@
+//!
+//! Length-prefixed messages on top of a byte stream. Each frame is the length
+//! of the message as a big-endian `u32`, followed by the message.
+//!
+
+use std::io::{self, Read, Write};
+
+/// Frames larger than this are rejected unless the limit is changed with
+/// [FramedStream::with_max_size]
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 << 20;
+
+const HEADER_SIZE: usize = 4;
+
+/// Sends and receives discrete messages over a [Read] + [Write] stream, e.g.
+/// one of the [Streams](super::Stream) accepted by a
+/// [Listener](super::Listener)
+#[derive(Debug)]
+pub struct FramedStream<S> {
+    inner: S,
+    max_size: usize,
+}
+
+impl<S: Read + Write> FramedStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self::with_max_size(inner, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Limits the size of the messages that can be sent or received. The
+    /// limit can't be more than [u32::MAX], the largest length a frame header
+    /// can hold.
+    pub fn with_max_size(inner: S, max_size: usize) -> Self {
+        Self {
+            inner,
+            max_size: max_size.min(u32::MAX as usize),
+        }
+    }
+
+    /// Writes `msg` as one frame and flushes the stream. Fails with
+    /// [io::ErrorKind::InvalidInput] if the message is over the size limit.
+    pub fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        if msg.len() > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "message of {} bytes is over the {} byte limit",
+                    msg.len(),
+                    self.max_size
+                ),
+            ));
+        }
+        self.inner.write_all(&(msg.len() as u32).to_be_bytes())?;
+        self.inner.write_all(msg)?;
+        self.inner.flush()
+    }
+
+    /// Reads the next message. Returns [None] if the stream ended cleanly
+    /// between frames.
+    ///
+    /// Fails with [io::ErrorKind::UnexpectedEof] if the stream ends inside a
+    /// frame, and with [io::ErrorKind::InvalidData] if the peer announces a
+    /// message over the size limit. Nothing is allocated for an oversized
+    /// message, and the stream can't be used after either error because the
+    /// frame boundaries are lost.
+    pub fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut header = [0; HEADER_SIZE];
+        let mut red = 0;
+        while red < HEADER_SIZE {
+            match self.inner.read(&mut header[red..]) {
+                Ok(0) if red == 0 => return Ok(None),
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream ended inside a frame header",
+                    ))
+                }
+                Ok(n) => red += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let len = u32::from_be_bytes(header) as usize;
+        if len > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes is over the {} byte limit",
+                    len, self.max_size
+                ),
+            ));
+        }
+        let mut msg = vec![0; len];
+        self.inner.read_exact(&mut msg)?;
+        Ok(Some(msg))
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn framed(bytes: Vec<u8>, max_size: usize) -> FramedStream<Cursor<Vec<u8>>> {
+        FramedStream::with_max_size(Cursor::new(bytes), max_size)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut stream = FramedStream::new(Cursor::new(Vec::new()));
+        for msg in [&b"hello"[..], b"", b"world"] {
+            stream.send(msg).unwrap();
+        }
+        assert_eq!(
+            b"\0\0\0\x05hello\0\0\0\0\0\0\0\x05world",
+            stream.get_ref().get_ref().as_slice()
+        );
+
+        stream.get_mut().set_position(0);
+        assert_eq!(Some(b"hello".to_vec()), stream.recv().unwrap());
+        assert_eq!(Some(Vec::new()), stream.recv().unwrap());
+        assert_eq!(Some(b"world".to_vec()), stream.recv().unwrap());
+        assert_eq!(None, stream.recv().unwrap());
+    }
+
+    #[test]
+    fn test_max_size() {
+        let mut stream = framed(Vec::new(), 4);
+        let err = stream.send(b"hello").unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert!(stream.get_ref().get_ref().is_empty());
+
+        let mut stream = framed(b"\0\0\0\x05hello".to_vec(), 4);
+        let err = stream.recv().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_truncated() {
+        for bytes in [&b"\0\0"[..], b"\0\0\0\x05hel"] {
+            let err = framed(bytes.to_vec(), 16).recv().unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind(), "{:?}", bytes);
+        }
+    }
+}
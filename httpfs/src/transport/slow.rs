@@ -0,0 +1,88 @@
+//!
+//! A [Stream] that behaves like a slow network, for tests
+//!
+
+use std::{
+    io::{self, Read, Write},
+    thread,
+    time::Duration,
+};
+
+use super::{SocketOptions, Stream};
+
+/// Wraps a stream so that every read and write waits for `delay` first, and
+/// moves at most `max_read` or `max_write` bytes. Code that assumes a read
+/// fills its buffer, or that a write takes all of it, breaks on it the way
+/// it would on a congested connection.
+pub(crate) struct SlowStream<S: Stream> {
+    inner: S,
+    delay: Duration,
+    max_read: usize,
+    max_write: usize,
+}
+
+impl<S: Stream> SlowStream<S> {
+    /// Moves one byte per call, without delay
+    pub(crate) fn new(inner: S) -> Self {
+        Self {
+            inner,
+            delay: Duration::ZERO,
+            max_read: 1,
+            max_write: 1,
+        }
+    }
+
+    pub(crate) fn delay(self, delay: Duration) -> Self {
+        Self { delay, ..self }
+    }
+
+    pub(crate) fn max_read(self, max_read: usize) -> Self {
+        Self {
+            max_read: max_read.max(1),
+            ..self
+        }
+    }
+
+    pub(crate) fn max_write(self, max_write: usize) -> Self {
+        Self {
+            max_write: max_write.max(1),
+            ..self
+        }
+    }
+
+    fn wait(&self) {
+        if !self.delay.is_zero() {
+            thread::sleep(self.delay);
+        }
+    }
+}
+
+impl<S: Stream> Read for SlowStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.wait();
+        let n = buf.len().min(self.max_read);
+        self.inner.read(&mut buf[..n])
+    }
+}
+
+impl<S: Stream> Write for SlowStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wait();
+        let n = buf.len().min(self.max_write);
+        self.inner.write(&buf[..n])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Stream> Stream for SlowStream<S> {
+    fn peer(&self) -> String {
+        self.inner.peer()
+    }
+
+    fn configure(&self, opts: &SocketOptions) -> io::Result<()> {
+        self.inner.configure(opts)
+    }
+}
@@ -0,0 +1,113 @@
+//!
+//! The request parser for JavaScript, with the `wasm` feature. The crate is a
+//! library, so the `.wasm` file is built as a cdylib explicitly:
+//!
+//! ```text
+//! cargo rustc --lib --release --crate-type cdylib --target wasm32-unknown-unknown \
+//!     --no-default-features --features wasm
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/httpfs.wasm
+//! ```
+//!
+//! ```js
+//! import init, { parseHttpRequest } from "./pkg/httpfs.js";
+//!
+//! await init();
+//! const req = parseHttpRequest(new TextEncoder().encode("GET /a.txt HTTP/1.1\r\n\r\n"));
+//! console.log(req.method, req.path);
+//! ```
+//!
+
+use std::io::Read;
+
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    bullshit_scanner::BullshitScanner,
+    parse::{self, Proto},
+};
+
+/// A request read by [parse_http_request]
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone)]
+pub struct ParsedRequest {
+    /// `GET` or `POST`
+    pub method: String,
+    pub path: String,
+
+    /// The query, without the `?`
+    pub query: Option<String>,
+
+    /// `HTTP/1.1`, `HTTP/1.0`, or the version the client sent
+    pub proto: String,
+
+    /// The bytes after the headers, up to the `Content-Length`
+    pub body: Vec<u8>,
+    headers: Vec<(String, String)>,
+}
+
+#[wasm_bindgen]
+impl ParsedRequest {
+    /// The value of the header, whatever the case of its name
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    }
+
+    /// The names of the headers, in no particular order
+    #[wasm_bindgen(js_name = headerNames)]
+    pub fn header_names(&self) -> Vec<String> {
+        self.headers.iter().map(|(key, _)| key.clone()).collect()
+    }
+}
+
+/// Parses the request in `bytes` with [parse::parse_http_request]. Throws an
+/// `Error` with the message of the [ServerError](crate::ServerError) if the
+/// request is malformed.
+#[wasm_bindgen(js_name = parseHttpRequest)]
+pub fn parse_http_request(bytes: &[u8]) -> Result<ParsedRequest, JsError> {
+    parse(bytes).map_err(|e| JsError::new(&e))
+}
+
+fn parse(mut bytes: &[u8]) -> Result<ParsedRequest, String> {
+    let mut req =
+        parse::parse_http_request(BullshitScanner::new(&mut bytes)).map_err(|e| e.to_string())?;
+    let mut body = Vec::new();
+    req.body.read_to_end(&mut body).map_err(|e| e.to_string())?;
+    Ok(ParsedRequest {
+        method: format!("{:?}", req.method),
+        path: req.file,
+        query: req.query,
+        proto: match req.proto {
+            Proto::HTTP1_1 => String::from("HTTP/1.1"),
+            Proto::HTTP1_0 => String::from("HTTP/1.0"),
+            Proto::Other(proto) => proto,
+        },
+        body,
+        headers: req.headers.into_iter().collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let req =
+            parse(b"POST /notes.txt?v=2 HTTP/1.0\r\ncontent-length: 5\r\n\r\nhello world").unwrap();
+        assert_eq!("POST", req.method);
+        assert_eq!("/notes.txt", req.path);
+        assert_eq!(Some(String::from("v=2")), req.query);
+        assert_eq!("HTTP/1.0", req.proto);
+        assert_eq!(b"hello", req.body.as_slice());
+        assert_eq!(Some(String::from("5")), req.header("Content-Length"));
+        assert_eq!(vec![String::from("content-length")], req.header_names());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(parse(b"GET\r\n\r\n").is_err());
+    }
+}
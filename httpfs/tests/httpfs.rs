@@ -1,16 +1,29 @@
-#![allow(clippy::type_complexity)]
+#![allow(clippy::type_complexity, clippy::result_large_err)]
 
 #[cfg(test)]
 pub mod test_utils;
 
 use crate::test_utils::*;
 use core::panic;
-use httpfs::bullshit_scanner::BullshitScanner;
+use httpfs::{
+    bullshit_scanner::BullshitScanner,
+    discovery::Discovery,
+    server::{
+        websocket::{self, Message, WebSocket},
+        ConnectionEvent, ErrorPages, Job, Proxy, Response, Router, Server, SignedUrls, Spawner,
+    },
+    transport::BoundAddr,
+    ServerError, StatusCode,
+};
 use std::{
-    io::Write,
-    net::TcpStream,
-    sync::{mpsc, Arc, Mutex},
+    io::{Read, Write},
+    net::{IpAddr, Ipv6Addr, Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
+    time::{Duration, Instant, SystemTime},
 };
 use test_utils::better_ureq::*;
 
@@ -25,11 +38,15 @@ fn server() -> ServerDropper {
     SERVERS.lock().unwrap().next_server()
 }
 
+fn server_with(configure: impl FnOnce(&mut Server)) -> ServerDropper {
+    SERVERS.lock().unwrap().next_server_with(configure)
+}
+
 #[test]
 fn test_simple_get() {
     let handle = server();
     let contents = "Hello world!\n";
-    let file = TempFile::new_or_panic("hello!.txt", contents);
+    let file = handle.file("hello!.txt", contents);
     let got = ureq::get(&handle.file_addr(&file.name))
         .call()
         .unwrap()
@@ -43,7 +60,7 @@ fn test_simple_get() {
 fn test_simple_post() {
     let handle = server();
     let contents = "Hello world!\n";
-    let file = TempFile::new_or_panic("hello.txt", "");
+    let file = handle.file("hello.txt", "");
     let posted = ureq::post(&handle.file_addr(&file.name))
         .send_string(contents)
         .unwrap();
@@ -61,16 +78,64 @@ fn test_simple_post() {
     assert_eq!(contents, &got);
 }
 
+/// Tests reading and writing files in subdirectories of the served directory
+#[test]
+fn test_nested_paths() {
+    let handle = server();
+    handle.dir().write("a/b/read.txt", "nested\n").unwrap();
+    assert_eq!(
+        (200, String::from("nested\n")),
+        ureq_get_errors_are_ok(&handle.file_addr("a/b/read.txt")).unwrap()
+    );
+
+    let (status, _) =
+        ureq_post_errors_are_ok(&handle.file_addr("a/b/written.txt"), "uploaded\n").unwrap();
+    assert_eq!(201, status);
+    assert_eq!(
+        Some(String::from("uploaded\n")),
+        handle.dir().read("a/b/written.txt")
+    );
+}
+
 #[test]
 fn test_not_found() {
     let handle = server();
     assertions::assert_request_returns_error(
         ureq::get(&handle.file_addr("hello.txt")),
         404,
-        Some("File '/hello.txt' could not be found on the server (directory being served is ./)\n"),
+        Some("File '/hello.txt' could not be found on the server\n"),
     );
 }
 
+/// Tests that the served directory only shows in error bodies when asked for
+#[test]
+fn test_reveal_paths() {
+    for reveal in [false, true] {
+        let handle = server_with(|srv| srv.reveal_paths = reveal);
+        let dir = handle.dir().path.to_string_lossy().into_owned();
+        let (status, body) = raw_request(&handle, "GET /nope.txt HTTP/1.1\r\n\r\n");
+        assert_eq!("404 Not Found", status);
+        assert_eq!(
+            reveal,
+            body.contains(&format!("directory being served is {}", dir)),
+            "{}",
+            body
+        );
+
+        let dir = std::fs::canonicalize(&dir).unwrap();
+        let (status, body) = raw_request(&handle, "GET /../../hello.txt HTTP/1.1\r\n\r\n");
+        assert_eq!("403 Forbidden", status);
+        assert_eq!(reveal, body.contains(&*dir.to_string_lossy()), "{}", body);
+        if !reveal {
+            assert!(
+                body.contains("File '/../../hello.txt' is located outside"),
+                "{}",
+                body
+            );
+        }
+    }
+}
+
 /// Tests that attempting to access files outside the served directory fails
 #[test]
 fn test_forbidden() {
@@ -105,12 +170,64 @@ fn test_forbidden() {
     assert!(body.contains("hello.txt' is located outside the directory that is being served"))
 }
 
+/// Tests that encoded dots and backslashes can't be used to leave the directory
+#[test]
+fn test_forbidden_encoded() {
+    let handle = server();
+    for path in [
+        "/%2e%2e/%2e%2e/hello.txt",
+        "/%2E%2E%2F%2E%2E%2Fhello.txt",
+        "/..%5c..%5chello.txt",
+        "/a/%2e%2e/%2e%2e/hello.txt",
+    ] {
+        let (status, _) = raw_request(&handle, &format!("GET {} HTTP/1.1\r\n\r\n", path));
+        assert_eq!("403 Forbidden", status, "{}", path);
+    }
+}
+
+/// Tests that a request that cannot be parsed gets a 400 instead of a 500
+#[test]
+fn test_malformed_request() {
+    let handle = server();
+    let (status, body) = raw_request(&handle, "GET\r\n\r\n");
+    assert_eq!("400 Bad Request", status);
+    assert!(
+        body.contains("no protocol found in request line"),
+        "{}",
+        body
+    );
+}
+
+/// Tests serving a file over a unix domain socket, and that the socket file is
+/// removed on shutdown
+#[cfg(unix)]
+#[test]
+fn test_unix_socket() {
+    use std::{io::Read, os::unix::net::UnixStream};
+
+    let sockets = TempDir::new_or_panic("sockets");
+    let path = sockets.join("httpfs.sock");
+    let handle = server_with(|srv| srv.unix_socket = Some(path.clone()));
+    let file = handle.file("hello.txt", "Hello world!\n");
+
+    let mut sock = UnixStream::connect(&path).unwrap();
+    sock.write_all(format!("GET /{} HTTP/1.1\r\n\r\n", file.name).as_bytes())
+        .unwrap();
+    let mut res = String::new();
+    sock.read_to_string(&mut res).unwrap();
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}", res);
+    assert!(res.ends_with("\r\n\r\nHello world!\n"), "{}", res);
+
+    drop(handle);
+    assert!(!path.exists());
+}
+
 /// Tests multiple clients reading the same file
 #[test]
 fn test_multiple_clients_get_same_file() {
     let server = server();
     let contents = "Hello world\n";
-    let file = TempFile::new("hello.txt", contents).unwrap();
+    let file = server.file("hello.txt", contents);
     let n = 25;
     let mut threads = Vec::with_capacity(n);
     let (taskout, taskin) = mpsc::channel::<Result<(u16, String), ureq::Error>>();
@@ -142,7 +259,7 @@ fn test_multiple_clients_get_same_file() {
 fn test_multiple_clients_reading_and_writing_same_file() {
     let handle = server();
     let contents = "Hello world\n";
-    let file = TempFile::new("hello.txt", contents).unwrap();
+    let file = handle.file("hello.txt", contents);
 
     let n = 25;
     let mut threads = Vec::with_capacity(n);
@@ -188,3 +305,1146 @@ fn test_multiple_clients_reading_and_writing_same_file() {
         }
     }
 }
+
+/// Tests changing the log level through the admin endpoint
+#[test]
+fn test_admin_log_level() {
+    let handle = server_with(|srv| srv.admin = true);
+    let addr = handle.file_addr(Server::ADMIN_LOG_LEVEL_PATH.trim_start_matches('/'));
+
+    let (code, body) = ureq_post_errors_are_ok(&addr, "trace").unwrap();
+    assert_eq!(200, code);
+    assert_eq!("trace\n", body);
+    assert_eq!(log::LevelFilter::Trace, log::max_level());
+
+    let (code, _) = ureq_post_errors_are_ok(&addr, "loud").unwrap();
+    assert_eq!(400, code);
+
+    log::set_max_level(log::LevelFilter::Off);
+    assert_eq!(
+        (200, String::from("off\n")),
+        ureq_get_errors_are_ok(&addr).unwrap()
+    );
+}
+
+/// Tests that the bytes sent are counted by client and by path
+#[test]
+fn test_admin_usage() {
+    let handle = server_with(|srv| srv.admin = true);
+    let file = handle.file("usage.txt", "counted\n");
+    let (code, body) = ureq_get_errors_are_ok(&handle.file_addr(&file.name)).unwrap();
+    assert_eq!((200, "counted\n"), (code, body.as_str()));
+
+    // The request is counted once the connection is done with, which can be
+    // just after the client has the response
+    let addr = handle.file_addr(Server::ADMIN_USAGE_PATH.trim_start_matches('/'));
+    let line = format!("  /{} requests=1 bytes=", file.name);
+    let mut usage = String::new();
+    for _ in 0..50 {
+        usage = ureq_get_errors_are_ok(&addr).unwrap().1;
+        if usage.contains(&line) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(usage.contains(&line), "{}", usage);
+    assert!(usage.contains("  127.0.0.1 requests="), "{}", usage);
+}
+
+/// Tests that the admin endpoints are not served unless enabled
+#[test]
+fn test_admin_disabled_by_default() {
+    let handle = server();
+    let addr = handle.file_addr(Server::ADMIN_LOG_LEVEL_PATH.trim_start_matches('/'));
+    assert_eq!(404, ureq_get_errors_are_ok(&addr).unwrap().0);
+}
+
+/// Tests that an announced server can be discovered
+#[test]
+fn test_announce() {
+    let to = SocketAddr::from(([127, 0, 0, 1], 8698));
+    let discovery = Discovery::bind(to.port()).unwrap();
+    let handle = server_with(|srv| srv.announce = Some(to));
+
+    let found = discovery.collect(Duration::from_millis(500)).unwrap();
+    let server = found
+        .iter()
+        .find(|s| format!("http://{}", s.addr) == handle.addr())
+        .unwrap_or_else(|| panic!("server not discovered, found {:?}", found));
+    assert_eq!("tcp", server.beacon.transport);
+    assert_eq!(
+        handle.dir().path.file_name().unwrap().to_string_lossy(),
+        server.beacon.name
+    );
+}
+
+/// Tests that a server started on port 0 reports the port it was given
+#[test]
+fn test_ephemeral_port() {
+    let mut cfg = ServerDropper::DEFAULT_SERVER_CONFIG;
+    cfg.1 = 0;
+    let handle = ServerDropper::new_or_panic(cfg);
+    assert!(!handle.addr().ends_with(":0"), "{}", handle.addr());
+
+    let file = handle.file("ephemeral.txt", "hello");
+    let got = ureq::get(&handle.file_addr(&file.name))
+        .call()
+        .unwrap()
+        .into_string()
+        .unwrap();
+    assert_eq!("hello", got);
+}
+
+#[test]
+fn test_extra_addrs() {
+    let mut cfg = ServerDropper::DEFAULT_SERVER_CONFIG;
+    cfg.1 = 0;
+    let handle = ServerDropper::with(cfg, |srv| {
+        srv.extra_addrs = vec![IpAddr::from(Ipv6Addr::LOCALHOST)]
+    })
+    .unwrap();
+    let file = handle.file("extra_addrs.txt", "hello");
+
+    let addrs = handle
+        .bound_addrs()
+        .iter()
+        .map(|addr| match addr {
+            BoundAddr::Tcp(addr) => *addr,
+            #[cfg(unix)]
+            addr => panic!("expected a TCP listener, got {}", addr),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(2, addrs.len(), "{:?}", addrs);
+    assert!(addrs[0].is_ipv4() && addrs[1].is_ipv6(), "{:?}", addrs);
+    assert_eq!(addrs[0].port(), addrs[1].port());
+    for addr in addrs {
+        let got = ureq::get(&format!("http://{}/{}", addr, file.name))
+            .call()
+            .unwrap()
+            .into_string()
+            .unwrap();
+        assert_eq!("hello", got, "{}", addr);
+    }
+}
+
+/// Tests that files with non-ASCII names can be uploaded and downloaded. ureq
+/// percent-encodes the paths.
+#[test]
+fn test_unicode_file_names() {
+    let handle = server();
+    for name in [
+        "\u{1F600}.txt",
+        "\u{6587}\u{4EF6}.txt",
+        "caf\u{E9} & co.txt",
+    ] {
+        let file = handle.file(name, "");
+        let contents = format!("contents of {}\n", name);
+        let posted = ureq::post(&handle.file_addr(&file.name))
+            .send_string(&contents)
+            .unwrap();
+        assert_eq!(201, posted.status());
+        assert_eq!(contents, std::fs::read_to_string(&file.path).unwrap());
+
+        let got = ureq::get(&handle.file_addr(&file.name)).call().unwrap();
+        assert_eq!(
+            Some(format!(
+                "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+                file.name.replace(|c: char| !c.is_ascii(), "_"),
+                httpfs::parse::percent_encode(&file.name)
+            ))
+            .as_deref(),
+            got.header("Content-Disposition"),
+        );
+        assert_eq!(contents, got.into_string().unwrap());
+    }
+}
+
+/// Tests that paths that don't decode to UTF-8 are rejected
+#[test]
+fn test_invalid_utf8_path() {
+    let handle = server();
+    for request in [
+        &b"GET /%FF%FE.txt HTTP/1.1\r\n\r\n"[..],
+        b"GET /%zz.txt HTTP/1.1\r\n\r\n",
+        b"GET /\xff\xfe.txt HTTP/1.1\r\n\r\n",
+    ] {
+        let (status, _) = raw_request_bytes(&handle, request);
+        assert_eq!("400 Bad Request", status, "{:?}", request);
+    }
+}
+
+/// Tests that long request lines and headers are refused with their own
+/// statuses
+#[test]
+fn test_max_line_length() {
+    let handle = server_with(|srv| srv.max_line_length = 64);
+    let file = handle.file("short.txt", "short\n");
+
+    let (status, body) = raw_request(
+        &handle,
+        &format!(
+            "GET /{} HTTP/1.1\r\nX-Fits: {}\r\n\r\n",
+            file.name,
+            "a".repeat(40)
+        ),
+    );
+    assert_eq!("200 OK", status, "{}", body);
+
+    let (status, body) = raw_request(
+        &handle,
+        &format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64)),
+    );
+    assert_eq!("414 URI Too Long", status);
+    assert!(
+        body.contains("request line is longer than 64 bytes"),
+        "{}",
+        body
+    );
+
+    let (status, body) = raw_request(
+        &handle,
+        &format!(
+            "GET /{} HTTP/1.1\r\nX-Long: {}\r\n\r\n",
+            file.name,
+            "a".repeat(64)
+        ),
+    );
+    assert_eq!("431 Request Header Fields Too Large", status);
+    assert!(
+        body.contains("request header is longer than 64 bytes"),
+        "{}",
+        body
+    );
+}
+
+/// Tests sorting and filtering directory listings with the query
+#[test]
+fn test_listing_sort_and_filter() {
+    let handle = server();
+    let now = SystemTime::now();
+    for (name, contents, age) in [
+        ("b.rs", "0123456789", 3),
+        ("a.txt", "012", 1),
+        ("c.rs", "0", 2),
+        ("sub/d.rs", "", 0),
+    ] {
+        let path = handle.dir().write(name, contents).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(now - Duration::from_secs(age * 60))
+            .unwrap();
+    }
+
+    let links = |query: &str| {
+        let (status, body) = ureq_get_errors_are_ok(&handle.file_addr(query)).unwrap();
+        assert_eq!(200, status, "{}", body);
+        listing_links(&body)
+    };
+    assert_eq!(vec!["a.txt", "b.rs", "c.rs", "sub/"], links("?sort=name"));
+    assert_eq!(vec!["sub/", "c.rs", "b.rs", "a.txt"], links("?order=desc"));
+    assert_eq!(vec!["sub/", "c.rs", "a.txt", "b.rs"], links("?sort=size"));
+    assert_eq!(
+        vec!["b.rs", "c.rs", "sub/"],
+        links("?sort=size&order=desc&filter=*.rs")
+    );
+    assert_eq!(
+        vec!["b.rs", "c.rs", "a.txt", "sub/"],
+        links("?sort=mtime&filter=?.*")
+    );
+    assert_eq!(4, links("").len());
+
+    let (status, body) = ureq_get_errors_are_ok(&handle.file_addr("?sort=colour")).unwrap();
+    assert_eq!(400, status);
+    assert!(body.contains("sort=colour"), "{}", body);
+}
+
+/// Tests that listings are split into pages that link to each other
+#[test]
+fn test_listing_pages() {
+    let handle = server();
+    for name in ["a", "b", "c", "d", "e"] {
+        handle.dir().write(name, "").unwrap();
+    }
+
+    let page = |query: &str| {
+        let res = ureq::get(&handle.file_addr(query)).call().unwrap();
+        let link = res.header("Link").map(String::from);
+        (listing_links(&res.into_string().unwrap()), link)
+    };
+    assert_eq!(
+        (
+            vec![String::from("a"), String::from("b")],
+            Some(String::from(
+                r#"<?sort=name&page=2&per_page=2>; rel="next""#
+            ))
+        ),
+        page("?sort=name&per_page=2")
+    );
+    assert_eq!(
+        (
+            vec![String::from("c"), String::from("d")],
+            Some(String::from(
+                r#"<?sort=name&page=1&per_page=2>; rel="prev", <?sort=name&page=3&per_page=2>; rel="next""#
+            ))
+        ),
+        page("?sort=name&per_page=2&page=2")
+    );
+    assert_eq!(
+        (
+            vec![String::from("e")],
+            Some(String::from(
+                r#"<?sort=name&page=2&per_page=2>; rel="prev""#
+            ))
+        ),
+        page("?sort=name&per_page=2&page=3")
+    );
+    assert_eq!(
+        (
+            vec![],
+            Some(String::from(
+                r#"<?sort=name&page=3&per_page=2>; rel="prev""#
+            ))
+        ),
+        page("?sort=name&per_page=2&page=4")
+    );
+
+    // Unsorted pages still cover every entry once
+    let mut all = (1..=3)
+        .flat_map(|n| page(&format!("?per_page=2&page={}", n)).0)
+        .collect::<Vec<_>>();
+    all.sort();
+    assert_eq!(vec!["a", "b", "c", "d", "e"], all);
+    assert_eq!(
+        (vec![String::from("e")], None),
+        page("?per_page=10&filter=e")
+    );
+
+    let (status, _) = ureq_get_errors_are_ok(&handle.file_addr("?page=0")).unwrap();
+    assert_eq!(400, status);
+}
+
+/// Tests that listings are streamed, chunked to HTTP/1.1 clients and until the
+/// connection closes to HTTP/1.0 ones
+#[test]
+fn test_listing_streamed() {
+    let handle = server();
+    let names = (0..500)
+        .map(|n| format!("file-{:03}", n))
+        .collect::<Vec<_>>();
+    for name in &names {
+        handle.dir().write(name, "").unwrap();
+    }
+
+    let res = ureq::get(&handle.file_addr("?sort=name")).call().unwrap();
+    assert_eq!(Some("chunked"), res.header("Transfer-Encoding"));
+    assert_eq!(None, res.header("Content-Length"));
+    let body = res.into_string().unwrap();
+    assert!(body.trim_end().ends_with("</html>"), "{}", body);
+    assert_eq!(names, listing_links(&body));
+
+    let (status, rest) = raw_request(&handle, "GET /?sort=name HTTP/1.0\r\n\r\n");
+    assert_eq!("200 OK", status);
+    assert!(!rest.contains("Transfer-Encoding"), "{}", rest);
+    assert_eq!(names, listing_links(&rest));
+}
+
+/// Tests that uploads over the directory quota are rejected
+#[test]
+fn test_quota() {
+    let handle = server_with(|srv| {
+        std::fs::write(format!("{}/existing.txt", srv.dir), "0123456789").unwrap();
+        srv.max_dir_bytes = Some(24);
+    });
+
+    // 10 bytes are in use, so 10 more fit but 20 more don't
+    let upload = |name: &str, len: usize| {
+        ureq_post_errors_are_ok(&handle.file_addr(name), &"x".repeat(len)).unwrap()
+    };
+    assert_eq!(201, upload("a.txt", 10).0);
+    let (status, body) = upload("b.txt", 10);
+    assert_eq!(507, status);
+    assert!(body.contains("quota of 24 bytes"), "{}", body);
+
+    // Overwriting only counts the bytes past the end of the file
+    assert_eq!(201, upload("a.txt", 14).0);
+    assert_eq!(507, upload("existing.txt", 11).0);
+}
+
+#[test]
+fn test_keep_versions() {
+    let handle = server_with(|srv| {
+        std::fs::create_dir(format!("{}/sub", srv.dir)).unwrap();
+        srv.keep_versions = Some(2);
+        srv.max_dir_bytes = Some(1000);
+    });
+
+    for (i, body) in ["first", "second", "third", "fourth"].iter().enumerate() {
+        let (status, _) =
+            ureq_post_errors_are_ok(&handle.file_addr("sub/notes.txt"), body).unwrap();
+        assert_eq!(201, status, "upload {}", i);
+    }
+
+    let read = |path: &str| handle.dir().read(path);
+    assert_eq!(Some(String::from("fourth")), read("sub/notes.txt"));
+    assert_eq!(None, read(".trash/sub/notes.txt.1"));
+    assert_eq!(Some(String::from("second")), read(".trash/sub/notes.txt.2"));
+    assert_eq!(Some(String::from("third")), read(".trash/sub/notes.txt.3"));
+
+    // Clients can neither read old versions nor plant versions of their own
+    // to push the real ones out
+    for path in [
+        ".trash/sub/notes.txt.3",
+        ".trash/sub/notes.txt.9",
+        ".trash/",
+    ] {
+        let (status, _) = ureq_get_errors_are_ok(&handle.file_addr(path)).unwrap();
+        assert_eq!(403, status, "{}", path);
+        let (status, _) = ureq_post_errors_are_ok(&handle.file_addr(path), "fake").unwrap();
+        assert_eq!(403, status, "{}", path);
+    }
+    assert_eq!(None, read(".trash/sub/notes.txt.9"));
+    assert_eq!(Some(String::from("third")), read(".trash/sub/notes.txt.3"));
+}
+
+#[test]
+fn test_dedup() {
+    let handle = server_with(|srv| srv.dedup = true);
+
+    let contents = "the same contents\n".repeat(100);
+    for name in ["a.txt", "b.txt", "a.txt"] {
+        assert_eq!(
+            201,
+            ureq_post_errors_are_ok(&handle.file_addr(name), &contents)
+                .unwrap()
+                .0
+        );
+    }
+    assert_eq!(
+        201,
+        ureq_post_errors_are_ok(&handle.file_addr("c.txt"), "different")
+            .unwrap()
+            .0
+    );
+    assert_eq!(
+        (200, contents),
+        ureq_get_errors_are_ok(&handle.file_addr("b.txt")).unwrap()
+    );
+
+    // One object per distinct upload
+    let objects = std::fs::read_dir(handle.dir().join(".objects"))
+        .unwrap()
+        .flatten()
+        .flat_map(|prefix| std::fs::read_dir(prefix.path()).unwrap().flatten())
+        .count();
+    assert_eq!(2, objects);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let inode = |name: &str| std::fs::metadata(handle.dir().join(name)).unwrap().ino();
+        assert_eq!(inode("a.txt"), inode("b.txt"));
+        assert_ne!(inode("a.txt"), inode("c.txt"));
+    }
+}
+
+/// Tests that clients can neither read nor replace the stored objects, which
+/// later uploads of the same contents would be linked to
+#[test]
+fn test_objects_are_reserved() {
+    let handle = server_with(|srv| srv.dedup = true);
+    let contents = "the real contents\n";
+    assert_eq!(
+        201,
+        ureq_post_errors_are_ok(&handle.file_addr("a.txt"), contents)
+            .unwrap()
+            .0
+    );
+    let (_, digest) = sha256_of(contents.as_bytes()).unwrap();
+    let object = format!(".objects/{}/{}", &digest[..2], digest);
+
+    for path in [object.as_str(), ".objects/", ".Objects/00/new"] {
+        let (status, _) = ureq_get_errors_are_ok(&handle.file_addr(path)).unwrap();
+        assert_eq!(403, status, "{}", path);
+        let (status, _) = ureq_post_errors_are_ok(&handle.file_addr(path), "forged").unwrap();
+        assert_eq!(403, status, "{}", path);
+    }
+    assert_eq!(Some(String::from(contents)), handle.dir().read(&object));
+
+    // Uploads of the same contents still get the real ones
+    assert_eq!(
+        201,
+        ureq_post_errors_are_ok(&handle.file_addr("b.txt"), contents)
+            .unwrap()
+            .0
+    );
+    assert_eq!(
+        (200, String::from(contents)),
+        ureq_get_errors_are_ok(&handle.file_addr("b.txt")).unwrap()
+    );
+}
+
+/// Tests that big files go both ways intact, without holding them in memory
+#[test]
+fn test_large_file() {
+    let handle = server();
+    let len = large_file_len();
+    let file = TempFile::random(&handle.dir().path, "large.bin", 1, len).unwrap();
+    let res = ureq::get(&handle.file_addr(&file.name)).call().unwrap();
+    assert_eq!(
+        sha256_of(RandomReader::new(1, len)).unwrap(),
+        sha256_of(res.into_reader()).unwrap()
+    );
+
+    let res = ureq::post(&handle.file_addr("uploaded.bin"))
+        .set("Content-Length", &len.to_string())
+        .send(RandomReader::new(2, len))
+        .unwrap();
+    assert_eq!(201, res.status());
+    assert_eq!(
+        sha256_of(RandomReader::new(2, len)).unwrap(),
+        sha256_of(std::fs::File::open(handle.dir().join("uploaded.bin")).unwrap()).unwrap()
+    );
+}
+
+#[test]
+fn test_checksum_trailer() {
+    let handle = server();
+    let file = handle.file("trailer.txt", "hello world\n");
+    let request = |te: &str| {
+        raw_request(
+            &handle,
+            &format!(
+                "GET /{} HTTP/1.1\r\nHost: localhost{}\r\n\r\n",
+                file.name, te
+            ),
+        )
+    };
+
+    let (status, rest) = request("\r\nTE: trailers");
+    assert_eq!("200 OK", status);
+    assert!(rest.contains("Transfer-Encoding: chunked"), "{}", rest);
+    assert!(rest.contains("Trailer: X-Checksum-SHA256"), "{}", rest);
+    assert!(
+        rest.ends_with(concat!(
+            "\nc\nhello world\n\n0\n",
+            "X-Checksum-SHA256: a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447\n"
+        )),
+        "{}",
+        rest
+    );
+
+    // Without TE: trailers the file is sent as before
+    let (_, rest) = request("");
+    assert!(rest.contains("Content-Length: 12"), "{}", rest);
+    assert!(!rest.contains("X-Checksum-SHA256"), "{}", rest);
+}
+
+#[test]
+fn test_http1_0() {
+    let handle = server();
+    let file = handle.file("http1_0.txt", "hello world\n");
+    let request = |extra: &str| {
+        let mut sock = TcpStream::connect(handle.addr().trim_start_matches("http://")).unwrap();
+        write!(sock, "GET /{} HTTP/1.0\r\n{}\r\n", file.name, extra).unwrap();
+        let mut res = String::new();
+        sock.read_to_string(&mut res).unwrap();
+        res
+    };
+
+    let res = request("");
+    assert!(res.starts_with("HTTP/1.0 200 OK\r\n"), "{}", res);
+    assert!(res.contains("\r\nConnection: close\r\n"), "{}", res);
+    assert!(res.ends_with("\r\n\r\nhello world\n"), "{}", res);
+
+    // No chunked encoding, so no trailers either
+    let res = request("TE: trailers\r\n");
+    assert!(!res.contains("chunked"), "{}", res);
+    assert!(res.contains("Content-Length: 12"), "{}", res);
+}
+
+#[test]
+fn test_request_id() {
+    let handle = server();
+    let header_value = |rest: &str| {
+        rest.lines()
+            .find_map(|l| l.strip_prefix("X-Request-Id: "))
+            .map(String::from)
+    };
+
+    let (status, rest) = raw_request(&handle, "GET /nope.txt HTTP/1.1\r\n\r\n");
+    assert_eq!("404 Not Found", status);
+    let id = header_value(&rest).unwrap();
+    assert!(
+        id.len() == 16 && id.bytes().all(|b| b.is_ascii_hexdigit()),
+        "{}",
+        id
+    );
+
+    // Handler errors carry the client's id too
+    let (status, rest) = raw_request(
+        &handle,
+        "POST / HTTP/1.1\r\nContent-Length: 0\r\nX-Request-Id: upload-1\r\n\r\n",
+    );
+    assert_eq!("403 Forbidden", status);
+    assert_eq!(Some(String::from("upload-1")), header_value(&rest));
+
+    let (_, rest) = raw_request(
+        &handle,
+        "GET /nope.txt HTTP/1.1\r\nX-Request-Id: not valid\r\n\r\n",
+    );
+    assert_ne!(Some(String::from("not valid")), header_value(&rest));
+}
+
+#[test]
+fn test_concurrent_uploads_to_same_file() {
+    let handle = server_with(|srv| {
+        // One worker for each of the three uploads
+        srv.n_workers = 3;
+        srv.upload_waiters = 1;
+        srv.upload_wait = Duration::from_secs(5);
+    });
+    let file = handle.file("contended.txt", "");
+    let addr = handle.file_addr(&file.name);
+
+    // Start an upload and leave it hanging halfway through its body
+    let mut slow = TcpStream::connect(handle.addr().trim_start_matches("http://")).unwrap();
+    write!(
+        slow,
+        "POST /{} HTTP/1.1\r\nContent-Length: 10\r\n\r\nfirst",
+        file.name
+    )
+    .unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    // One upload waits for its turn, the one after that is refused
+    let waiting = {
+        let addr = addr.clone();
+        thread::spawn(move || ureq_post_errors_are_ok(&addr, "second").unwrap())
+    };
+    thread::sleep(Duration::from_millis(100));
+    let (status, body) = ureq_post_errors_are_ok(&addr, "third").unwrap();
+    assert_eq!(409, status);
+    assert!(body.contains("is being uploaded"), "{}", body);
+
+    write!(slow, "-done").unwrap();
+    let mut res = String::new();
+    slow.read_to_string(&mut res).unwrap();
+    assert!(res.starts_with("HTTP/1.1 201 Created"), "{}", res);
+    assert_eq!(201, waiting.join().unwrap().0);
+}
+
+#[test]
+fn test_proxy() {
+    let upstream = server();
+    upstream
+        .dir()
+        .write("hello.txt", "hello from upstream\n")
+        .unwrap();
+    let dead = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    };
+    let handle = server_with(|srv| {
+        srv.proxies = vec![
+            Proxy {
+                prefix: String::from("/up"),
+                upstream: upstream.addr()["http://".len()..].parse().unwrap(),
+            },
+            Proxy {
+                prefix: String::from("/dead"),
+                upstream: dead,
+            },
+        ]
+    });
+
+    assert_eq!(
+        (200, String::from("hello from upstream\n")),
+        ureq_get_errors_are_ok(&handle.file_addr("up/hello.txt")).unwrap()
+    );
+    assert_eq!(
+        201,
+        ureq_post_errors_are_ok(&handle.file_addr("up/posted.txt"), "through the proxy")
+            .unwrap()
+            .0
+    );
+    assert_eq!(
+        "through the proxy",
+        upstream.dir().read("posted.txt").unwrap()
+    );
+
+    // The upstream server answers with the client's request id
+    let (status, rest) = raw_request(
+        &handle,
+        "GET /up/nope.txt HTTP/1.1\r\nHost: files.example\r\nX-Request-Id: via-proxy\r\n\r\n",
+    );
+    assert_eq!("404 Not Found", status);
+    assert!(rest.contains("X-Request-Id: via-proxy"), "{}", rest);
+
+    // Bodies are framed by the length they were read with, whatever the case
+    // of the client's header
+    let (status, rest) = raw_request(
+        &handle,
+        "POST /up/lower.txt HTTP/1.1\r\ncontent-length: 5\r\n\r\nlower",
+    );
+    assert_eq!("201 Created", status, "{}", rest);
+    assert_eq!(
+        Some(String::from("lower")),
+        upstream.dir().read("lower.txt")
+    );
+
+    // Bodies of unknown length can't be framed
+    for request in [
+        "POST /up/chunked.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        "POST /up/unknown.txt HTTP/1.1\r\n\r\n",
+    ] {
+        let (status, rest) = raw_request(&handle, request);
+        assert_eq!("411 Length Required", status, "{}", rest);
+    }
+    assert_eq!(None, upstream.dir().read("chunked.txt"));
+
+    let (status, _) = ureq_get_errors_are_ok(&handle.file_addr("dead/hello.txt")).unwrap();
+    assert_eq!(502, status);
+}
+
+/// Tests that an upstream server that never answers doesn't hold on to the
+/// worker
+#[test]
+fn test_proxy_timeout() {
+    let stalled = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let handle = server_with(|srv| {
+        srv.proxies = vec![Proxy {
+            prefix: String::from("/stalled"),
+            upstream: stalled.local_addr().unwrap(),
+        }];
+        srv.proxy_timeout = Duration::from_millis(200);
+    });
+
+    // The connection is accepted by the system, but nothing ever reads it or
+    // answers
+    let started = Instant::now();
+    let (status, body) = ureq_get_errors_are_ok(&handle.file_addr("stalled/a.txt")).unwrap();
+    assert_eq!(504, status, "{}", body);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    drop(stalled);
+}
+
+#[test]
+fn test_signed_urls() {
+    let signed = SignedUrls::new(b"secret", vec![String::from("/signed.txt")]);
+    let handle = server_with(|srv| srv.signed_urls = Some(signed.clone()));
+    let file = handle.file("signed.txt", "for your eyes only\n");
+    let public = handle.file("unsigned.txt", "for everyone\n");
+    let get = |path: &str| ureq_get_errors_are_ok(&format!("{}{}", handle.addr(), path)).unwrap();
+
+    let hour = Duration::from_secs(3600);
+    let link = signed.sign(&format!("/{}", file.name), SystemTime::now() + hour);
+    assert_eq!((200, String::from("for your eyes only\n")), get(&link));
+    assert_eq!(403, get(&format!("/{}", file.name)).0);
+    assert_eq!(403, get(&format!("/./{}?expires=1&sig=00", file.name)).0);
+
+    // The same file on case-insensitive filesystems, so it is protected too
+    assert_eq!(403, get(&format!("/{}", file.name.to_uppercase())).0);
+
+    let expired = signed.sign(&format!("/{}", file.name), SystemTime::now() - hour);
+    assert_eq!(403, get(&expired).0);
+
+    // Queries on other paths are ignored
+    assert_eq!(
+        (200, String::from("for everyone\n")),
+        get(&format!("/{}?expires=1&sig=00", public.name))
+    );
+}
+
+#[test]
+fn test_router() {
+    let router = Router::new()
+        .get("/api/files/{name}", |req| {
+            Ok(Response::text(
+                StatusCode::OK,
+                format!("info about {}\n", req.params["name"]),
+            ))
+        })
+        .post("/api/echo/{*rest}", |req| {
+            let mut body = String::new();
+            req.body.read_to_string(&mut body)?;
+            Ok(Response::text(
+                StatusCode::CREATED,
+                format!("{}: {}", req.params["rest"], body),
+            )
+            .header("X-Echo", "yes"))
+        })
+        .get("/api/login", |_| {
+            Ok(Response::text(StatusCode::OK, "welcome\n")
+                .header("Set-Cookie", "session=1")
+                .header("X-Order", "between")
+                .header("Set-Cookie", "theme=dark"))
+        });
+    let handle = server_with(|srv| srv.router = router);
+    let file = handle.file("routed.txt", "served from disk\n");
+
+    assert_eq!(
+        (200, String::from("info about a.txt\n")),
+        ureq_get_errors_are_ok(&handle.file_addr("api/files/a.txt")).unwrap()
+    );
+    assert_eq!(
+        (201, String::from("x/y: hello")),
+        ureq_post_errors_are_ok(&handle.file_addr("api/echo/x/y"), "hello").unwrap()
+    );
+
+    // Paths without a route go to the file server
+    assert_eq!(
+        (200, String::from("served from disk\n")),
+        ureq_get_errors_are_ok(&handle.file_addr(&file.name)).unwrap()
+    );
+
+    let (status, rest) = raw_request(&handle, "GET /api/echo/x HTTP/1.1\r\n\r\n");
+    assert_eq!("405 Method Not Allowed", status);
+    assert!(rest.contains("Allow: POST"), "{}", rest);
+
+    // Repeated headers all arrive, in the order the handler set them
+    let (status, rest) = raw_request(&handle, "GET /api/login HTTP/1.1\r\n\r\n");
+    assert_eq!("200 OK", status);
+    assert!(
+        rest.contains("Set-Cookie: session=1\nX-Order: between\nSet-Cookie: theme=dark\n"),
+        "{}",
+        rest
+    );
+}
+
+#[test]
+fn test_upgrade() {
+    let router = Router::new().get("/echo", |_| {
+        Ok(Response::text(StatusCode::OK, "echoing\n").upgrade(|conn| {
+            let mut buf = [0; 64];
+            loop {
+                match conn.read(&mut buf)? {
+                    0 => return Ok(()),
+                    n => conn.write_all(&buf[..n])?,
+                }
+            }
+        }))
+    });
+    let handle = server_with(|srv| srv.router = router);
+    let echo = |request: &str| {
+        let addr = handle.addr();
+        let mut sock = TcpStream::connect(addr.trim_start_matches("http://")).unwrap();
+        sock.write_all(request.as_bytes()).unwrap();
+        sock.write_all(b"later").unwrap();
+        sock.shutdown(Shutdown::Write).unwrap();
+        let mut out = String::new();
+        sock.read_to_string(&mut out).unwrap();
+        out
+    };
+
+    // Bytes sent along with the request reach the handler too
+    let out = echo(
+        "GET /echo HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\nUpgrade: echo\r\n\r\nearly ",
+    );
+    let (head, rest) = out.split_once("\r\n\r\n").unwrap();
+    assert!(
+        head.starts_with("HTTP/1.1 101 Switching Protocols"),
+        "{}",
+        head
+    );
+    assert!(head.contains("Connection: Upgrade"), "{}", head);
+    assert!(head.contains("Upgrade: echo"), "{}", head);
+    assert_eq!("echoing\nearly later", rest);
+
+    // Without asking to upgrade, the handler writes the rest of the body
+    let out = echo("GET /echo HTTP/1.1\r\n\r\n");
+    let (head, rest) = out.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+    assert!(!head.contains("Content-Length"), "{}", head);
+    assert_eq!("echoing\nlater", rest);
+}
+
+#[test]
+fn test_websocket() {
+    let router = Router::new().get("/ws", |req| {
+        websocket::accept(req, |ws| loop {
+            match ws.recv()? {
+                msg @ (Message::Text(_) | Message::Binary(_)) => ws.send(&msg)?,
+                Message::Close(_) => return Ok(()),
+                _ => {}
+            }
+        })
+    });
+    let handle = server_with(|srv| srv.router = router);
+    let addr = handle.addr();
+    let mut sock = TcpStream::connect(addr.trim_start_matches("http://")).unwrap();
+
+    let key = websocket::client_key().unwrap();
+    write!(
+        sock,
+        "GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+        Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {}\r\n\r\n",
+        key
+    )
+    .unwrap();
+
+    // Read the head a byte at a time, the frames come right after it
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut b = [0];
+        sock.read_exact(&mut b).unwrap();
+        head.push(b[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(
+        head.starts_with("HTTP/1.1 101 Switching Protocols"),
+        "{}",
+        head
+    );
+    let accept = format!("Sec-WebSocket-Accept: {}", websocket::accept_key(&key));
+    assert!(head.contains(&accept), "{}", head);
+
+    let mut ws = WebSocket::client(sock);
+    for msg in [
+        Message::Text(String::from("hello")),
+        Message::Binary(vec![0; 70000]),
+    ] {
+        ws.send(&msg).unwrap();
+        assert_eq!(msg, ws.recv().unwrap());
+    }
+    ws.send(&Message::Ping(b"ping".to_vec())).unwrap();
+    assert_eq!(Message::Pong(b"ping".to_vec()), ws.recv().unwrap());
+    ws.close(websocket::close_code::NORMAL, "done").unwrap();
+    assert_eq!(
+        Message::Close(Some((websocket::close_code::NORMAL, String::new()))),
+        ws.recv().unwrap()
+    );
+
+    // Not a handshake
+    let (status, _) = raw_request(&handle, "GET /ws HTTP/1.1\r\n\r\n");
+    assert_eq!("400 Bad Request", status);
+}
+
+#[test]
+fn test_wait_ready_and_join() {
+    let mut handle = Server {
+        port: 0,
+        ..Default::default()
+    }
+    .serve()
+    .unwrap();
+    handle.wait_ready(Duration::from_secs(5)).unwrap();
+    assert!(handle.is_running());
+
+    let addr = match handle.bound_addrs().first() {
+        Some(httpfs::transport::BoundAddr::Tcp(addr)) => *addr,
+        addr => panic!("not a TCP address: {:?}", addr),
+    };
+    TcpStream::connect(addr).unwrap();
+
+    // Clones can wait for the server to stop while another shuts it down
+    let waiters = (0..2)
+        .map(|_| {
+            let handle = handle.clone();
+            thread::spawn(move || handle.join().is_ok())
+        })
+        .collect::<Vec<_>>();
+
+    // Shutting down twice is fine, and a server that was shut down joins
+    // without an error
+    handle.clone().shutdown();
+    handle.shutdown();
+    assert!(!handle.is_running());
+    for waiter in waiters {
+        assert!(waiter.join().unwrap());
+    }
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_before_accept() {
+    let ran = Arc::new(AtomicBool::new(false));
+    let ranc = ran.clone();
+    let mut handle = Server {
+        port: 0,
+        before_accept: Some(Box::new(move || {
+            ranc.store(true, Ordering::SeqCst);
+            Ok(())
+        })),
+        ..Default::default()
+    }
+    .serve()
+    .unwrap();
+    assert!(ran.load(Ordering::SeqCst));
+    handle.shutdown();
+    handle.join().unwrap();
+
+    // The server doesn't start if it fails
+    let res = Server {
+        port: 0,
+        before_accept: Some(Box::new(|| Err(ServerError::new().msg("no privileges")))),
+        ..Default::default()
+    }
+    .serve();
+    match res {
+        Err(e) => assert!(e.to_string().contains("no privileges"), "{}", e),
+        Ok(_) => panic!("the server started"),
+    }
+}
+
+/// Runs each job on a thread of its own and remembers them
+#[derive(Default)]
+struct ThreadPerJob(Mutex<Vec<thread::JoinHandle<()>>>);
+
+impl Spawner for ThreadPerJob {
+    fn spawn(&self, job: Job) {
+        self.0.lock().unwrap().push(thread::spawn(job));
+    }
+
+    fn join(&self) {
+        let threads = std::mem::take(&mut *self.0.lock().unwrap());
+        threads.into_iter().for_each(|t| t.join().unwrap());
+    }
+}
+
+#[test]
+fn test_custom_spawner() {
+    let spawner = Arc::new(ThreadPerJob::default());
+    let handle = server_with(|srv| srv.spawner = Some(spawner.clone()));
+    let file = handle.file("spawned.txt", "spawned\n");
+    for _ in 0..3 {
+        assert_eq!(
+            (200, String::from("spawned\n")),
+            ureq_get_errors_are_ok(&handle.file_addr(&file.name)).unwrap()
+        );
+    }
+    assert_eq!(3, spawner.0.lock().unwrap().len());
+    drop(handle);
+    assert!(spawner.0.lock().unwrap().is_empty());
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_rayon_spawner() {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .unwrap();
+    let spawner = Arc::new(httpfs::server::Rayon::new(pool));
+    let handle = server_with(|srv| srv.spawner = Some(spawner.clone()));
+    let file = handle.file("rayon.txt", "stolen\n");
+    for _ in 0..3 {
+        assert_eq!(
+            (200, String::from("stolen\n")),
+            ureq_get_errors_are_ok(&handle.file_addr(&file.name)).unwrap()
+        );
+    }
+    drop(handle);
+    assert_eq!(0, spawner.pending());
+}
+
+#[test]
+fn test_connection_events() {
+    let (tx, rx) = mpsc::channel();
+    let handle = server_with(|srv| srv.connection_events = Some(tx));
+    let file = handle.file("events.txt", "events\n");
+    ureq::get(&handle.file_addr(&file.name)).call().unwrap();
+
+    let next = || rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let id = match next() {
+        ConnectionEvent::Opened { id, peer } => {
+            assert!(peer.starts_with("127.0.0.1:"), "{}", peer);
+            id
+        }
+        event => panic!("expected the connection to open first, got {:?}", event),
+    };
+    match next() {
+        ConnectionEvent::Closed {
+            id: closed,
+            path,
+            bytes_sent,
+            ..
+        } => {
+            assert_eq!(id, closed);
+            assert_eq!(Some(format!("/{}", file.name)), path);
+            assert!(bytes_sent > "events\n".len() as u64, "{}", bytes_sent);
+        }
+        event => panic!("expected the connection to close, got {:?}", event),
+    }
+
+    // Dropping the receiver doesn't bother the server
+    drop(rx);
+    assert_eq!(
+        (200, String::from("events\n")),
+        ureq_get_errors_are_ok(&handle.file_addr(&file.name)).unwrap()
+    );
+}
+
+#[test]
+fn test_error_pages() {
+    let handle = server_with(|srv| {
+        srv.error_pages = Some(ErrorPages::new().page(StatusCode::NOT_FOUND, |page| {
+            format!("<h1>{}</h1>", page.status)
+        }))
+    });
+    let get = |path: &str, accept: &str| {
+        let res = match ureq::get(&handle.file_addr(path))
+            .set("Accept", accept)
+            .call()
+        {
+            Ok(res) | Err(ureq::Error::Status(_, res)) => res,
+            Err(e) => panic!("{}", e),
+        };
+        (
+            res.status(),
+            res.content_type().to_string(),
+            res.into_string().unwrap(),
+        )
+    };
+
+    let html = "text/html,application/xhtml+xml,*/*;q=0.8";
+    assert_eq!(
+        (
+            404,
+            String::from("text/html"),
+            String::from("<h1>404 Not Found</h1>")
+        ),
+        get("nope.txt", html)
+    );
+
+    // Clients that don't ask for HTML, and statuses without a page, get the
+    // plain text message
+    let (status, content_type, body) = get("nope.txt", "*/*");
+    assert_eq!((404, "text/plain"), (status, content_type.as_str()));
+    assert!(body.contains("could not be found"), "{}", body);
+    let (status, content_type, _) = get("..%2f..%2fetc%2fpasswd", html);
+    assert_eq!((403, "text/plain"), (status, content_type.as_str()));
+}
+
+/// Checks the server against a real client, see [INTEROP_ENV_VARIABLE]
+#[test]
+fn test_interop_curl() {
+    if !interop_enabled() {
+        return;
+    }
+    let handle = server();
+    let file = handle.file("caf\u{E9} curl.txt", "");
+    let url = handle.file_addr(&httpfs::parse::percent_encode(&file.name));
+    let contents = "hello from curl\n";
+
+    let (status, _) = curl(&["--data-binary", contents, &url]);
+    assert_eq!(201, status);
+    assert_eq!(contents, std::fs::read_to_string(&file.path).unwrap());
+
+    // Content-Length, chunked with a trailer, and HTTP/1.0 downloads
+    for args in [&[][..], &["--header", "TE: trailers"], &["--http1.0"]] {
+        let args = args
+            .iter()
+            .copied()
+            .chain([url.as_str()])
+            .collect::<Vec<_>>();
+        assert_eq!((200, String::from(contents)), curl(&args), "{:?}", args);
+    }
+
+    assert_eq!(404, curl(&[&handle.file_addr("nope.txt")]).0);
+    assert_eq!(
+        403,
+        curl(&["--path-as-is", &handle.file_addr("../Cargo.toml")]).0
+    );
+}
@@ -1,85 +1,238 @@
+#![allow(clippy::result_large_err)]
+
 use std::{
     fs,
-    io::{Error, Write},
-    net::IpAddr,
+    io::{self, Error, Read, Write},
+    net::{IpAddr, TcpStream},
+    path::{Path, PathBuf},
+    process::Command,
 };
 
 use httpfs::{
+    bullshit_scanner::BullshitScanner,
     errors::ServerError,
     server::{Handle, Server},
+    transport::BoundAddr,
 };
 
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rand::{distributions::Alphanumeric, rngs::StdRng, thread_rng, Rng, RngCore, SeedableRng};
+use ring::digest::{Context, SHA256};
+
+pub type ServerConfig = (IpAddr, u32, usize);
+
+/// Prefixes the filename with `TEMP_` and a random string, to avoid conflicts
+pub fn temp_name(filename: &str) -> String {
+    vec![
+        "TEMP_",
+        thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect::<String>()
+            .as_str(),
+        "_",
+        filename,
+    ]
+    .into_iter()
+    .collect::<String>()
+}
+
+/// A directory under the system's temp dir. When [dropped](Drop), the
+/// [TempDir] gets deleted along with everything in it.
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates an empty directory. To avoid conflicts, its name will be
+    /// prefixed with a random string
+    pub fn new(name: &str) -> Result<Self, Error> {
+        let path = std::env::temp_dir().join(temp_name(name));
+        fs::create_dir(&path)?;
+        Ok(Self { path })
+    }
+
+    pub fn new_or_panic(name: &str) -> Self {
+        Self::new(name).unwrap()
+    }
+
+    /// The path of `name` inside the directory
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// Writes a file inside the directory, creating the directories leading
+    /// up to it
+    pub fn write(&self, name: &str, contents: &str) -> Result<PathBuf, Error> {
+        let path = self.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, contents)?;
+        Ok(path)
+    }
+
+    /// Reads a file inside the directory, if there is one
+    pub fn read(&self, name: &str) -> Option<String> {
+        fs::read_to_string(self.join(name)).ok()
+    }
+}
 
-pub type ServerConfig = (IpAddr, u32, &'static str, usize);
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).unwrap();
+    }
+}
 
-/// When [dropped](Drop), the [TempFile] gets deleted.
+/// A file inside a [TempDir]. When [dropped](Drop), the [TempFile] gets
+/// deleted, unless the [TempDir] already took it with it.
 pub struct TempFile {
+    /// The name of the file relative to its directory, which is also its path
+    /// on a server serving that directory
     pub name: String,
+
+    /// Where the file is on disk
+    pub path: PathBuf,
 }
 
 impl TempFile {
-    /// Creates a temporary file with the provided contents. To avoid filename
-    /// conflicts, the filename will be prefixed with a random string
-    pub fn new(filename: &str, contents: &str) -> Result<Self, Error> {
-        let filename = vec![
-            "TEMP_",
-            thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(16)
-                .map(char::from)
-                .collect::<String>()
-                .as_str(),
-            "_",
-            filename,
-        ]
-        .into_iter()
-        .collect::<String>();
+    /// Creates a file in `dir` with the provided contents
+    pub fn new(dir: &Path, filename: &str, contents: &str) -> Result<Self, Error> {
+        let file = Self::at(dir, filename);
+        fs::File::create(&file.path)?.write_all(contents.as_bytes())?;
+        Ok(file)
+    }
 
-        fs::File::create(&filename)?.write_all(contents.as_bytes())?;
-        Ok(Self { name: filename })
+    pub fn new_or_panic(dir: &Path, filename: &str, contents: &str) -> Self {
+        Self::new(dir, filename, contents).unwrap()
     }
 
-    pub fn new_or_panic(filename: &str, contents: &str) -> Self {
-        Self::new(filename, contents).unwrap()
+    /// Creates a file in `dir` with the `len` bytes of a [RandomReader]
+    /// seeded with `seed`. The file is written in chunks, so it can be larger
+    /// than the memory at hand.
+    pub fn random(dir: &Path, filename: &str, seed: u64, len: u64) -> Result<Self, Error> {
+        let file = Self::at(dir, filename);
+        io::copy(
+            &mut RandomReader::new(seed, len),
+            &mut io::BufWriter::new(fs::File::create(&file.path)?),
+        )?;
+        Ok(file)
+    }
+
+    fn at(dir: &Path, filename: &str) -> Self {
+        Self {
+            name: String::from(filename),
+            path: dir.join(filename),
+        }
     }
 }
 
 impl Drop for TempFile {
     fn drop(&mut self) {
-        fs::remove_file(&self.name).unwrap();
+        if let Err(e) = fs::remove_file(&self.path) {
+            assert_eq!(io::ErrorKind::NotFound, e.kind(), "{}", e);
+        }
     }
 }
 
-impl Default for TempFile {
-    /// Creates an empty temp file
-    fn default() -> Self {
-        Self::new_or_panic("file.tmp", "")
+/// Reads `len` pseudo-random bytes, the same ones for the same seed
+pub struct RandomReader {
+    rng: StdRng,
+    left: u64,
+}
+
+impl RandomReader {
+    pub fn new(seed: u64, len: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            left: len,
+        }
+    }
+}
+
+impl Read for RandomReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        self.rng.fill_bytes(&mut buf[..n]);
+        self.left -= n as u64;
+        Ok(n)
     }
 }
 
+/// Returns the number of bytes `reader` returns and their SHA-256 digest in
+/// hex. The bytes are hashed as they come, so bodies of any size can be
+/// compared without holding them in memory.
+pub fn sha256_of(mut reader: impl Read) -> io::Result<(u64, String)> {
+    let (mut hash, mut buf, mut len) = (Context::new(&SHA256), vec![0; 1 << 16], 0);
+    loop {
+        match reader.read(&mut buf)? {
+            0 => break,
+            n => {
+                hash.update(&buf[..n]);
+                len += n as u64;
+            }
+        }
+    }
+    let digest = hash.finish();
+    let hex = digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Ok((len, hex))
+}
+
+/// Sets the size in MB of the file in the large file tests, which is small by
+/// default, e.g. `HTTPFS_LARGE_FILE_MB=500 cargo test large_file`
+pub const LARGE_FILE_ENV_VARIABLE: &str = "HTTPFS_LARGE_FILE_MB";
+
+/// The size of the file in the large file tests, see [LARGE_FILE_ENV_VARIABLE]
+pub fn large_file_len() -> u64 {
+    let mb = std::env::var(LARGE_FILE_ENV_VARIABLE)
+        .ok()
+        .and_then(|mb| mb.parse::<u64>().ok())
+        .unwrap_or(8);
+    mb << 20
+}
+
 /// A wrapper around the server handle. Implements a [Drop::drop] method that
 /// calls [Handle::shutdown]. Warning: [Handle::shutdown] may block for a short
 /// time while it waits for the server to stop. That's the reason why this is
 /// not implemented for the general [Server] type.
+///
+/// Each server serves a [TempDir] of its own, which is deleted along with it.
 pub struct ServerDropper {
     handle: Handle,
     cfg: ServerConfig,
+    dir: TempDir,
 }
 
 impl ServerDropper {
-    pub const DEFAULT_SERVER_CONFIG: ServerConfig = (Server::LOCALHOST, 8666, "./", 2);
+    pub const DEFAULT_SERVER_CONFIG: ServerConfig = (Server::LOCALHOST, 8666, 2);
 
     pub fn new(cfg: ServerConfig) -> Result<Self, ServerError> {
+        Self::with(cfg, |_| {})
+    }
+
+    /// Like [ServerDropper::new], but lets the caller set extra options on the
+    /// [Server] before it starts
+    pub fn with(
+        cfg: ServerConfig,
+        configure: impl FnOnce(&mut Server),
+    ) -> Result<Self, ServerError> {
+        let dir = TempDir::new("served")?;
+        let mut server = Server {
+            addr: cfg.0,
+            port: cfg.1,
+            dir: dir.path.to_string_lossy().into_owned(),
+            n_workers: cfg.2,
+            ..Default::default()
+        };
+        configure(&mut server);
         Ok(Self {
             cfg,
-            handle: Server {
-                addr: cfg.0,
-                port: cfg.1,
-                dir: String::from(cfg.2),
-                n_workers: cfg.3,
-            }
-            .serve()?,
+            handle: server.serve()?,
+            dir,
         })
     }
 
@@ -87,14 +240,35 @@ impl ServerDropper {
         Self::new(cfg).unwrap()
     }
 
-    /// Returns a formatted string containing the address of this server
+    /// Returns a formatted string containing the address of this server. This
+    /// is the address the server is bound to, so it has the real port for
+    /// servers started on port 0.
     pub fn addr(&self) -> String {
-        format!("http://{}:{}", self.cfg.0, self.cfg.1)
+        match self.handle.bound_addrs().first() {
+            Some(BoundAddr::Tcp(addr)) => format!("http://{}", addr),
+            _ => format!("http://{}:{}", self.cfg.0, self.cfg.1),
+        }
     }
 
     pub fn file_addr(&self, filename: &str) -> String {
         format!("{}/{}", self.addr(), filename)
     }
+
+    /// The directory being served, unless the caller pointed the [Server]
+    /// somewhere else
+    pub fn dir(&self) -> &TempDir {
+        &self.dir
+    }
+
+    /// Creates a file in the directory being served
+    pub fn file(&self, filename: &str, contents: &str) -> TempFile {
+        TempFile::new_or_panic(&self.dir.path, filename, contents)
+    }
+
+    /// The addresses of all the server's listeners
+    pub fn bound_addrs(&self) -> &[BoundAddr] {
+        self.handle.bound_addrs()
+    }
 }
 
 impl Default for ServerDropper {
@@ -137,10 +311,15 @@ impl AddressCountingServerFactory {
     }
 
     pub fn next_server(&mut self) -> ServerDropper {
+        self.next_server_with(|_| {})
+    }
+
+    /// Spawns the next server, letting the caller set extra options on it
+    pub fn next_server_with(&mut self, configure: impl FnOnce(&mut Server)) -> ServerDropper {
         let mut cfg = ServerDropper::DEFAULT_SERVER_CONFIG;
         cfg.1 = self.next;
         self.next += 1;
-        ServerDropper::new_or_panic(cfg)
+        ServerDropper::with(cfg, configure).unwrap()
     }
 }
 
@@ -152,6 +331,70 @@ impl Default for AddressCountingServerFactory {
     }
 }
 
+/// Sends a raw request over TCP for requests that ureq refuses to make.
+/// Returns the status (e.g. `"403 Forbidden"`), and the rest of the response
+/// (headers and body) as lines joined by `\n`.
+pub fn raw_request(server: &ServerDropper, request: &str) -> (String, String) {
+    raw_request_bytes(server, request.as_bytes())
+}
+
+/// Like [raw_request], for requests that aren't valid UTF-8
+pub fn raw_request_bytes(server: &ServerDropper, request: &[u8]) -> (String, String) {
+    let mut sock = TcpStream::connect(server.addr().trim_start_matches("http://")).unwrap();
+    sock.write_all(request).unwrap();
+    let mut scnr = BullshitScanner::new(&mut sock);
+    let status = scnr
+        .next_line()
+        .unwrap()
+        .0
+        .split_once(' ')
+        .map(|pair| String::from(pair.1))
+        .unwrap();
+    let rest = scnr.lines().map(|l| l.0).collect::<Vec<_>>().join("\n");
+    (status, rest)
+}
+
+/// The names linked to in a directory listing page, in order, without the
+/// links to `.` and `..`
+pub fn listing_links(page: &str) -> Vec<String> {
+    page.lines()
+        .filter_map(|line| line.trim().strip_prefix("<a href=\""))
+        .filter_map(|link| link.split_once("\">"))
+        .filter(|(href, _)| !matches!(*href, "." | ".."))
+        .filter_map(|(_, text)| text.strip_suffix("</a>"))
+        .map(String::from)
+        .collect()
+}
+
+/// Set to run the tests that shell out to other HTTP implementations, e.g.
+/// `HTTPFS_INTEROP=1 cargo test interop`. They need `curl` on the `PATH`.
+pub const INTEROP_ENV_VARIABLE: &str = "HTTPFS_INTEROP";
+
+/// Whether the interop tests should run, see [INTEROP_ENV_VARIABLE]
+pub fn interop_enabled() -> bool {
+    std::env::var_os(INTEROP_ENV_VARIABLE).is_some()
+}
+
+/// Runs the system's `curl` with `args`. Returns the status code and the
+/// response body. Panics if curl fails, e.g. on a malformed response.
+pub fn curl(args: &[&str]) -> (u16, String) {
+    let output = Command::new("curl")
+        .args(["--silent", "--show-error", "--write-out", "\n%{http_code}"])
+        .args(args)
+        .output()
+        .expect("failed to run curl");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "curl {:?} failed: {}{}",
+        args,
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let (body, status) = stdout.rsplit_once('\n').unwrap();
+    (status.parse().unwrap(), String::from(body))
+}
+
 pub mod better_ureq {
     use ureq::{get, post, Error};
 